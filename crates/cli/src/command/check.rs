@@ -0,0 +1,31 @@
+use anyhow::{bail, Result};
+use core_lib::stage::check;
+
+use crate::config::Config;
+
+use super::QueryArgs;
+
+/// report what relocation missed in installed kegs, fails if there is any
+#[tracing::instrument(level = "debug", skip_all, fields(query = ?query.names))]
+pub async fn run(config: &Config, query: QueryArgs) -> Result<()> {
+  let local_opt_dir = config.base.local_opt();
+  let checked = check::exec(
+    check::Args::new(&config.base.prefix, &local_opt_dir),
+    query.names.iter().map(String::as_str),
+    (),
+  ).await?;
+  let mut count = 0;
+  for i in &checked {
+    if i.issues.is_empty() {
+      println!("{} {}: ok", i.name, i.version);
+    }
+    for (file, issue) in &i.issues {
+      println!("{} {}: {}: {}", i.name, i.version, file.display(), issue);
+    }
+    count += i.issues.len();
+  }
+  if count != 0 {
+    bail!("{} issues found", count)
+  }
+  Ok(())
+}
@@ -1,5 +1,5 @@
 use anyhow::Result;
-use core_lib::{io::{fetch::MirrorLists, read::{read_formulas, tmp_path}}, stage::{download, probe, resolve, verify}, ui::{event::{simplify_tracker, ItemEvent}, with_progess_bar, with_progess_multibar}};
+use core_lib::{io::{fetch::MirrorLists, index::FormulaIndex, read::tmp_path}, stage::{download, probe, resolve, verify}, ui::{event::{simplify_tracker, ItemEvent}, with_progess_bar, with_progess_multibar}};
 
 use crate::{command::PbStyle, config::Config, ACTIVE_PB};
 
@@ -7,7 +7,7 @@ use super::QueryArgs;
 
 #[tracing::instrument(level = "debug", skip_all, fields(query = ?query.names, arch = %config.base.arch))]
 pub async fn run(config: &Config, mirrors: &MirrorLists, query: QueryArgs) -> Result<()> {
-  let formulas = read_formulas(config.base.formula_json())?;
+  let formulas = FormulaIndex::open(config.base.formula_json())?;
 
   info!(message="resolve", ?query.names);
   let resolved = with_progess_bar(
@@ -20,7 +20,7 @@ pub async fn run(config: &Config, mirrors: &MirrorLists, query: QueryArgs) -> Re
       tracker
     ),
     ()
-  ).await.unwrap();
+  ).await?;
 
   info!(message="probe", ?resolved.names, resolved=resolved.packages.iter().map(|i| i.name.as_str()).collect::<Vec<_>>().join(","));
   let urls = with_progess_bar(
@@ -29,12 +29,13 @@ pub async fn run(config: &Config, mirrors: &MirrorLists, query: QueryArgs) -> Re
     Some(ItemEvent::Init { max: resolved.packages.len() }),
     |tracker| probe::exec(
       probe::Args::new(&config.base.arch, mirrors)
-        .cache(&config.base.cache, false),
+        .cache(&config.base.cache, false)
+        .offline(config.base.offline),
       &resolved.packages,
       tracker
     ),
     (),
-  ).await.unwrap();
+  ).await?;
 
   info!(message="download", urls.len=urls.len(), pkgs=urls.iter().map(|i| i.pkg.filename.as_str()).collect::<Vec<_>>().join(","));
   let cached = with_progess_multibar(
@@ -47,21 +48,23 @@ pub async fn run(config: &Config, mirrors: &MirrorLists, query: QueryArgs) -> Re
       tracker
     ),
     (),
-  ).await.unwrap();
+  ).await?;
   cached.iter().for_each(|i| info!(message="download", name=%i.name, size=%i.cache_size, path=%i.cache_pkg.display()));
 
-  info!(message="verify", cached.len=cached.len(), urls.len=urls.len());
+  // freshly downloaded files are hashed while downloading, only verify those already in cache
+  let to_verify = urls.iter().filter(|v| v.cached).collect::<Vec<_>>();
+  info!(message="verify", cached.len=cached.len(), to_verify.len=to_verify.len());
   let failed = with_progess_bar(
     ACTIVE_PB.clone(),
     Some(PbStyle::Items.style()),
-    Some(ItemEvent::Init { max: cached.len() }),
+    Some(ItemEvent::Init { max: to_verify.len() }),
     |tracker| verify::exec(
       &config.base.cache,
-      urls.iter().map(|a| (&a.pkg, &a.url, None)),
+      to_verify.iter().map(|a| (&a.pkg, &a.url, None)),
       simplify_tracker(tracker)
     ),
     ()
-  ).await.unwrap();
+  ).await?;
 
   failed.iter().for_each(|i| {
     warn!(message="failed", name=%i.name, reason=%i.reason);
@@ -0,0 +1,26 @@
+use anyhow::{Context as _, Result};
+use core_lib::io::index::{FormulaIndex, FormulaSource as _};
+
+use crate::config::Config;
+
+use super::QueryArgs;
+
+/// only the queried formulas are decoded from the index
+#[tracing::instrument(level = "debug", skip_all, fields(query = ?query.names))]
+pub fn run(config: &Config, query: QueryArgs) -> Result<()> {
+  let formulas = FormulaIndex::open(config.base.formula_json())?;
+  for name in &query.names {
+    let formula = formulas.find(name)?.with_context(|| format!("formula {} not found", name))?;
+    println!("{}: {} {}", formula.full_name, formula.versions.stable, formula.desc);
+    println!("  homepage: {}", formula.homepage);
+    if !formula.aliases.is_empty() {
+      println!("  aliases: {}", formula.aliases.join(", "));
+    }
+    if !formula.dependencies.is_empty() {
+      println!("  dependencies: {}", formula.dependencies.join(", "));
+    }
+    let bottle = formula.bottle.get("stable").is_some_and(|i| i.files.contains_key(&config.base.arch));
+    println!("  bottle for {}: {}", config.base.arch, if bottle { "yes" } else { "no" });
+  }
+  Ok(())
+}
@@ -1,5 +1,5 @@
 use anyhow::Result;
-use core_lib::{io::{fetch::MirrorLists, read::{read_formulas, tmp_path}}, package::package::PackageCache, stage::{link, probe, resolve, unpack, verify}, ui::{event::ItemEvent, with_progess_bar, with_progess_multibar}};
+use core_lib::{io::{fetch::MirrorLists, index::FormulaIndex, read::tmp_path}, package::package::PackageCache, stage::{link, probe, resolve, unpack, verify}, ui::{event::ItemEvent, with_progess_bar, with_progess_multibar}};
 
 use crate::{command::PbStyle, config::Config, ACTIVE_PB};
 
@@ -7,22 +7,23 @@ use super::QueryArgs;
 
 #[tracing::instrument(level = "debug", skip_all, fields(query = ?query.names, arch = %config.base.arch))]
 pub async fn run(config: &Config, mirrors: &MirrorLists, query: QueryArgs) -> Result<()> {
-  let formulas = read_formulas(config.base.formula_json())?;
+  let formulas = FormulaIndex::open(config.base.formula_json())?;
 
   info!(message="resolve", ?query.names);
   let resolved = resolve::exec(
     &formulas,
     query.names.iter(),
     (),
-  ).await.unwrap();
+  ).await?;
 
   info!(message="probe", ?resolved.names, resolved=resolved.packages.iter().map(|i| i.name.as_str()).collect::<Vec<_>>().join(","));
   let urls = probe::exec(
     probe::Args::new(&config.base.arch, mirrors)
-      .cache(&config.base.cache, false),
+      .cache(&config.base.cache, false)
+      .offline(config.base.offline),
     &resolved.packages,
     (),
-  ).await.unwrap();
+  ).await?;
 
   let mut cached = Vec::new();
   for i in &urls {
@@ -32,6 +33,7 @@ pub async fn run(config: &Config, mirrors: &MirrorLists, query: QueryArgs) -> Re
       name: i.pkg.name.clone(),
       cache_pkg,
       cache_size,
+      cellar: i.pkg.cellar.clone(),
     });
   }
 
@@ -40,7 +42,7 @@ pub async fn run(config: &Config, mirrors: &MirrorLists, query: QueryArgs) -> Re
     &config.base.cache,
     urls.iter().map(|a| (&a.pkg, &a.url, None)),
     (),
-  ).await.unwrap();
+  ).await?;
 
   failed.iter().for_each(|i| {
     warn!(message="failed", name=%i.name, reason=%i.reason);
@@ -55,13 +57,21 @@ pub async fn run(config: &Config, mirrors: &MirrorLists, query: QueryArgs) -> Re
     PbStyle::Bytes.style().into(),
     |tracker| unpack::exec(
       // TODO: force in args
-      unpack::Args::new(&config.base.prefix, &local_opt_dir).force(true),
+      unpack::Args::new(&config.base.prefix, &local_opt_dir).force(true)
+        .arch(&config.base.arch)
+        .placeholders(config.base.placeholders.clone()),
       &cached,
       tracker
     ),
     (),
-  ).await.unwrap();
+  ).await?;
   unpacked.iter().for_each(|i| info!(message="unpacked", name=%i.name, dest=%i.dest.display()));
+  for i in &unpacked {
+    for (file, tokens) in &i.unknown_placeholders {
+      let tokens = tokens.iter().map(String::as_str).collect::<Vec<_>>().join(", ");
+      eprintln!("unknown placeholders {} left in {} of package {}, set them in placeholders of pacbrew.toml", tokens, file.display(), i.name);
+    }
+  }
 
   let linked = with_progess_bar(
     ACTIVE_PB.clone(),
@@ -73,7 +83,7 @@ pub async fn run(config: &Config, mirrors: &MirrorLists, query: QueryArgs) -> Re
       tracker,
     ),
     (),
-  ).await.unwrap();
+  ).await?;
   linked.iter().for_each(|i| info!(message="linked", name=%i.name, version=%i.version));
   Ok(())
 }
@@ -0,0 +1,51 @@
+use anyhow::{Context as _, Result};
+use core_lib::{io::{fetch::MirrorLists, index::{FormulaIndex, FormulaSource as _}}, package::package::PackageVersion, stage::bench, ui::{event::ItemEvent, with_progess_bar}};
+
+use crate::{command::PbStyle, config::Config, ACTIVE_PB};
+
+#[derive(Debug, Clone, clap::Subcommand)]
+pub enum MirrorCommand {
+  /// download a known bottle from each mirror and print latency and throughput
+  Bench(BenchArgs),
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct BenchArgs {
+  /// formula whose bottle is downloaded from each mirror
+  #[arg(long, default_value = "zlib")]
+  pub name: String,
+}
+
+pub async fn run(config: &Config, mirrors: &MirrorLists, command: MirrorCommand) -> Result<()> {
+  match command {
+    MirrorCommand::Bench(args) => bench(config, mirrors, args).await,
+  }
+}
+
+#[tracing::instrument(level = "debug", skip_all, fields(name = %args.name, arch = %config.base.arch))]
+async fn bench(config: &Config, mirrors: &MirrorLists, args: BenchArgs) -> Result<()> {
+  if config.base.offline {
+    anyhow::bail!("cannot bench mirrors in offline mode");
+  }
+  let formulas = FormulaIndex::open(config.base.formula_json())?;
+  let formula = formulas.find(&args.name)?.with_context(|| format!("formula {} not found", args.name))?;
+  let package = PackageVersion::from(formula.into_owned());
+  let pkg = package.find_arch(&config.base.arch).with_context(|| format!("no bottle of {} for {}", args.name, config.base.arch))?;
+
+  let result = with_progess_bar(
+    ACTIVE_PB.clone(),
+    Some(PbStyle::Items.style()),
+    Some(ItemEvent::Init { max: mirrors.len() }),
+    |tracker| bench::exec(mirrors, pkg, config.base.cache.join(".bench"), tracker),
+    (),
+  ).await?;
+
+  println!("{:<48} {:<8} {:>10} {:>12}  {}", "mirror", "type", "latency", "throughput", "result");
+  for i in &result {
+    let latency = i.latency.map(|i| format!("{}ms", i.as_millis())).unwrap_or_else(|| "-".to_string());
+    let throughput = i.throughput().map(|i| format!("{:.2}MB/s", i / 1e6)).unwrap_or_else(|| "-".to_string());
+    let status = i.error.as_ref().map(|e| e.to_string()).unwrap_or_else(|| "ok".to_string());
+    println!("{:<48} {:<8} {:>10} {:>12}  {}", i.base_url, format!("{:?}", i.server_type), latency, throughput, status);
+  }
+  Ok(())
+}
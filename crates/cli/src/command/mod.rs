@@ -3,6 +3,11 @@ use indicatif::ProgressStyle;
 pub mod update;
 pub mod download;
 pub mod install;
+pub mod check;
+pub mod info;
+pub mod search;
+pub mod serve;
+pub mod mirror;
 
 #[derive(Debug, Clone, clap::Args)]
 pub struct QueryArgs {
@@ -0,0 +1,20 @@
+use anyhow::Result;
+use core_lib::io::index::FormulaIndex;
+
+use crate::config::Config;
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct SearchArgs {
+  /// case-insensitive substring of name or description
+  pub pattern: String,
+}
+
+/// search names and descriptions in the index, without decoding any formula
+#[tracing::instrument(level = "debug", skip_all, fields(pattern = %args.pattern))]
+pub fn run(config: &Config, args: SearchArgs) -> Result<()> {
+  let formulas = FormulaIndex::open(config.base.formula_json())?;
+  for i in formulas.search(&args.pattern) {
+    println!("{}: {}", formulas.name(i), formulas.desc(i));
+  }
+  Ok(())
+}
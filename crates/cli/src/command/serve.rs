@@ -0,0 +1,20 @@
+use anyhow::Result;
+use core_lib::io::serve::Server;
+
+use crate::config::Config;
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct ServeArgs {
+  /// address to listen on, e.g. `0.0.0.0:8080` to act as a LAN mirror
+  #[arg(long, default_value = "127.0.0.1:8080")]
+  pub bind: String,
+}
+
+/// serve the download cache as a `Bottle` mirror
+#[tracing::instrument(level = "debug", skip_all, fields(bind = %args.bind))]
+pub async fn run(config: &Config, args: ServeArgs) -> Result<()> {
+  let listener = Server::bind(&args.bind).await?;
+  info!(addr=?listener.local_addr().ok(), cache=%config.base.cache.display(), "serve cache as bottle mirror");
+  Server::new(&config.base.cache).run(listener).await?;
+  Ok(())
+}
@@ -1,18 +1,59 @@
-use anyhow::Result;
-use core_lib::{io::fetch::MirrorLists, stage::update_db, ui::with_progess_bar};
+use anyhow::{Context as _, Result};
+use core_lib::{io::fetch::MirrorLists, stage::{update_db, update_formula}, ui::{event::ItemEvent, with_progess_bar}};
 
 use crate::{command::PbStyle, config::Config, ACTIVE_PB};
 
+#[derive(Debug, Clone, clap::Args)]
+pub struct UpdateArgs {
+  /// only refresh these formulas from `api/formula/<name>.json`, instead of the whole formula.json.
+  /// these files are not signed, so they are merged without verification
+  pub names: Vec<String>,
+  /// with names, also refresh their dependencies
+  #[arg(long)]
+  pub deps: bool,
+}
 
 #[tracing::instrument(level = "debug", skip_all, fields(mirrors.len=mirrors.len()))]
-pub async fn run(config: &Config, mirrors: &MirrorLists) -> Result<()> {
-  with_progess_bar(
+pub async fn run(config: &Config, mirrors: &MirrorLists, args: UpdateArgs) -> Result<()> {
+  if config.base.offline {
+    anyhow::bail!("cannot update formula.json in offline mode, remove --offline or set base.offline = false");
+  }
+  if !args.names.is_empty() {
+    if config.base.verify_enabled() {
+      eprintln!("warning: api/formula/<name>.json is not signed, merged without verification, run `pacbrew update` for the signed formula.json");
+    }
+    let value = with_progess_bar(
+      ACTIVE_PB.clone(),
+      Some(PbStyle::Items.style()),
+      Some(ItemEvent::Init { max: args.names.len() }),
+      |tracker| update_formula::exec(
+        update_formula::Args::new(mirrors, &config.base.cache)
+          .compression(config.base.index_compression)
+          .deps(args.deps),
+        args.names.iter(),
+        tracker
+      ),
+      (),
+    ).await?;
+    println!("updated {}", value.names.join(", "));
+    return Ok(())
+  }
+  let keys = config.base.public_keys().context("load api_keys, set `api_keys = {}` in [base] to skip verification")?;
+  let value = with_progess_bar(
     ACTIVE_PB.clone(),
     Some(PbStyle::Bytes.style()),
     None,
-    |tracker| update_db::exec(mirrors, &config.base.cache, tracker),
+    |tracker| update_db::exec(
+      update_db::Args::new(mirrors, &config.base.cache)
+        .compression(config.base.index_compression)
+        .verify(keys.as_ref()),
+      tracker
+    ),
     (),
-  ).await.unwrap();
-  info!("update formula.json success");
+  ).await?;
+  match value {
+    update_db::Value::Updated => info!("update formula.json success"),
+    update_db::Value::UpToDate => println!("formula.json already up to date"),
+  }
   Ok(())
 }
@@ -1,12 +1,27 @@
-use std::path::PathBuf;
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
 
-use core_lib::package::mirror::MirrorType;
+use core_lib::{io::{auth::Credentials, compress::Compression, jws::PublicKeys}, package::mirror::{MirrorType, NetworkOptions, TlsVersion}};
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Mirror {
   pub url: String,
   pub api_url: Option<String>,
   pub r#type: MirrorType,
+  /// extra headers for this mirror, e.g. `Authorization`
+  #[serde(default)]
+  pub headers: BTreeMap<String, String>,
+  /// basic credentials for private registries
+  pub username: Option<String>,
+  pub password: Option<String>,
+}
+
+impl Mirror {
+  pub fn credentials(&self) -> Option<Credentials> {
+    Some(Credentials {
+      username: self.username.clone()?,
+      password: self.password.clone().unwrap_or_default(),
+    })
+  }
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -25,10 +40,47 @@ pub struct BaseConfig {
   pub local_opt: Option<PathBuf>,
   pub db: PathBuf,
   pub arch: String,
+  /// never touch the network, only bottles in cache are used
+  #[serde(default)]
+  pub offline: bool,
+  /// keep formula.json compressed in cache, e.g. `"gzip"` stores `formula.json.gz`
+  #[serde(default)]
+  pub index_compression: Option<Compression>,
+  /// pem files by kid to verify formula.jws.json, the vendored Homebrew key if not set, an empty table skips verification
+  #[serde(default)]
+  pub api_keys: Option<BTreeMap<String, PathBuf>>,
+  /// values of relocation placeholders, e.g. `HOMEBREW_PERL = "/usr/local/bin/perl"`, defaults depend on `arch`
+  #[serde(default)]
+  pub placeholders: BTreeMap<String, String>,
 }
 
 impl BaseConfig {
-  pub fn formula_json(&self) -> PathBuf { self.cache.join("formula.json") }
+  /// the configured variant, or any existing one if it's not downloaded yet
+  pub fn formula_json(&self) -> PathBuf {
+    let plain = self.cache.join("formula.json");
+    let configured = Compression::path(&plain, self.index_compression);
+    if configured.exists() {
+      return configured
+    }
+    [None, Some(Compression::Gzip), Some(Compression::Brotli)].into_iter()
+      .map(|i| Compression::path(&plain, i))
+      .find(|i| i.exists())
+      .unwrap_or(configured)
+  }
+  pub fn mirror_health(&self) -> PathBuf { self.cache.join("mirror_health.json") }
+  pub fn probe_cache(&self) -> PathBuf { self.cache.join("probe_cache.json") }
+  /// false if `api_keys = {}`
+  pub fn verify_enabled(&self) -> bool {
+    !matches!(&self.api_keys, Some(api_keys) if api_keys.is_empty())
+  }
+  /// None if verification is disabled
+  pub fn public_keys(&self) -> core_lib::error::Result<Option<PublicKeys>> {
+    match &self.api_keys {
+      None => PublicKeys::homebrew().map(Some),
+      Some(api_keys) if api_keys.is_empty() => Ok(None),
+      Some(api_keys) => api_keys.iter().try_fold(PublicKeys::new(), |keys, (kid, path)| keys.load(kid, path)).map(Some),
+    }
+  }
   pub fn local_opt(&self) -> PathBuf { self.local_opt.clone().unwrap_or_else(|| self.prefix.join("local").join("opt")) }
 }
 
@@ -36,12 +88,47 @@ impl BaseConfig {
 pub struct NetworkConfig {
   #[serde(default = "retry_default")]
   pub retry: usize,
+  /// seconds to wait for a connection
+  pub connect_timeout: Option<u64>,
+  /// seconds to wait between two reads
+  pub read_timeout: Option<u64>,
+  /// seconds for a whole request, note this includes downloading the bottle
+  pub timeout: Option<u64>,
+  /// e.g. `http://proxy.corp:3128`
+  pub proxy: Option<String>,
+  /// e.g. `localhost,.corp`
+  pub no_proxy: Option<String>,
+  /// pem files of extra root CA
+  #[serde(default)]
+  pub ca_certs: Vec<PathBuf>,
+  pub min_tls_version: Option<TlsVersion>,
 }
 
 impl Default for NetworkConfig {
   fn default() -> Self {
     Self {
       retry: retry_default(),
+      connect_timeout: None,
+      read_timeout: None,
+      timeout: None,
+      proxy: None,
+      no_proxy: None,
+      ca_certs: Vec::new(),
+      min_tls_version: None,
+    }
+  }
+}
+
+impl NetworkConfig {
+  pub fn options(&self) -> NetworkOptions {
+    NetworkOptions {
+      connect_timeout: self.connect_timeout.map(Duration::from_secs),
+      read_timeout: self.read_timeout.map(Duration::from_secs),
+      timeout: self.timeout.map(Duration::from_secs),
+      proxy: self.proxy.clone(),
+      no_proxy: self.no_proxy.clone(),
+      ca_certs: self.ca_certs.clone(),
+      min_tls_version: self.min_tls_version,
     }
   }
 }
@@ -3,7 +3,7 @@
 use std::sync::{Arc, RwLock};
 
 use clap::Parser;
-use core_lib::{io::{fetch::MirrorLists, read::read_toml}, package::mirror::MirrorServer, ui::bar::{ActiveSuspendable, PbWriter}};
+use core_lib::{io::{fetch::MirrorLists, health::MirrorHealth, probe_cache::ProbeCache, read::read_toml}, package::mirror::MirrorServer, ui::bar::{ActiveSuspendable, PbWriter}};
 use tracing_subscriber::fmt::format::FmtSpan;
 
 pub mod config;
@@ -11,15 +11,26 @@ pub mod command;
 
 #[derive(Debug, Clone, clap::Parser)]
 pub struct Args {
+  /// never touch the network, same as `base.offline` in pacbrew.toml
+  #[arg(long, global = true)]
+  pub offline: bool,
   #[command(subcommand)]
   pub command: Command,
 }
 
 #[derive(Debug, Clone, clap::Subcommand)]
 pub enum Command {
-  Update,
+  Update(command::update::UpdateArgs),
   Download(command::QueryArgs),
   Install(command::QueryArgs),
+  /// scan installed kegs for leftover placeholders, foreign load paths and broken symlinks
+  Check(command::QueryArgs),
+  /// show formulas by name or alias
+  Info(command::QueryArgs),
+  Search(command::search::SearchArgs),
+  Serve(command::serve::ServeArgs),
+  #[command(subcommand)]
+  Mirror(command::mirror::MirrorCommand),
 }
 
 lazy_static::lazy_static! {
@@ -38,15 +49,37 @@ async fn main() {
   let root = std::env::var("CARGO_MANIFEST_DIR").unwrap();
   std::env::set_current_dir(&root).ok();
   info!(cwd=root);
-  let config: config::Config = read_toml("pacbrew.toml").unwrap();
+  let mut config: config::Config = read_toml("pacbrew.toml").unwrap();
   let args = Args::parse();
+  config.base.offline |= args.offline;
   info!(?config, ?args);
-  let mirrors = MirrorLists {
-    lists: config.mirror_list.iter().map(|i| MirrorServer::new(i.r#type, &i.url, i.api_url.as_deref())).collect()
+  let mirrors = MirrorLists::new(
+    config.mirror_list.iter()
+      .map(|i| MirrorServer::new(i.r#type, &i.url, i.api_url.as_deref())
+        .network(config.network.options())
+        .headers(i.headers.clone())
+        .credentials(i.credentials()))
+      .collect()
+  ).health(MirrorHealth::load(config.base.mirror_health()))
+    .probe_cache(ProbeCache::load(config.base.probe_cache()));
+  let result = match args.command {
+    Command::Update(args) => command::update::run(&config, &mirrors, args).await,
+    Command::Download(query) => command::download::run(&config, &mirrors, query).await,
+    Command::Install(query) => command::install::run(&config, &mirrors, query).await,
+    Command::Check(query) => command::check::run(&config, query).await,
+    Command::Info(query) => command::info::run(&config, query),
+    Command::Search(args) => command::search::run(&config, args),
+    Command::Serve(args) => command::serve::run(&config, args).await,
+    Command::Mirror(command) => command::mirror::run(&config, &mirrors, command).await,
   };
-  match args.command {
-    Command::Update => command::update::run(&config, &mirrors).await.unwrap(),
-    Command::Download(query) => command::download::run(&config, &mirrors, query).await.unwrap(),
-    Command::Install(query) => command::install::run(&config, &mirrors, query).await.unwrap(),
+  if let Err(error) = mirrors.health.save() {
+    warn!(%error, "save mirror health failed");
+  }
+  if let Err(error) = mirrors.probed.save() {
+    warn!(%error, "save probe cache failed");
+  }
+  if let Err(error) = result {
+    eprintln!("error: {:#}", error);
+    std::process::exit(1);
   }
 }
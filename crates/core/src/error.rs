@@ -26,6 +26,12 @@ pub enum Error {
     #[source]
     error: reqwest::Error,
   },
+  #[error("sha256 of {} not match, expect {expected} but got {actual}", .filename.to_string_lossy())]
+  ChecksumMismatch {
+    filename: PathBuf,
+    expected: String,
+    actual: String,
+  },
   #[error("io failed when {} file {}, caused by: {error}", .action, .filename.to_string_lossy())]
   IoFailed {
     action: &'static str,
@@ -53,10 +59,51 @@ pub enum Error {
     #[source]
     error: toml::de::Error,
   },
+  #[error("build client for {} failed when {}, caused by: {inner}", .url, .reason)]
+  ClientBuildFailed {
+    url: String,
+    reason: &'static str,
+    #[source]
+    inner: anyhow::Error,
+  },
+  #[error("serve on {} failed, caused by: {error}", .addr)]
+  ServeFailed {
+    addr: String,
+    #[source]
+    error: std::io::Error,
+  },
+  #[error("signature of {} invalid: {}", .filename.to_string_lossy(), .reason)]
+  SignatureInvalid {
+    filename: PathBuf,
+    reason: String,
+  },
+  #[error("relocate {} failed: {}", .filename.to_string_lossy(), .reason)]
+  RelocateFailed {
+    filename: PathBuf,
+    reason: String,
+  },
+  #[error("bottle of {} is built for cellar {}, but the configured cellar is {}", .name, .expected.to_string_lossy(), .actual.to_string_lossy())]
+  CellarMismatch {
+    name: String,
+    expected: PathBuf,
+    actual: PathBuf,
+  },
+  #[error("entry {} of archive escapes the destination: {}", .entry.to_string_lossy(), .reason)]
+  UnsafeEntry {
+    entry: PathBuf,
+    reason: String,
+  },
   #[error("malformed url {}", .0)]
   MalformedUrl(String),
   #[error("no available mirror for req {}", .0)]
   MirrorFailed(FetchReq),
+  #[error("offline but {} bottles not in cache {}: {}", .missing.len(), .cache_dir.to_string_lossy(), .missing.join(", "))]
+  MissingFromCache {
+    cache_dir: PathBuf,
+    missing: Vec<String>,
+  },
+  #[error("offline but no cache dir to look bottles up in")]
+  OfflineWithoutCache,
   #[error("package not found: {} with {:?} in [{}]", .name, .arch, .avaliable.join(","))]
   PackageNotFound {
     name: String,
@@ -72,9 +119,29 @@ impl Error {
   pub fn package_arch_not_found(package: &PackageVersion, arch: &str) -> Self {
     Self::PackageNotFound { name: package.name.clone(), arch: Some(arch.to_string()), avaliable: package.prebuilds.iter().map(|i| i.arch.clone()).collect() }
   }
+  pub fn signature_invalid(filename: &Path, reason: String) -> Self {
+    Self::SignatureInvalid { filename: filename.to_owned(), reason }
+  }
+  pub fn relocate_failed(filename: &Path, reason: String) -> Self {
+    Self::RelocateFailed { filename: filename.to_owned(), reason }
+  }
+  pub fn unsafe_entry(entry: &Path, reason: String) -> Self {
+    Self::UnsafeEntry { entry: entry.to_owned(), reason }
+  }
   pub fn parse_response<'a, E: Into<anyhow::Error>>(action: &'static str, url: &'a str, reason: &'a str) -> impl FnOnce(E) -> Self + 'a {
     move |e: E| Self::ResponseMalformed { action, url: url.to_string(), reason: reason.to_string(), inner: e.into() }
   }
+  pub fn build_client<'a, E: Into<anyhow::Error>>(url: &'a str, reason: &'static str) -> impl FnOnce(E) -> Self + 'a {
+    move |e: E| Self::ClientBuildFailed { url: url.to_string(), reason, inner: e.into() }
+  }
+  /// whether the mirror itself failed: connect, timeout or 5xx, but not e.g. a 404 for a bottle it lacks
+  pub fn is_mirror_down(&self) -> bool {
+    match self {
+      Self::RequestFailed { error: Some(error), .. } | Self::HttpDownloadFailed { error, .. } =>
+        error.is_connect() || error.is_timeout() || error.status().is_some_and(|i| i.is_server_error()),
+      _ => false,
+    }
+  }
   pub fn parse_response_error<'a>(action: &'static str, url: &'a str, reason: &'a str) -> Self {
     Self::ResponseMalformed { action, url: url.to_string(), reason: reason.to_string(), inner: anyhow::Error::msg("option") }
   }
@@ -0,0 +1,205 @@
+//! registry auth for OCI mirrors (ghcr.io or self-hosted registries)
+//! on `401 Unauthorized` the registry answers a challenge like
+//!   `WWW-Authenticate: Bearer realm="https://ghcr.io/token",service="ghcr.io",scope="repository:homebrew/core/wget:pull"`
+//! and we should `GET {realm}?service={service}&scope={scope}` (with optional basic credentials)
+//! for a token, then retry the request with `Authorization: Bearer {token}`.
+//!
+//! see also:
+//!   https://distribution.github.io/distribution/spec/auth/token/
+
+use std::{collections::HashMap, sync::Mutex, time::{Duration, Instant}};
+
+use crate::error::{Error, ErrorExt as _, Result};
+
+#[derive(Clone)]
+pub struct Credentials {
+  pub username: String,
+  pub password: String,
+}
+
+impl std::fmt::Debug for Credentials {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Credentials")
+      .field("username", &self.username)
+      // .field("password", &self.password)
+      .finish()
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Challenge {
+  Basic,
+  Bearer {
+    realm: String,
+    service: Option<String>,
+    scope: Option<String>,
+  },
+}
+
+impl Challenge {
+  /// parse value of `WWW-Authenticate`, quoted values may contain `,` like `scope="repository:a:pull,push"`
+  pub fn parse(s: &str) -> Option<Self> {
+    let (scheme, rest) = s.trim().split_once(' ').unwrap_or((s.trim(), ""));
+    let mut params = HashMap::new();
+    let mut rest = rest.trim_start();
+    while let Some((key, value)) = rest.split_once('=') {
+      let key = key.trim().to_ascii_lowercase();
+      let value = value.trim_start();
+      let (value, remain) = match value.strip_prefix('"') {
+        Some(quoted) => {
+          let end = quoted.find('"')?;
+          (&quoted[..end], &quoted[end+1..])
+        },
+        None => value.split_once(',').unwrap_or((value, "")),
+      };
+      params.insert(key, value.to_string());
+      rest = remain.trim_start().trim_start_matches(',').trim_start();
+    }
+    if scheme.eq_ignore_ascii_case("basic") {
+      Some(Self::Basic)
+    } else if scheme.eq_ignore_ascii_case("bearer") {
+      Some(Self::Bearer {
+        realm: params.remove("realm")?,
+        service: params.remove("service"),
+        scope: params.remove("scope"),
+      })
+    } else {
+      None
+    }
+  }
+}
+
+pub enum Authorization {
+  Basic(Credentials),
+  Bearer(String),
+}
+
+#[derive(serde::Deserialize)]
+struct TokenResponse {
+  token: Option<String>,
+  access_token: Option<String>,
+  expires_in: Option<u64>,
+}
+
+/// token of a registry, cached per scope until it expires
+#[derive(Debug, Default)]
+pub struct RegistryAuth {
+  pub credentials: Option<Credentials>,
+  tokens: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl RegistryAuth {
+  /// the spec says tokens without `expires_in` should be treated as 60 seconds
+  const DEFAULT_EXPIRES: Duration = Duration::from_secs(60);
+  /// refresh a bit earlier than the registry expires the token
+  const EXPIRES_MARGIN: Duration = Duration::from_secs(10);
+
+  pub fn new(credentials: Option<Credentials>) -> Self {
+    Self { credentials, tokens: Default::default() }
+  }
+
+  /// guess scope from url like `{base}/v2/{name}/blobs/{digest}` or `{base}/v2/{name}/manifests/{reference}`,
+  /// so we could send the cached token without being challenged first.
+  pub fn scope_of(url: &str) -> Option<String> {
+    let path = reqwest::Url::parse(url).ok()?.path().to_string();
+    let (_, name) = path.split_once("/v2/")?;
+    let name = name.rsplit_once("/blobs/").or_else(|| name.rsplit_once("/manifests/"))?.0;
+    Some(format!("repository:{}:pull", name))
+  }
+
+  pub fn cached(&self, scope: &str) -> Option<String> {
+    let mut tokens = self.tokens.lock().unwrap();
+    match tokens.get(scope) {
+      Some((token, expires)) if *expires > Instant::now() => Some(token.clone()),
+      Some(_) => {
+        tokens.remove(scope);
+        None
+      },
+      None => None,
+    }
+  }
+
+  /// returns None if we could not answer the challenge
+  pub async fn authorize(&self, client: &reqwest::Client, challenge: &Challenge, scope: Option<&str>) -> Result<Option<Authorization>> {
+    let (realm, service, scope) = match challenge {
+      Challenge::Basic => return Ok(self.credentials.clone().map(Authorization::Basic)),
+      Challenge::Bearer { realm, service, scope: challenge_scope } => (realm, service, challenge_scope.as_deref().or(scope)),
+    };
+    let mut url = reqwest::Url::parse(realm).map_err(|_| Error::MalformedUrl(realm.to_string()))?;
+    if let Some(service) = service {
+      url.query_pairs_mut().append_pair("service", service);
+    }
+    if let Some(scope) = scope {
+      url.query_pairs_mut().append_pair("scope", scope);
+    }
+    debug!(%url, has_credentials=self.credentials.is_some(), "request token");
+    let mut req = client.get(url.as_str());
+    if let Some(credentials) = &self.credentials {
+      req = req.basic_auth(&credentials.username, Some(&credentials.password));
+    }
+    let resp = req.send().await.when(("token", url.as_str()))?
+      .error_for_status().when(("token", url.as_str()))?;
+    let body = resp.bytes().await.when(("token", url.as_str()))?;
+    let resp = serde_json::from_slice::<TokenResponse>(&body).map_err(Error::parse_response("token", url.as_str(), "json"))?;
+    let token = resp.token.or(resp.access_token).ok_or_else(|| Error::parse_response_error("token", url.as_str(), "token"))?;
+    let expires_in = resp.expires_in.map(Duration::from_secs).unwrap_or(Self::DEFAULT_EXPIRES);
+    if let Some(scope) = scope {
+      let expires = Instant::now() + expires_in.saturating_sub(Self::EXPIRES_MARGIN);
+      self.tokens.lock().unwrap().insert(scope.to_string(), (token.clone(), expires));
+    }
+    Ok(Some(Authorization::Bearer(token)))
+  }
+}
+
+#[test]
+fn test_challenge() {
+  let challenge = Challenge::parse(r#"Bearer realm="https://ghcr.io/token",service="ghcr.io",scope="repository:homebrew/core/wget:pull,push""#);
+  assert_eq!(challenge, Some(Challenge::Bearer {
+    realm: "https://ghcr.io/token".to_string(),
+    service: Some("ghcr.io".to_string()),
+    scope: Some("repository:homebrew/core/wget:pull,push".to_string()),
+  }));
+  assert_eq!(Challenge::parse(r#"Basic realm="registry""#), Some(Challenge::Basic));
+  assert_eq!(RegistryAuth::scope_of("https://ghcr.io/v2/homebrew/core/wget/blobs/sha256:00"), Some("repository:homebrew/core/wget:pull".to_string()));
+}
+
+#[tokio::test]
+async fn test_registry_auth() {
+  use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+  use crate::{package::mirror::{MirrorServer, MirrorType}, tests::*};
+  init_logger(None);
+  let token_requests = Arc::new(AtomicUsize::new(0));
+  let counter = token_requests.clone();
+  let base = stand_in(move |req| {
+    let auth = req.headers.get("authorization").map(String::as_str);
+    if req.path.starts_with("/token?") {
+      counter.fetch_add(1, Ordering::SeqCst);
+      // user:pass
+      if auth != Some("Basic dXNlcjpwYXNz") || !req.path.contains("scope=repository%3Alocal%2Fwget%3Apull") {
+        return StandInResponse::status(403)
+      }
+      return StandInResponse::ok(r#"{"token":"tok-1","expires_in":300}"#)
+    }
+    match auth {
+      Some("Bearer tok-1") => StandInResponse::ok("blob"),
+      _ => StandInResponse::status(401).header("www-authenticate", &format!(
+        r#"Bearer realm="http://{}/token",service="stand-in",scope="repository:local/wget:pull""#, req.headers["host"]
+      )),
+    }
+  }).await;
+
+  let url = format!("{}/v2/local/wget/blobs/sha256:00", base);
+  let anonymous = MirrorServer::new(MirrorType::Oci, &format!("{}/v2/local", base), None);
+  let result = anonymous.client().unwrap().get(&url).await;
+  assert!(matches!(result, Err(crate::error::Error::RequestFailed { action: "token", .. })));
+
+  let credentials = Credentials { username: "user".to_string(), password: "pass".to_string() };
+  let mirror = MirrorServer::new(MirrorType::Oci, &format!("{}/v2/local", base), None).credentials(Some(credentials));
+  token_requests.store(0, Ordering::SeqCst);
+  for _ in 0..3 {
+    let resp = mirror.client().unwrap().get(&url).await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    assert_eq!(resp.text().await.unwrap(), "blob");
+  }
+  assert_eq!(token_requests.load(Ordering::SeqCst), 1);
+}
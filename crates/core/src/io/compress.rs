@@ -0,0 +1,167 @@
+//! api files could be transferred and stored compressed, e.g. `formula.json` is ~25MB but ~4MB in gzip.
+//! the format on disk is told by the extension (`formula.json.gz`), and converted from the transfer encoding while downloading.
+//! brotli needs the `brotli` feature, which pulls in the brotli crate.
+
+use std::{io::Read as _, path::{Path, PathBuf}};
+
+use tokio::io::AsyncWrite;
+
+use crate::error::{ErrorExt as _, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Compression {
+  #[serde(rename = "gzip")]
+  Gzip,
+  #[serde(rename = "br")]
+  Brotli,
+}
+
+pub type Writer = Box<dyn AsyncWrite + Unpin + Send>;
+
+impl Compression {
+  pub fn suffix(self) -> &'static str {
+    match self {
+      Self::Gzip => ".gz",
+      Self::Brotli => ".br",
+    }
+  }
+
+  /// `formula.json` => `formula.json.gz`
+  pub fn path(path: &Path, compression: Option<Self>) -> PathBuf {
+    match compression {
+      Some(compression) => super::read::tmp_path(path, compression.suffix()),
+      None => path.to_path_buf(),
+    }
+  }
+
+  pub fn from_path(path: &Path) -> Option<Self> {
+    match path.extension()?.to_str()? {
+      "gz" => Some(Self::Gzip),
+      "br" => Some(Self::Brotli),
+      _ => None,
+    }
+  }
+
+  /// value of `Content-Encoding`, None for identity or unknown encodings
+  pub fn from_encoding(encoding: &str) -> Option<Self> {
+    match encoding.trim() {
+      "gzip" | "x-gzip" => Some(Self::Gzip),
+      "br" => Some(Self::Brotli),
+      _ => None,
+    }
+  }
+
+  pub fn encoding(self) -> &'static str {
+    match self {
+      Self::Gzip => "gzip",
+      Self::Brotli => "br",
+    }
+  }
+
+  /// value of `Accept-Encoding` for the encodings we could decode
+  pub fn accept_encoding() -> &'static str {
+    if cfg!(feature = "brotli") { "br, gzip" } else { "gzip" }
+  }
+
+  fn unsupported(self) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Unsupported, format!("{} is not supported, enable the brotli feature", self.encoding()))
+  }
+
+  /// write through `writer` encodes data into this format
+  pub fn encoder(self, writer: Writer) -> std::io::Result<Writer> {
+    match self {
+      Self::Gzip => Ok(Box::new(async_compression::tokio::write::GzipEncoder::new(writer))),
+      #[cfg(feature = "brotli")]
+      Self::Brotli => Ok(Box::new(async_compression::tokio::write::BrotliEncoder::new(writer))),
+      #[cfg(not(feature = "brotli"))]
+      Self::Brotli => Err(self.unsupported()),
+    }
+  }
+
+  /// write through `writer` decodes data of this format
+  pub fn decoder(self, writer: Writer) -> std::io::Result<Writer> {
+    match self {
+      Self::Gzip => Ok(Box::new(async_compression::tokio::write::GzipDecoder::new(writer))),
+      #[cfg(feature = "brotli")]
+      Self::Brotli => Ok(Box::new(async_compression::tokio::write::BrotliDecoder::new(writer))),
+      #[cfg(not(feature = "brotli"))]
+      Self::Brotli => Err(self.unsupported()),
+    }
+  }
+
+  /// writer which takes data in `from` format, and writes `to` format into `writer`, data is untouched if formats are the same
+  pub fn transcoder(writer: Writer, from: Option<Self>, to: Option<Self>) -> std::io::Result<Writer> {
+    if from == to {
+      return Ok(writer)
+    }
+    let writer = match to {
+      Some(to) => to.encoder(writer)?,
+      None => writer,
+    };
+    match from {
+      Some(from) => from.decoder(writer),
+      None => Ok(writer),
+    }
+  }
+
+  fn decode(self, content: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    let mut result = Vec::new();
+    match self {
+      Self::Gzip => { flate2::read::GzDecoder::new(content.as_slice()).read_to_end(&mut result)?; },
+      #[cfg(feature = "brotli")]
+      Self::Brotli => {
+        use futures::AsyncReadExt as _;
+        let mut decoder = async_compression::futures::bufread::BrotliDecoder::new(futures::io::Cursor::new(content));
+        futures::executor::block_on(decoder.read_to_end(&mut result))?;
+      },
+      #[cfg(not(feature = "brotli"))]
+      Self::Brotli => return Err(self.unsupported()),
+    }
+    Ok(result)
+  }
+}
+
+/// read the whole file, decoded according to its extension
+pub fn read_decoded(path: &Path) -> Result<Vec<u8>> {
+  let content = std::fs::read(path).when(("read", path))?;
+  match Compression::from_path(path) {
+    Some(compression) => compression.decode(content).when(("decode", path)),
+    None => Ok(content),
+  }
+}
+
+/// write the whole content, encoded into `compression`
+pub async fn write_encoded(path: &Path, content: &[u8], compression: Option<Compression>) -> Result<()> {
+  use tokio::io::AsyncWriteExt as _;
+  let file = tokio::fs::File::create(path).await.when(("create", path))?;
+  let mut writer = Compression::transcoder(Box::new(file), None, compression).when(("create", path))?;
+  writer.write_all(content).await.when(("write", path))?;
+  writer.shutdown().await.when(("write", path))?;
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_transcoder() {
+  use tokio::io::AsyncWriteExt as _;
+  let dir = &*crate::tests::TestDir::new("test_transcoder");
+  let content = br#"[{"name":"wget"}]"#.repeat(100);
+  let write = |path: PathBuf, from: Option<Compression>, to: Option<Compression>, data: Vec<u8>| async move {
+    let file = tokio::fs::File::create(&path).await.unwrap();
+    let mut writer = Compression::transcoder(Box::new(file), from, to).unwrap();
+    writer.write_all(&data).await.unwrap();
+    writer.shutdown().await.unwrap();
+    path
+  };
+
+  // plain transfer stored as gzip
+  let gz = write(dir.join("formula.json.gz"), None, Some(Compression::Gzip), content.clone()).await;
+  let compressed = std::fs::read(&gz).unwrap();
+  assert!(compressed.len() < content.len());
+  assert_eq!(read_decoded(&gz).unwrap(), content);
+  // gzip transfer stored as plain
+  let plain = write(dir.join("formula.json"), Some(Compression::Gzip), None, compressed.clone()).await;
+  assert_eq!(read_decoded(&plain).unwrap(), content);
+  // gzip transfer stored as is
+  let kept = write(dir.join("kept.json.gz"), Some(Compression::Gzip), Some(Compression::Gzip), compressed.clone()).await;
+  assert_eq!(std::fs::read(&kept).unwrap(), compressed);
+}
@@ -0,0 +1,380 @@
+//! rewrite `PT_INTERP`, `DT_NEEDED` and `DT_RUNPATH`/`DT_RPATH` of 64-bit little-endian ELF files in process,
+//! like `patchelf --set-interpreter --replace-needed --set-rpath`.
+//! strings not longer than the old ones are replaced in place (padded with NUL),
+//! otherwise `.dynstr` (and `.interp`) are copied into a new `PT_LOAD` segment appended to the file, as patchelf does:
+//!   the program headers move there too (so there is room for the new entry),
+//!   its `vaddr - offset` is the one of the first `PT_LOAD`, as kernels before 5.18 take `AT_PHDR` as load bias + `e_phoff`,
+//!   the file is padded if needed, where patchelf shifts the content of executables instead,
+//!   old strings keep their offsets so symbols and version needs still resolve,
+//!   `DT_STRTAB`/`DT_STRSZ`, `PT_PHDR`, `PT_INTERP` and the section headers are pointed at the new copies,
+//!   and `vn_file` of version needs (`DT_VERNEED`) follows its renamed `DT_NEEDED`, or ld.so fails the version lookup.
+//!   nothing refers to the old `.interp` and `.dynstr` then, they are zeroed so their placeholders are gone too.
+//!
+//! see also:
+//!   https://github.com/NixOS/patchelf/blob/master/src/patchelf.cc
+//!   https://github.com/Homebrew/brew/blob/master/Library/Homebrew/extend/os/linux/keg_relocate.rb
+
+use std::ops::Range;
+
+use goblin::elf::Elf;
+
+use super::relocate::Relocations;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const PT_PHDR: u32 = 6;
+const PF_R: u32 = 4;
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
+const DT_STRSZ: u64 = 10;
+const DT_RPATH: u64 = 15;
+const DT_RUNPATH: u64 = 29;
+const DT_VERNEED: u64 = 0x6ffffffe;
+const DT_VERNEEDNUM: u64 = 0x6fffffff;
+const PHDR_SIZE: usize = 56;
+const SHDR_SIZE: usize = 64;
+const DYN_SIZE: usize = 16;
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+  u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+  u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+  u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn set_u16(data: &mut [u8], offset: usize, value: u16) {
+  data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn set_u32(data: &mut [u8], offset: usize, value: u32) {
+  data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn set_u64(data: &mut [u8], offset: usize, value: u64) {
+  data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+fn align(n: u64, to: u64) -> u64 {
+  n.div_ceil(to) * to
+}
+
+fn cstr(data: &[u8]) -> Option<&str> {
+  let end = data.iter().position(|&i| i == 0)?;
+  std::str::from_utf8(&data[..end]).ok()
+}
+
+/// file offset of a virtual address in a loaded segment
+fn to_offset(elf: &Elf, vaddr: u64) -> Option<usize> {
+  elf.program_headers.iter()
+    .find(|i| i.p_type == PT_LOAD && i.p_vaddr <= vaddr && vaddr < i.p_vaddr + i.p_filesz)
+    .map(|i| (vaddr - i.p_vaddr + i.p_offset) as usize)
+}
+
+/// file ranges of the interpreter and `.dynstr` in use, which [`rewrite`] takes care of
+pub fn string_ranges(elf: &Elf, len: usize) -> Vec<Range<usize>> {
+  let mut result = elf.program_headers.iter()
+    .filter(|i| i.p_type == PT_INTERP)
+    .map(|i| i.p_offset as usize..(i.p_offset + i.p_filesz) as usize)
+    .collect::<Vec<_>>();
+  if let Some(dynamic) = &elf.dynamic {
+    result.extend(to_offset(elf, dynamic.info.strtab as u64).map(|offset| offset..offset + dynamic.info.strsz));
+  }
+  result.into_iter().map(|i| i.start.min(len)..i.end.min(len)).collect()
+}
+
+/// a string to be replaced, either in `.dynstr` (referenced by a dynamic entry) or the interpreter
+struct Change {
+  /// file offset of the dynamic entry, None for the interpreter
+  entry: Option<usize>,
+  /// file offset of the old string
+  offset: usize,
+  old_len: usize,
+  new: String,
+}
+
+/// apply `reloc` (interpreter, needed libraries as links, runpaths as rpaths) to an ELF
+pub fn rewrite(data: &[u8], reloc: &Relocations) -> Result<Vec<u8>, String> {
+  let elf = Elf::parse(data).map_err(|e| format!("parse elf: {}", e))?;
+  if !elf.is_64 || !elf.little_endian {
+    return Err("only 64-bit little-endian elf is supported".to_string())
+  }
+  let to_offset = |vaddr: u64| to_offset(&elf, vaddr);
+
+  let mut changes = Vec::new();
+  if let Some(interp) = elf.program_headers.iter().find(|i| i.p_type == PT_INTERP) {
+    let offset = interp.p_offset as usize;
+    let old = data.get(offset..offset + interp.p_filesz as usize).and_then(cstr).ok_or("malformed interpreter")?;
+    if !reloc.interpreter.0.is_empty() && reloc.interpreter.0 == old {
+      changes.push(Change { entry: None, offset, old_len: interp.p_filesz as usize, new: reloc.interpreter.1.clone() });
+    }
+  }
+
+  let mut entries = Vec::new();
+  if let Some(dynamic) = elf.program_headers.iter().find(|i| i.p_type == PT_DYNAMIC) {
+    let start = dynamic.p_offset as usize;
+    for offset in (start..start + dynamic.p_filesz as usize).step_by(DYN_SIZE) {
+      let tag = data.get(offset..offset + DYN_SIZE).map(|_| u64_at(data, offset)).ok_or("dynamic section out of range")?;
+      if tag == DT_NULL {
+        break
+      }
+      entries.push((offset, tag, u64_at(data, offset + 8)));
+    }
+  }
+  let get = |tag| entries.iter().find(|i| i.1 == tag).map(|i| i.2);
+  let strtab = match (get(DT_STRTAB), get(DT_STRSZ)) {
+    (Some(vaddr), Some(size)) => Some((vaddr, to_offset(vaddr).ok_or("DT_STRTAB not in a loaded segment")?, size as usize)),
+    _ => None,
+  };
+  if let Some((_, strtab_offset, strsz)) = strtab {
+    let dynstr = data.get(strtab_offset..strtab_offset + strsz).ok_or("dynstr out of range")?;
+    for &(entry, tag, value) in &entries {
+      let new = match tag {
+        DT_NEEDED | DT_RPATH | DT_RUNPATH => {
+          let old = dynstr.get(value as usize..).and_then(cstr).ok_or("malformed string in dynstr")?;
+          let new = if tag == DT_NEEDED { reloc.links.get(old) } else { reloc.rpaths.get(old) };
+          new.map(|new| (old.len(), new))
+        },
+        _ => None,
+      };
+      if let Some((old_len, new)) = new {
+        changes.push(Change { entry: Some(entry), offset: strtab_offset + value as usize, old_len: old_len + 1, new: new.clone() });
+      }
+    }
+  }
+
+  // file offsets of `vn_file` in each Elf64_Verneed
+  let mut vn_files = Vec::new();
+  if let (Some(vaddr), Some(num)) = (get(DT_VERNEED), get(DT_VERNEEDNUM)) {
+    let mut offset = to_offset(vaddr).ok_or("DT_VERNEED not in a loaded segment")?;
+    for _ in 0..num {
+      data.get(offset..offset + 16).ok_or("verneed out of range")?;
+      vn_files.push(offset + 4);
+      match u32_at(data, offset + 12) {
+        0 => break,
+        next => offset += next as usize,
+      }
+    }
+  }
+
+  let mut result = data.to_vec();
+  if changes.iter().all(|i| i.new.len() < i.old_len) {
+    for change in &changes {
+      let region = &mut result[change.offset..change.offset + change.old_len];
+      region.fill(0);
+      region[..change.new.len()].copy_from_slice(change.new.as_bytes());
+    }
+    // the interpreter is read up to p_filesz, so shrink it
+    if let (Some(change), Some(index)) = (changes.iter().find(|i| i.entry.is_none()), elf.program_headers.iter().position(|i| i.p_type == PT_INTERP)) {
+      let p = elf.header.e_phoff as usize + index * PHDR_SIZE;
+      let size = change.new.len() as u64 + 1;
+      set_u64(&mut result, p + 32, size);
+      set_u64(&mut result, p + 40, size);
+      let old = &elf.program_headers[index];
+      update_section(&elf, &mut result, old.p_vaddr, old.p_offset, old.p_vaddr, size);
+    }
+    return Ok(result)
+  }
+  grow(&elf, data, result, &changes, strtab, &vn_files)
+}
+
+/// move program headers, interpreter and dynstr into a new segment at the end of file
+fn grow(elf: &Elf, data: &[u8], mut result: Vec<u8>, changes: &[Change], strtab: Option<(u64, usize, usize)>, vn_files: &[usize]) -> Result<Vec<u8>, String> {
+  let loads = elf.program_headers.iter().filter(|i| i.p_type == PT_LOAD).collect::<Vec<_>>();
+  let page = loads.iter().map(|i| i.p_align).max().unwrap_or(0x1000).max(0x1000);
+  let delta = loads.first().map(|i| i.p_vaddr.wrapping_sub(i.p_offset)).unwrap_or_default();
+  let vaddr_end = align(loads.iter().map(|i| i.p_vaddr + i.p_memsz).max().unwrap_or_default(), page);
+  let seg_offset = align(data.len() as u64, page).max(vaddr_end.saturating_sub(delta));
+  let seg_vaddr = seg_offset.wrapping_add(delta);
+
+  let phoff = u64_at(data, 32) as usize;
+  let phnum = u16_at(data, 56) as usize;
+  let mut segment = data.get(phoff..phoff + phnum * PHDR_SIZE).ok_or("program headers out of range")?.to_vec();
+  segment.resize((phnum + 1) * PHDR_SIZE, 0);
+
+  let interp = changes.iter().find(|i| i.entry.is_none()).map(|change| {
+    let offset = segment.len();
+    segment.extend_from_slice(change.new.as_bytes());
+    segment.push(0);
+    (offset, change.new.len() + 1)
+  });
+  let mut dynstr = None;
+  if let Some((_, strtab_offset, strsz)) = strtab.filter(|_| changes.iter().any(|i| i.entry.is_some())) {
+    let start = segment.len();
+    segment.extend_from_slice(&data[strtab_offset..strtab_offset + strsz]);
+    for change in changes.iter().filter(|i| i.entry.is_some()) {
+      let entry = change.entry.unwrap();
+      let new_offset = (segment.len() - start) as u64;
+      set_u64(&mut result, entry + 8, new_offset);
+      if u64_at(data, entry) == DT_NEEDED {
+        let old_offset = u64_at(data, entry + 8);
+        for &vn_file in vn_files.iter().filter(|&&i| u32_at(data, i) as u64 == old_offset) {
+          set_u32(&mut result, vn_file, new_offset as u32);
+        }
+      }
+      segment.extend_from_slice(change.new.as_bytes());
+      segment.push(0);
+    }
+    dynstr = Some((start, segment.len() - start));
+  }
+  let seg_size = segment.len() as u64;
+
+  // program headers, with the new PT_LOAD as the last one (highest vaddr)
+  for i in 0..phnum {
+    let p = i * PHDR_SIZE;
+    match u32_at(&segment, p) {
+      PT_PHDR => set_phdr(&mut segment, p, seg_offset, seg_vaddr, (phnum + 1) as u64 * PHDR_SIZE as u64),
+      PT_INTERP => if let Some((offset, len)) = interp {
+        set_phdr(&mut segment, p, seg_offset + offset as u64, seg_vaddr + offset as u64, len as u64);
+      },
+      _ => {},
+    }
+  }
+  let p = phnum * PHDR_SIZE;
+  segment[p..p + 4].copy_from_slice(&PT_LOAD.to_le_bytes());
+  segment[p + 4..p + 8].copy_from_slice(&PF_R.to_le_bytes());
+  set_phdr(&mut segment, p, seg_offset, seg_vaddr, seg_size);
+  set_u64(&mut segment, p + 48, page);
+
+  set_u64(&mut result, 32, seg_offset);
+  set_u16(&mut result, 56, (phnum + 1) as u16);
+  if let (Some((start, size)), Some((old_vaddr, _, _))) = (dynstr, strtab) {
+    if let Some(dynamic) = elf.program_headers.iter().find(|i| i.p_type == PT_DYNAMIC) {
+      let begin = dynamic.p_offset as usize;
+      for offset in (begin..begin + dynamic.p_filesz as usize).step_by(DYN_SIZE) {
+        match u64_at(&result, offset) {
+          DT_STRTAB => set_u64(&mut result, offset + 8, seg_vaddr + start as u64),
+          DT_STRSZ => set_u64(&mut result, offset + 8, size as u64),
+          DT_NULL => break,
+          _ => {},
+        }
+      }
+    }
+    update_section(elf, &mut result, old_vaddr, seg_offset + start as u64, seg_vaddr + start as u64, size as u64);
+  }
+  if let (Some((offset, len)), Some(old)) = (interp, elf.program_headers.iter().find(|i| i.p_type == PT_INTERP)) {
+    update_section(elf, &mut result, old.p_vaddr, seg_offset + offset as u64, seg_vaddr + offset as u64, len as u64);
+    result[old.p_offset as usize..(old.p_offset + old.p_filesz) as usize].fill(0);
+  }
+  if let (Some(_), Some((_, strtab_offset, strsz))) = (dynstr, strtab) {
+    result[strtab_offset..strtab_offset + strsz].fill(0);
+  }
+
+  result.resize(seg_offset as usize, 0);
+  result.extend_from_slice(&segment);
+  Ok(result)
+}
+
+fn set_phdr(segment: &mut [u8], p: usize, offset: u64, vaddr: u64, size: u64) {
+  set_u64(segment, p + 8, offset);
+  set_u64(segment, p + 16, vaddr);
+  set_u64(segment, p + 24, vaddr);
+  set_u64(segment, p + 32, size);
+  set_u64(segment, p + 40, size);
+}
+
+/// point the section header at `old_addr` to its new copy, so tools like readelf agree with the loader
+fn update_section(elf: &Elf, result: &mut [u8], old_addr: u64, offset: u64, addr: u64, size: u64) {
+  let shoff = elf.header.e_shoff as usize;
+  let Some(index) = elf.section_headers.iter().position(|i| i.sh_addr == old_addr && i.sh_addr != 0) else { return };
+  let s = shoff + index * SHDR_SIZE;
+  if result.len() < s + SHDR_SIZE {
+    return
+  }
+  set_u64(result, s + 16, addr);
+  set_u64(result, s + 24, offset);
+  set_u64(result, s + 32, size);
+}
+
+#[test]
+fn test_elf_rewrite() {
+  use crate::tests::*;
+  let interp = "@@HOMEBREW_PREFIX@@/lib/ld.so";
+  let lib = "@@HOMEBREW_PREFIX@@/opt/openssl@3/lib/libssl.so.3";
+  let runpath = "@@HOMEBREW_PREFIX@@/lib:$ORIGIN/../lib";
+  let data = sample_elf(interp, &[lib, "libc.so.6"], runpath);
+  let parsed = Elf::parse(&data).unwrap();
+  assert_eq!(parsed.interpreter, Some(interp));
+
+  // longer paths need a new segment
+  let reloc = Relocations {
+    interpreter: (interp.to_string(), "/home/linuxbrew/.linuxbrew/lib/ld.so".to_string()),
+    links: [(lib.to_string(), "/home/linuxbrew/.linuxbrew/opt/openssl@3/lib/libssl.so.3".to_string())].into(),
+    rpaths: [(runpath.to_string(), "/home/linuxbrew/.linuxbrew/lib:$ORIGIN/../lib".to_string())].into(),
+    ..Default::default()
+  };
+  let result = rewrite(&data, &reloc).unwrap();
+  assert!(result.len() > data.len());
+  let elf = Elf::parse(&result).unwrap();
+  assert_eq!(elf.interpreter, Some("/home/linuxbrew/.linuxbrew/lib/ld.so"));
+  assert_eq!(elf.libraries, ["/home/linuxbrew/.linuxbrew/opt/openssl@3/lib/libssl.so.3", "libc.so.6"]);
+  assert_eq!(elf.runpaths, ["/home/linuxbrew/.linuxbrew/lib:$ORIGIN/../lib"]);
+  assert_eq!(elf.program_headers.len(), parsed.program_headers.len() + 1);
+  let phdr = elf.program_headers.iter().find(|i| i.p_type == PT_PHDR).unwrap();
+  assert_eq!(phdr.p_offset, elf.header.e_phoff);
+  let load = elf.program_headers.iter().rfind(|i| i.p_type == PT_LOAD).unwrap();
+  assert!(load.p_offset <= phdr.p_offset && phdr.p_offset + phdr.p_filesz <= load.p_offset + load.p_filesz);
+  assert_eq!(load.p_offset % 0x1000, load.p_vaddr % 0x1000);
+  let first = elf.program_headers.iter().find(|i| i.p_type == PT_LOAD).unwrap();
+  assert_eq!(load.p_vaddr - load.p_offset, first.p_vaddr - first.p_offset);
+  // version needs follow the renamed library
+  let vn_files = |elf: &Elf| elf.verneed.as_ref().unwrap().iter().map(|i| elf.dynstrtab.get_at(i.vn_file).unwrap().to_string()).collect::<Vec<_>>();
+  assert_eq!(vn_files(&parsed), [lib, "libc.so.6"]);
+  assert_eq!(vn_files(&elf), ["/home/linuxbrew/.linuxbrew/opt/openssl@3/lib/libssl.so.3", "libc.so.6"]);
+
+  // shorter paths are replaced in place
+  let reloc = Relocations {
+    interpreter: (interp.to_string(), "/opt/pb/lib/ld.so".to_string()),
+    rpaths: [(runpath.to_string(), "/opt/pb/lib".to_string())].into(),
+    ..Default::default()
+  };
+  let result = rewrite(&data, &reloc).unwrap();
+  assert_eq!(result.len(), data.len());
+  let elf = Elf::parse(&result).unwrap();
+  assert_eq!(elf.interpreter, Some("/opt/pb/lib/ld.so"));
+  assert_eq!(elf.libraries, [lib, "libc.so.6"]);
+  assert_eq!(elf.runpaths, ["/opt/pb/lib"]);
+}
+
+/// grown binaries still run, both position independent and not
+#[cfg(target_os = "linux")]
+#[test]
+fn test_elf_rewrite_run() {
+  use std::process::Command;
+  let dest = &*crate::tests::TestDir::new("test_elf_rewrite_run");
+  std::fs::create_dir_all(dest).unwrap();
+  let dest = super::read::try_abs_path(dest).unwrap();
+  std::fs::write(dest.join("hello.c"), "#include <stdio.h>\nint main() { puts(\"hello\"); return 0; }\n").unwrap();
+  // the interpreter is reached through a path longer than the old one
+  let long = dest.join("very-long-directory-name-for-the-interpreter".repeat(2));
+  std::fs::create_dir_all(&long).unwrap();
+  for pie in ["-pie", "-no-pie"] {
+    let exe = dest.join(format!("hello{}", pie));
+    let status = Command::new("cc").args([pie, "-fPIE", "-Wl,--enable-new-dtags,-rpath,/x", "-o"]).arg(&exe).arg(dest.join("hello.c")).status().unwrap();
+    assert!(status.success());
+    let data = std::fs::read(&exe).unwrap();
+    let interp = Elf::parse(&data).unwrap().interpreter.unwrap().to_string();
+    let new_interp = long.join("ld.so");
+    std::os::unix::fs::symlink(&interp, &new_interp).ok();
+    let reloc = Relocations {
+      interpreter: (interp, new_interp.to_str().unwrap().to_string()),
+      rpaths: [("/x".to_string(), long.to_str().unwrap().to_string())].into(),
+      ..Default::default()
+    };
+    let result = rewrite(&data, &reloc).unwrap();
+    assert!(result.len() > data.len());
+    // the kernel here may compute AT_PHDR correctly anyway
+    let loads = Elf::parse(&result).unwrap().program_headers.into_iter().filter(|i| i.p_type == PT_LOAD).collect::<Vec<_>>();
+    assert_eq!(loads.last().unwrap().p_vaddr - loads.last().unwrap().p_offset, loads[0].p_vaddr - loads[0].p_offset, "{}", pie);
+    std::fs::write(&exe, &result).unwrap();
+    let output = Command::new(&exe).output().unwrap();
+    assert!(output.status.success(), "{} {:?}", pie, output);
+    assert_eq!(output.stdout, b"hello\n");
+  }
+}
@@ -1,31 +1,163 @@
-use std::path::{Path, PathBuf};
+use std::{path::{Path, PathBuf}, time::Instant};
 
-use crate::{error::{Error, ErrorExt, Result}, package::{mirror::MirrorServer, package::PkgBuild}, ui::{bar::FeedBar, EventListener}};
+use reqwest::{header, Method};
 
-use super::http::DownloadTask;
+use crate::{error::{Error, ErrorExt, Result}, package::{mirror::{MirrorServer, MirrorType}, oci::{self, BottleTab, ImageIndex, ImageManifest}, package::PkgBuild}, ui::{bar::FeedBar, EventListener}};
+
+use super::{health::{unix_now, MirrorHealth}, probe_cache::ProbeCache, http::{DownloadTask, MirrorClient, Validators}};
 
 pub struct MirrorLists {
   pub lists: Vec<MirrorServer>,
+  pub health: MirrorHealth,
+  pub probed: ProbeCache,
+}
+
+/// a candidate url of some mirror for the request
+pub struct MirrorUrl<'a> {
+  pub mirror: &'a MirrorServer,
+  pub client: MirrorClient,
+  pub url: String,
+}
+
+/// the final url to download, size and tab are known if resolved from OCI manifests
+#[derive(Debug, Clone)]
+pub struct Resolved {
+  pub url: String,
+  pub size: Option<u64>,
+  pub tab: Option<BottleTab>,
 }
 
 impl MirrorLists {
-  pub fn url_iter<'a>(&'a self, req: FetchReq) -> Box<dyn Iterator<Item = (reqwest::Client, String)> + Send + 'a> {
+  pub fn new(lists: Vec<MirrorServer>) -> Self {
+    Self { lists, health: Default::default(), probed: Default::default() }
+  }
+
+  pub fn health(self, health: MirrorHealth) -> Self {
+    Self { health, ..self }
+  }
+
+  pub fn probe_cache(self, probed: ProbeCache) -> Self {
+    Self { probed, ..self }
+  }
+
+  /// mirrors are tried in order of recent health, see [`MirrorHealth::rank`],
+  /// except the mirror which answered the probe of a package goes first, unless it's failing now
+  pub fn url_iter<'a>(&'a self, req: FetchReq) -> Box<dyn Iterator<Item = MirrorUrl<'a>> + Send + 'a> {
+    let mut ranked = self.health.rank(&self.lists);
+    if let FetchReq::Package(pkg) = &req {
+      let probed = self.probed.mirror(&pkg.sha256, self.lists.iter().map(|i| i.base_url.as_str()));
+      let healthy = |base_url: &str| self.health.get(base_url).is_none_or(|i| i.rank(unix_now()).0 == 0);
+      if let Some(pos) = probed.filter(|i| healthy(i)).and_then(|probed| ranked.iter().position(|i| i.base_url == probed)) {
+        let mirror = ranked.remove(pos);
+        ranked.insert(0, mirror);
+      }
+    }
     match req {
       FetchReq::Api(api) => {
-        let iter = self.lists.iter().filter_map(move |i| i.api_url(&api).map(|u| (i.client(), u)));
+        let iter = ranked.into_iter().filter_map(move |i| {
+          let url = i.api_url(&api)?;
+          Some(MirrorUrl { mirror: i, client: Self::client(i)?, url })
+        });
         return Box::new(iter)
       },
       FetchReq::Package(pkg) => {
-        let iter = self.lists.iter().map(move |i| (i.client(), i.package_url(&pkg)));
+        let iter = ranked.into_iter().filter_map(move |i| Some(MirrorUrl { mirror: i, client: Self::client(i)?, url: i.package_url(&pkg) }));
         return Box::new(iter)
       },
     }
   }
+  /// a mirror with broken network config is skipped, see [`Self::unavailable`]
+  pub fn client(mirror: &MirrorServer) -> Option<MirrorClient> {
+    mirror.client().map_err(|e| error!(base_url=mirror.base_url, error=%e, "skip mirror")).ok()
+  }
+  /// the error when no mirror could even be tried for `req`: why the client of the first mirror failed to build,
+  /// or [`Error::MirrorFailed`] if there is no mirror for it at all
+  pub fn unavailable(&self, req: FetchReq) -> Error {
+    self.lists.iter()
+      .filter(|i| match &req {
+        FetchReq::Api(api) => i.api_url(api).is_some(),
+        FetchReq::Package(_) => true,
+      })
+      .find_map(|i| i.client().err())
+      .unwrap_or(Error::MirrorFailed(req))
+  }
   pub fn len(&self) -> usize {
     self.lists.len()
   }
 }
 
+impl MirrorUrl<'_> {
+  /// packages on OCI mirrors are looked up by `{repo}:{tag}` through the image index and the platform manifest,
+  /// files on local mirrors are sized from metadata, other requests use the url as is.
+  pub async fn resolve(&self, req: &FetchReq) -> Result<Resolved> {
+    let pkg = match (self.mirror.server_type, req) {
+      (MirrorType::Oci | MirrorType::Ghcr, FetchReq::Package(pkg)) => pkg,
+      (MirrorType::Local, _) => return self.resolve_local(),
+      _ => return Ok(Resolved { url: self.url.clone(), size: None, tab: None }),
+    };
+    if let Some(resolved) = self.mirror.resolved.lock().unwrap().get(&pkg.sha256) {
+      return Ok(resolved.clone())
+    }
+    let resolved = self.resolve_oci(pkg).await?;
+    self.mirror.resolved.lock().unwrap().insert(pkg.sha256.clone(), resolved.clone());
+    Ok(resolved)
+  }
+
+  /// size from resolved manifests or metadata, otherwise ask the mirror with `HEAD`
+  pub async fn content_length(&self, resolved: &Resolved) -> Result<u64> {
+    if let Some(size) = resolved.size {
+      return Ok(size)
+    }
+    let url = &resolved.url;
+    let resp = self.client.head(url).await?.error_for_status().when(("head", url))?;
+    let size = resp.headers()
+      .get(header::CONTENT_LENGTH).ok_or_else(|| Error::parse_response_error("head", url, "CONTENT_LENGTH"))?
+      .to_str().map_err(Error::parse_response("head", url, "CONTENT_LENGTH.to_str"))?
+      .parse::<u64>().map_err(Error::parse_response("head", url, "CONTENT_LENGTH.parse"))?;
+    Ok(size)
+  }
+
+  fn resolve_local(&self) -> Result<Resolved> {
+    let path = reqwest::Url::parse(&self.url).ok().and_then(|url| url.to_file_path().ok())
+      .ok_or_else(|| Error::MalformedUrl(self.url.clone()))?;
+    let size = path.metadata().when(("metadata", &path))?.len();
+    Ok(Resolved { url: self.url.clone(), size: Some(size), tab: None })
+  }
+
+  async fn get_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T> {
+    let resp = self.client.send(Method::GET, url, |req| req.header(header::ACCEPT, oci::MEDIA_TYPES)).await?
+      .error_for_status().when(("get", url))?;
+    let body = resp.bytes().await.when(("get", url))?;
+    serde_json::from_slice(&body).map_err(Error::parse_response("get", url, std::any::type_name::<T>()))
+  }
+
+  #[tracing::instrument(level = "debug", skip_all, fields(package = %pkg.name, tag = %pkg.oci_tag()))]
+  async fn resolve_oci(&self, pkg: &PkgBuild) -> Result<Resolved> {
+    let repo = self.mirror.repository_url(pkg);
+    let index_url = format!("{}/manifests/{}", repo, pkg.oci_tag());
+    let index = self.get_json::<ImageIndex>(&index_url).await?;
+    let ref_name = pkg.oci_ref_name();
+    let descriptor = index.find(&ref_name).ok_or_else(|| Error::parse_response_error("get", &index_url, &ref_name))?;
+    let manifest_url = format!("{}/manifests/{}", repo, descriptor.digest);
+    let manifest = self.get_json::<ImageManifest>(&manifest_url).await?;
+    let layer = manifest.layers.first().ok_or_else(|| Error::parse_response_error("get", &manifest_url, "layers"))?;
+    // another bottle than the formula expects, let the next mirror try
+    if layer.digest != format!("sha256:{}", pkg.sha256) {
+      warn!(layer.digest, pkg.sha256, "digest not match");
+      return Err(Error::parse_response_error("get", &manifest_url, &format!("layer digest {} but expect sha256:{}", layer.digest, pkg.sha256)))
+    }
+    let tab = descriptor.annotations.get(oci::BREW_TAB).or_else(|| manifest.annotations.get(oci::BREW_TAB))
+      .and_then(|tab| serde_json::from_str::<BottleTab>(tab).map_err(|error| warn!(%error, "malformed tab")).ok());
+    let resolved = Resolved {
+      url: format!("{}/blobs/{}", repo, layer.digest),
+      size: Some(layer.size),
+      tab,
+    };
+    debug!(url=resolved.url, size=layer.size, "resolved");
+    Ok(resolved)
+  }
+}
+
 #[derive(Debug, Clone)]
 pub enum FetchReq {
   Api(String),
@@ -57,6 +189,22 @@ pub struct FetchState {
   pub max: u64,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchOutcome {
+  Fetched(FetchState),
+  /// the file at path is still up to date, see [`Validators`]
+  NotModified,
+}
+
+impl FetchOutcome {
+  pub fn fetched(self) -> Option<FetchState> {
+    match self {
+      Self::Fetched(state) => Some(state),
+      Self::NotModified => None,
+    }
+  }
+}
+
 impl FeedBar for FetchState {
   fn message(&self) -> Option<String> { None }
   fn position(&self) -> Option<u64> { Some(self.current as _) }
@@ -64,31 +212,55 @@ impl FeedBar for FetchState {
 }
 
 /// download json api from https://formulae.brew.sh/api/formula.json
+/// api requests are conditional on the file already at path (and its [`Validators`]), which is kept if not modified.
+/// they are transferred compressed if the mirror supports, and stored in the format told by the extension of path.
 #[tracing::instrument(level = "debug", skip_all, fields(mirrors.len=mirrors.lists.len(), req = %req, path = %path.as_ref().to_string_lossy()))]
-pub async fn fetch_remote<P: AsRef<Path>>(mirrors: &MirrorLists, req: FetchReq, path: P, tracker: impl EventListener<FetchState>) -> Result<()> {
+pub async fn fetch_remote<P: AsRef<Path>>(mirrors: &MirrorLists, req: FetchReq, path: P, tracker: impl EventListener<FetchState>) -> Result<FetchOutcome> {
   let filename = path.as_ref();
   if let Some(i) = path.as_ref().parent() {
     std::fs::create_dir_all(i).when(("create_dir_all", i))?;
   }
+  let (sha256, validators) = match &req {
+    FetchReq::Package(pkg) => (Some(pkg.sha256.clone()), None),
+    FetchReq::Api(_) => (None, Some(Validators::load(filename).unwrap_or_default())),
+  };
   let mut retrying = false;
-  for (client, url) in mirrors.url_iter(req.clone()) {
-    debug!(message="try mirror", url);
+  let mut tried = false;
+  for mirror_url in mirrors.url_iter(req.clone()) {
+    tried = true;
+    debug!(message="try mirror", url=mirror_url.url);
     if retrying {
-      info!(url, "download failed, retrying");
+      info!(url=mirror_url.url, "download failed, retrying");
     }
-    let mut task = DownloadTask::new(url, filename, None)?;
-    // TODO: keep partial download
-    match task.client(Some(client)).force(true).run(|e| tracker.on_event(e)).await {
-      Ok(state) => {
+    let start = Instant::now();
+    let result = async {
+      let resolved = mirror_url.resolve(&req).await?;
+      let mut task = DownloadTask::new(resolved.url, filename, sha256.clone())?;
+      // TODO: keep partial download
+      task.client(Some(mirror_url.client.clone())).force(true).validators(validators.clone()).encoding(sha256.is_none()).run(|e| tracker.on_event(e)).await
+    }.await;
+    match result {
+      Ok(FetchOutcome::Fetched(state)) => {
+        mirrors.health.record_transfer(&mirror_url.mirror.base_url, state.current, start.elapsed());
         tracker.on_event(state.clone());
-        return Ok(())
+        return Ok(FetchOutcome::Fetched(state))
+      },
+      Ok(FetchOutcome::NotModified) => {
+        mirrors.health.record_latency(&mirror_url.mirror.base_url, start.elapsed());
+        return Ok(FetchOutcome::NotModified)
       },
       Err(e) => {
         warn!(error=%e, message="download failed");
+        if e.is_mirror_down() {
+          mirrors.health.record_failure(&mirror_url.mirror.base_url);
+        }
         retrying = true;
       }
     }
   }
+  if !tried {
+    return Err(mirrors.unavailable(req))
+  }
   return Err(Error::MirrorFailed(req));
 }
 
@@ -110,3 +282,109 @@ async fn test_download_db() {
   info!(len=%std::fs::metadata(&target).unwrap().len());
   // std::fs::remove_file(target).unwrap();
 }
+
+#[tokio::test]
+async fn test_resolve_oci() {
+  use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+  use crate::tests::*;
+  init_logger(None);
+  let blob = b"bottle of wget".to_vec();
+  let pkg = sample_pkg("wget", "1.24.5", &blob);
+  let sha256 = pkg.sha256.clone();
+  let index = serde_json::json!({
+    "schemaVersion": 2,
+    "manifests": [
+      { "digest": "sha256:linux", "size": 10, "annotations": { "org.opencontainers.image.ref.name": "1.24.5.x86_64_linux" } },
+      { "digest": "sha256:sonoma", "size": 10, "annotations": {
+        "org.opencontainers.image.ref.name": "1.24.5.arm64_sonoma",
+        "sh.brew.tab": r#"{"homebrew_version":"4.2.11","changed_files":["bin/wget"],"arch":"arm64"}"#,
+      } },
+    ],
+  }).to_string();
+  let manifest = serde_json::json!({
+    "schemaVersion": 2,
+    "layers": [{ "digest": format!("sha256:{}", sha256), "size": blob.len() }],
+  }).to_string();
+  let blob_path = format!("/v2/local/wget/blobs/sha256:{}", sha256);
+  let heads = Arc::new(AtomicUsize::new(0));
+  let counter = heads.clone();
+  let body = blob.clone();
+  let base = stand_in(move |req| {
+    if req.method == "HEAD" {
+      counter.fetch_add(1, Ordering::SeqCst);
+    }
+    match req.path.as_str() {
+      "/v2/local/wget/manifests/1.24.5" => StandInResponse::ok(index.clone()),
+      "/v2/local/wget/manifests/sha256:sonoma" => StandInResponse::ok(manifest.clone()),
+      path if path == blob_path => StandInResponse::ok(body.clone()),
+      _ => StandInResponse::status(404),
+    }
+  }).await;
+
+  let mirrors = MirrorLists::new(vec![MirrorServer::new(MirrorType::Oci, &format!("{}/v2/local", base), None)]);
+  let url = crate::stage::probe::step(&mirrors, &pkg).await.unwrap();
+  assert_eq!(url.pkg_size, blob.len() as u64);
+  assert_eq!(url.tab.unwrap().changed_files, vec!["bin/wget".to_string()]);
+  assert_eq!(heads.load(Ordering::SeqCst), 0);
+
+  let dir = TestDir::new("test_resolve_oci");
+  let target = dir.join(&pkg.filename);
+  fetch_remote(&mirrors, FetchReq::Package(pkg.clone()), &target, ()).await.unwrap();
+  assert_eq!(std::fs::read(&target).unwrap(), blob);
+
+  // the manifest points to another bottle
+  let other = PkgBuild { sha256: "0".repeat(64), ..pkg };
+  assert!(matches!(crate::stage::probe::step(&mirrors, &other).await, Err(Error::MirrorFailed(_))));
+  let target = dir.join("other").join(&other.filename);
+  assert!(matches!(fetch_remote(&mirrors, FetchReq::Package(other), &target, ()).await, Err(Error::MirrorFailed(_))));
+  assert!(!target.exists());
+}
+
+#[tokio::test]
+async fn test_local_mirror() {
+  use crate::tests::*;
+  init_logger(None);
+  let root = TestDir::new("test_local_mirror");
+  let mirror_dir = root.join("mirror");
+  std::fs::create_dir_all(mirror_dir.join("api")).unwrap();
+  let blob = b"bottle of wget".to_vec();
+  let pkg = sample_pkg("wget", "1.24.5", &blob);
+  std::fs::write(mirror_dir.join(&pkg.filename), &blob).unwrap();
+  std::fs::write(mirror_dir.join("api/formula.json"), "[]").unwrap();
+
+  let mirror = MirrorServer::new(MirrorType::Local, &mirror_dir.to_string_lossy(), None);
+  assert!(mirror.base_url.starts_with("file:///"), "{}", mirror.base_url);
+  let mirrors = MirrorLists::new(vec![mirror]);
+  let url = crate::stage::probe::step(&mirrors, &pkg).await.unwrap();
+  assert_eq!(url.pkg_size, blob.len() as u64);
+
+  let states = std::sync::Mutex::new(Vec::new());
+  let target = root.join("cache").join(&pkg.filename);
+  fetch_remote(&mirrors, FetchReq::Package(pkg.clone()), &target, |e: FetchState| states.lock().unwrap().push(e)).await.unwrap();
+  assert_eq!(std::fs::read(&target).unwrap(), blob);
+  assert_eq!(states.lock().unwrap().last(), Some(&FetchState { current: blob.len() as u64, max: blob.len() as u64 }));
+
+  let target = root.join("cache/formula.json");
+  fetch_remote(&mirrors, FetchReq::Api("formula.json".to_string()), &target, ()).await.unwrap();
+  assert_eq!(std::fs::read_to_string(&target).unwrap(), "[]");
+
+  let broken = PkgBuild { sha256: "0".repeat(64), ..pkg };
+  let target = root.join("broken").join(&broken.filename);
+  let result = fetch_remote(&mirrors, FetchReq::Package(broken), &target, ()).await;
+  assert!(matches!(result, Err(Error::MirrorFailed(_))));
+  assert_eq!(std::fs::read(mirror_dir.join("wget-1.24.5.arm64_sonoma.bottle.tar.gz")).unwrap(), blob);
+}
+
+#[tokio::test]
+async fn test_mirror_unavailable() {
+  use crate::{package::mirror::NetworkOptions, tests::*};
+  let pkg = sample_pkg("wget", "1.24.5", b"bottle of wget");
+  let dir = TestDir::new("test_mirror_unavailable");
+  let network = NetworkOptions { ca_certs: vec![dir.join("missing.pem")], ..Default::default() };
+  let mirrors = MirrorLists::new(vec![MirrorServer::new(MirrorType::Bottle, "http://127.0.0.1:9", None).network(network)]);
+  let target = dir.join(&pkg.filename);
+  let result = fetch_remote(&mirrors, FetchReq::Package(pkg.clone()), &target, ()).await;
+  assert!(matches!(&result, Err(Error::IoFailed { filename, .. }) if filename.ends_with("missing.pem")), "{:?}", result.err());
+  let result = crate::stage::probe::step(&mirrors, &pkg).await;
+  assert!(matches!(&result, Err(Error::IoFailed { filename, .. }) if filename.ends_with("missing.pem")), "{:?}", result.err());
+}
@@ -0,0 +1,162 @@
+//! per-mirror health statistics, persisted in the cache dir (e.g. `cache/mirror_health.json`),
+//! so a dead mirror is tried last instead of costing a timeout on every package.
+//! latency is recorded when probing, throughput when downloading, both as moving averages.
+//! mirrors are ranked by recent failures, then latency, then throughput.
+
+use std::{cmp::Reverse, collections::BTreeMap, path::{Path, PathBuf}, sync::Mutex, time::{Duration, SystemTime}};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{error::Result, package::mirror::MirrorServer};
+
+use super::read::{load_state, save_state};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MirrorStats {
+  pub successes: u64,
+  pub failures: u64,
+  /// reset on success
+  pub consecutive_failures: u32,
+  /// time until the size of a package is known, in milliseconds
+  pub latency_ms: Option<f64>,
+  /// bytes per second of downloads
+  pub throughput: Option<f64>,
+  /// unix timestamps in seconds
+  pub last_success: Option<u64>,
+  pub last_failure: Option<u64>,
+}
+
+impl MirrorStats {
+  /// failures older than this are forgiven, so the mirror gets another chance
+  pub const RECOVER_SECS: u64 = 3600;
+  /// mirrors within the same latency bucket keep the config order
+  pub const LATENCY_BUCKET_MS: f64 = 100.;
+  /// and within the same throughput bucket (bytes per second), which only breaks ties of latency
+  pub const THROUGHPUT_BUCKET: f64 = 1024. * 1024.;
+
+  /// lower is better, unknown latency or throughput counts as the fastest so new mirrors are measured soon
+  pub fn rank(&self, now: u64) -> (u32, u64, Reverse<u64>) {
+    let failures = match self.last_failure {
+      Some(last) if now.saturating_sub(last) < Self::RECOVER_SECS => self.consecutive_failures,
+      _ => 0,
+    };
+    let latency = self.latency_ms.map(|i| (i / Self::LATENCY_BUCKET_MS) as u64).unwrap_or_default();
+    let throughput = self.throughput.map(|i| (i / Self::THROUGHPUT_BUCKET) as u64).unwrap_or(u64::MAX);
+    (failures, latency, Reverse(throughput))
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct MirrorHealth {
+  /// not persisted if None
+  pub path: Option<PathBuf>,
+  stats: Mutex<BTreeMap<String, MirrorStats>>,
+}
+
+impl MirrorHealth {
+  /// weight of the newest sample in moving averages
+  const SMOOTH: f64 = 0.3;
+
+  /// see [`load_state`]
+  pub fn load<P: AsRef<Path>>(path: P) -> Self {
+    let path = path.as_ref();
+    Self { path: Some(path.to_path_buf()), stats: Mutex::new(load_state(path)) }
+  }
+
+  pub fn save(&self) -> Result<()> {
+    let Some(path) = &self.path else { return Ok(()) };
+    save_state(path, &*self.stats.lock().unwrap())
+  }
+
+  pub fn get(&self, base_url: &str) -> Option<MirrorStats> {
+    self.stats.lock().unwrap().get(base_url).cloned()
+  }
+
+  /// healthy mirrors first, the config order is kept among mirrors of the same rank
+  pub fn rank<'a>(&self, mirrors: &'a [MirrorServer]) -> Vec<&'a MirrorServer> {
+    let now = unix_now();
+    let stats = self.stats.lock().unwrap();
+    let mut result = mirrors.iter().collect::<Vec<_>>();
+    result.sort_by_key(|i| stats.get(&i.base_url).map(|s| s.rank(now)).unwrap_or((0, 0, Reverse(u64::MAX))));
+    result
+  }
+
+  pub fn record_latency(&self, base_url: &str, latency: Duration) {
+    self.update(base_url, |stats| {
+      stats.latency_ms = Some(Self::average(stats.latency_ms, latency.as_secs_f64() * 1000.));
+      Self::succeed(stats);
+    })
+  }
+
+  pub fn record_transfer(&self, base_url: &str, bytes: u64, elapsed: Duration) {
+    self.update(base_url, |stats| {
+      if let Some(speed) = Some(elapsed.as_secs_f64()).filter(|&i| i > 0.).map(|i| bytes as f64 / i) {
+        stats.throughput = Some(Self::average(stats.throughput, speed));
+      }
+      Self::succeed(stats);
+    })
+  }
+
+  pub fn record_failure(&self, base_url: &str) {
+    self.update(base_url, |stats| {
+      stats.failures += 1;
+      stats.consecutive_failures += 1;
+      stats.last_failure = Some(unix_now());
+    })
+  }
+
+  fn update(&self, base_url: &str, f: impl FnOnce(&mut MirrorStats)) {
+    let mut stats = self.stats.lock().unwrap();
+    f(stats.entry(base_url.to_string()).or_default());
+  }
+
+  fn succeed(stats: &mut MirrorStats) {
+    stats.successes += 1;
+    stats.consecutive_failures = 0;
+    stats.last_success = Some(unix_now());
+  }
+
+  fn average(old: Option<f64>, sample: f64) -> f64 {
+    match old {
+      Some(old) => old * (1. - Self::SMOOTH) + sample * Self::SMOOTH,
+      None => sample,
+    }
+  }
+}
+
+pub(crate) fn unix_now() -> u64 {
+  SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|i| i.as_secs()).unwrap_or_default()
+}
+
+#[test]
+fn test_mirror_health() {
+  use crate::{package::mirror::MirrorType, tests::TestDir};
+  let dir = TestDir::new("test_mirror_health");
+  let path = dir.join("mirror_health.json");
+  let mirrors = ["http://a", "http://b", "http://c"].map(|i| MirrorServer::new(MirrorType::Bottle, i, None));
+  let health = MirrorHealth::load(&path);
+  let order = |health: &MirrorHealth| health.rank(&mirrors).iter().map(|i| i.base_url.as_str()).collect::<Vec<_>>();
+  assert_eq!(order(&health), ["http://a", "http://b", "http://c"]);
+
+  health.record_failure("http://a");
+  health.record_latency("http://b", Duration::from_millis(900));
+  health.record_latency("http://c", Duration::from_millis(20));
+  health.record_transfer("http://c", 1000, Duration::from_millis(500));
+  assert_eq!(order(&health), ["http://c", "http://b", "http://a"]);
+  assert_eq!(health.get("http://c").unwrap().throughput, Some(2000.));
+  health.save().unwrap();
+
+  let health = MirrorHealth::load(&path);
+  assert_eq!(order(&health), ["http://c", "http://b", "http://a"]);
+  health.record_latency("http://a", Duration::from_millis(10));
+  assert_eq!(health.get("http://a").unwrap().consecutive_failures, 0);
+  assert_eq!(order(&health), ["http://a", "http://c", "http://b"]);
+
+  // same latency bucket, the faster download wins
+  for _ in 0..10 {
+    health.record_latency("http://b", Duration::from_millis(10));
+  }
+  health.record_transfer("http://a", 1 << 20, Duration::from_secs(1));
+  health.record_transfer("http://b", 8 << 20, Duration::from_secs(1));
+  assert_eq!(order(&health), ["http://b", "http://a", "http://c"]);
+}
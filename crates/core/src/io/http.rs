@@ -1,12 +1,66 @@
 
-use std::path::PathBuf;
-use crate::{error::{Error, ErrorExt, Result}, ui::EventListener};
+use std::{path::{Path, PathBuf}, sync::Arc};
+use crate::{error::{Error, ErrorExt, IoErrorExt as _, Result}, ui::EventListener};
 
 use futures::StreamExt as _;
-use reqwest::{IntoUrl, Url};
-use tokio::io::AsyncWriteExt as _;
+use reqwest::{header, IntoUrl, Method, RequestBuilder, StatusCode, Url};
+use sha2::{Digest as _, Sha256};
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
 
-use super::{fetch::FetchState, read::tmp_path};
+use super::{auth::{Authorization, Challenge, RegistryAuth}, compress::{Compression, Writer}, fetch::{FetchOutcome, FetchState}, read::tmp_path};
+
+/// http client of a mirror, which would answer auth challenges of registries
+#[derive(Debug, Clone, Default)]
+pub struct MirrorClient {
+  pub inner: reqwest::Client,
+  pub auth: Option<Arc<RegistryAuth>>,
+}
+
+impl MirrorClient {
+  pub fn new(inner: reqwest::Client, auth: Option<Arc<RegistryAuth>>) -> Self {
+    Self { inner, auth }
+  }
+
+  pub async fn get(&self, url: &str) -> Result<reqwest::Response> {
+    self.send(Method::GET, url, |req| req).await
+  }
+
+  pub async fn head(&self, url: &str) -> Result<reqwest::Response> {
+    self.send(Method::HEAD, url, |req| req).await
+  }
+
+  /// `f` would be called again to build the request when retrying with auth
+  pub async fn send<F: Fn(RequestBuilder) -> RequestBuilder>(&self, method: Method, url: &str, f: F) -> Result<reqwest::Response> {
+    let action = match method {
+      Method::GET => "get",
+      Method::HEAD => "head",
+      _ => "request",
+    };
+    let Some(auth) = &self.auth else {
+      return f(self.inner.request(method, url)).send().await.when((action, url));
+    };
+    let scope = RegistryAuth::scope_of(url);
+    let mut req = f(self.inner.request(method.clone(), url));
+    if let Some(token) = scope.as_deref().and_then(|scope| auth.cached(scope)) {
+      req = req.bearer_auth(token);
+    }
+    let resp = req.send().await.when((action, url))?;
+    if resp.status() != StatusCode::UNAUTHORIZED {
+      return Ok(resp)
+    }
+    let Some(challenge) = resp.headers().get(header::WWW_AUTHENTICATE).and_then(|i| i.to_str().ok()).and_then(Challenge::parse) else {
+      return Ok(resp)
+    };
+    debug!(url, ?challenge, "unauthorized");
+    let req = f(self.inner.request(method, url));
+    let req = match auth.authorize(&self.inner, &challenge, scope.as_deref()).await? {
+      Some(Authorization::Basic(credentials)) => req.basic_auth(credentials.username, Some(credentials.password)),
+      Some(Authorization::Bearer(token)) => req.bearer_auth(token),
+      None => return Ok(resp),
+    };
+    req.send().await.when((action, url))
+  }
+}
 
 pub trait ErrorDownloadExt<T> {
   fn when_download(self, task: &DownloadTask) -> Result<T>;
@@ -18,15 +72,80 @@ impl<T> ErrorDownloadExt<T> for Result<T, reqwest::Error> {
   }
 }
 
-/// The download task would download url to filename, and verify sha256.
-/// it would first download to filename.tmp, then rename to filename.
+/// `ETag` and `Last-Modified` of a downloaded file, stored next to it as `{filename}.validators`,
+/// and sent back as `If-None-Match` and `If-Modified-Since` to the same url, so an unchanged file is not downloaded again.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Validators {
+  pub url: String,
+  pub etag: Option<String>,
+  pub last_modified: Option<String>,
+}
+
+impl Validators {
+  pub fn path(filename: &Path) -> PathBuf {
+    tmp_path(filename, ".validators")
+  }
+
+  /// None if either the file or its validators is missing
+  pub fn load(filename: &Path) -> Option<Self> {
+    if !filename.exists() {
+      return None
+    }
+    let path = Self::path(filename);
+    let content = std::fs::read(&path).ok()?;
+    serde_json::from_slice(&content).map_err(|error| warn!(path=%path.display(), %error, "malformed validators")).ok()
+  }
+
+  /// validators without etag or last_modified are useless, the stale file is removed instead
+  pub fn save(&self, filename: &Path) -> Result<()> {
+    let path = Self::path(filename);
+    if self.etag.is_none() && self.last_modified.is_none() {
+      return std::fs::remove_file(&path).ok_not_found_none().when(("remove", &path));
+    }
+    let content = serde_json::to_vec(self).when(("ser", "Validators", None))?;
+    std::fs::write(&path, content).when(("write", &path))
+  }
+
+  fn from_response(url: &Url, resp: &reqwest::Response) -> Self {
+    let get = |name| resp.headers().get(name).and_then(|i| i.to_str().ok()).map(str::to_string);
+    Self { url: url.to_string(), etag: get(header::ETAG), last_modified: get(header::LAST_MODIFIED) }
+  }
+
+  fn apply(&self, url: &Url, mut req: RequestBuilder) -> RequestBuilder {
+    if self.url != url.as_str() {
+      return req
+    }
+    if let Some(etag) = &self.etag {
+      req = req.header(header::IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = &self.last_modified {
+      req = req.header(header::IF_MODIFIED_SINCE, last_modified);
+    }
+    req
+  }
+}
+
+struct Transferred {
+  current: u64,
+  max: u64,
+  sha256: Option<String>,
+  validators: Validators,
+}
+
+/// The download task would download url (or copy `file://` url) to filename, and verify sha256.
+/// it would first download to filename.part, then rename to filename.
+/// sha256 is computed while chunks arrive, a mismatched file is moved to filename.broken.
 #[derive(Debug)]
 pub struct DownloadTask {
-  pub client: Option<reqwest::Client>,
+  pub client: Option<MirrorClient>,
   pub url: Url,
   pub filename: PathBuf,
   pub sha256: Option<String>,
   pub force: bool,
+  /// if set, the request is conditional on them, and validators of the response are saved next to filename
+  pub validators: Option<Validators>,
+  /// for api files: ask for compressed transfer, and store in the format told by the extension of filename, see [`Compression`]
+  pub encoding: bool,
 }
 
 impl Clone for DownloadTask {
@@ -37,6 +156,8 @@ impl Clone for DownloadTask {
       filename: self.filename.clone(),
       sha256: self.sha256.clone(),
       force: self.force.clone(),
+      validators: self.validators.clone(),
+      encoding: self.encoding,
     }
   }
 }
@@ -45,10 +166,10 @@ impl DownloadTask {
   pub fn new<U: IntoUrl, P: Into<PathBuf>>(url: U, filename: P, sha256: Option<String>) -> Result<Self> {
     let url = into_url(url)?;
     let filename = filename.into();
-    Ok(Self { client: None, url, filename, sha256, force: false })
+    Ok(Self { client: None, url, filename, sha256, force: false, validators: None, encoding: false })
   }
 
-  pub fn client(&mut self, client: Option<reqwest::Client>) -> &mut Self {
+  pub fn client(&mut self, client: Option<MirrorClient>) -> &mut Self {
     self.client = client;
     self
   }
@@ -58,39 +179,179 @@ impl DownloadTask {
     self
   }
 
+  pub fn validators(&mut self, validators: Option<Validators>) -> &mut Self {
+    self.validators = validators;
+    self
+  }
+
+  pub fn encoding(&mut self, encoding: bool) -> &mut Self {
+    self.encoding = encoding;
+    self
+  }
+
+  /// format of filename on disk, bottles (`.tar.gz`) are always stored as is
+  fn storage(&self) -> Option<Compression> {
+    self.encoding.then(|| Compression::from_path(&self.filename)).flatten()
+  }
+
+  /// the returned file is used to sync after the writer is shutdown
+  async fn create(&self, tmp_filename: &Path, from: Option<Compression>) -> Result<(Writer, tokio::fs::File)> {
+    let file = tokio::fs::File::create(tmp_filename).await.when(("create", tmp_filename))?;
+    let sync = file.try_clone().await.when(("create", tmp_filename))?;
+    let writer = Compression::transcoder(Box::new(file), from, self.storage()).when(("create", tmp_filename))?;
+    Ok((writer, sync))
+  }
+
   #[tracing::instrument(level = "trace", skip_all, fields(url = %self.url.as_str(), path = %self.filename.to_string_lossy()))]
-  pub async fn run(&self, tracker: impl EventListener<FetchState>) -> Result<FetchState> {
+  pub async fn run(&self, tracker: impl EventListener<FetchState>) -> Result<FetchOutcome> {
     if !self.force && self.filename.exists() {
       let length = self.filename.metadata().when(("metadata", &self.filename))?.len();
-      return Ok(FetchState { current: length, max: length })
+      return Ok(FetchOutcome::Fetched(FetchState { current: length, max: length }))
+    }
+    let tmp_filename = tmp_path(&self.filename, ".part");
+    let transferred = match self.url.scheme() {
+      "file" => self.copy_local(&tmp_filename, &tracker).await?,
+      _ => match self.download(&tmp_filename, &tracker).await? {
+        Some(transferred) => transferred,
+        None => return Ok(FetchOutcome::NotModified),
+      },
+    };
+    if let Some(expected) = self.sha256.as_ref().filter(|&i| Some(i) != transferred.sha256.as_ref()) {
+      let hash = transferred.sha256.unwrap_or_default();
+      let broken = tmp_path(&self.filename, ".broken");
+      warn!(url=%self.url, expected, hash, broken=%broken.display(), "hash not match");
+      tokio::fs::rename(&tmp_filename, &broken).await.when(("rename", &broken))?;
+      return Err(Error::ChecksumMismatch { filename: broken, expected: expected.clone(), actual: hash });
+    }
+    debug!(message="rename", from=%tmp_filename.display(), to=%self.filename.display());
+    tokio::fs::rename(&tmp_filename, &self.filename).await.when(("rename", &self.filename))?;
+    if self.validators.is_some() {
+      transferred.validators.save(&self.filename)?;
+    }
+    Ok(FetchOutcome::Fetched(FetchState { current: transferred.current, max: transferred.max }))
+  }
+
+  /// returns None if not modified since validators
+  async fn download(&self, tmp_filename: &Path, tracker: &impl EventListener<FetchState>) -> Result<Option<Transferred>> {
+    let client = self.client.clone().unwrap_or_default();
+    let resp = client.send(Method::GET, self.url.as_str(), |req| {
+      let req = match self.encoding {
+        true => req.header(header::ACCEPT_ENCODING, Compression::accept_encoding()),
+        false => req,
+      };
+      match &self.validators {
+        Some(validators) => validators.apply(&self.url, req),
+        None => req,
+      }
+    }).await?;
+    if resp.status() == StatusCode::NOT_MODIFIED && self.validators.is_some() {
+      debug!(url=%self.url, filename=%self.filename.display(), "not modified");
+      return Ok(None)
     }
-    let client = self.client.clone().unwrap_or_else(|| reqwest::Client::new());
-    let resp = client.get(self.url.clone()).send().await.when_download(&self)?;
     if !resp.status().is_success() {
       info!(url=%self.url, filename=%self.filename.display(), status_code=?resp.status(), "request failed");
-      return Err(std::io::Error::other(format!("download from {} failed with status {}", self.url, resp.status()))).when(("dowanlod", &self.filename))?;
     }
+    // keeps the status, so a 404 is not taken for a broken mirror
+    let resp = resp.error_for_status().when_download(self)?;
     let length = resp.content_length().unwrap_or(0);
+    let validators = Validators::from_response(&self.url, &resp);
+    let content_encoding = match self.encoding {
+      true => resp.headers().get(header::CONTENT_ENCODING).and_then(|i| i.to_str().ok()).and_then(Compression::from_encoding),
+      false => None,
+    };
     let mut partial_len = 0;
-    let tmp_filename = tmp_path(&self.filename, ".part");
-    debug!(message="download_to", tmp_filename=%tmp_filename.display());
-    let mut file = tokio::fs::File::create(&tmp_filename).await.when(("create", &tmp_filename))?;
+    debug!(message="download_to", tmp_filename=%tmp_filename.display(), ?content_encoding, storage=?self.storage());
+    let (mut file, sync) = self.create(tmp_filename, content_encoding).await?;
+    let mut hasher = Sha256::new();
     let mut stream = resp.bytes_stream();
     while let Some(bytes) = stream.next().await {
       let bytes = bytes.when_download(&self)?;
       partial_len += bytes.len() as u64;
-      file.write_all(&bytes).await.when(("write", &tmp_filename))?;
+      hasher.update(&bytes);
+      file.write_all(&bytes).await.when(("write", tmp_filename))?;
       // debug!(tracker=self.tracker.is_some(), partial_len);
       tracker.on_event(FetchState { current: partial_len as u64, max: length });
     }
-    file.sync_all().await.when(("sync", &tmp_filename))?;
-    debug!(message="rename", from=%tmp_filename.display(), to=%self.filename.display());
-    tokio::fs::rename(&tmp_filename, &self.filename).await.when(("rename", &self.filename))?;
-    Ok(FetchState { current: partial_len, max: length })
+    file.shutdown().await.when(("write", tmp_filename))?;
+    sync.sync_all().await.when(("sync", tmp_filename))?;
+    Ok(Some(Transferred { current: partial_len, max: length, sha256: Some(format!("{:x}", hasher.finalize())), validators }))
   }
-}
 
+  /// files of local mirrors are hardlinked if possible (the bottles are never modified in place),
+  /// or copied otherwise, e.g. the mirror is on another filesystem.
+  /// the file is read through to verify sha256 either way, unless no sha256 is expected.
+  async fn copy_local(&self, tmp_filename: &Path, tracker: &impl EventListener<FetchState>) -> Result<Transferred> {
+    let source = self.url.to_file_path().map_err(|_| Error::MalformedUrl(self.url.to_string()))?;
+    let length = source.metadata().when(("metadata", &source))?.len();
+    if tmp_filename.exists() {
+      std::fs::remove_file(tmp_filename).when(("remove", tmp_filename))?;
+    }
+    let from = self.encoding.then(|| Compression::from_path(&source)).flatten();
+    let transcode = from != self.storage();
+    let linked = !transcode && std::fs::hard_link(&source, tmp_filename).map_err(|error| debug!(%error, "hardlink failed, fallback to copy")).is_ok();
+    if linked && self.sha256.is_none() {
+      tracker.on_event(FetchState { current: length, max: length });
+      return Ok(Transferred { current: length, max: length, sha256: None, validators: Default::default() })
+    }
+    debug!(message="copy_to", source=%source.display(), tmp_filename=%tmp_filename.display(), linked);
+    let read_from = if linked { tmp_filename } else { source.as_path() };
+    let mut reader = tokio::fs::File::open(read_from).await.when(("open", read_from))?;
+    let mut writer = match linked {
+      true => None,
+      false => Some(self.create(tmp_filename, from).await?),
+    };
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1024 * 1024];
+    let mut partial_len = 0;
+    loop {
+      let n = reader.read(&mut buffer).await.when(("read", read_from))?;
+      if n == 0 { break }
+      partial_len += n as u64;
+      hasher.update(&buffer[..n]);
+      if let Some((writer, _)) = writer.as_mut() {
+        writer.write_all(&buffer[..n]).await.when(("write", tmp_filename))?;
+      }
+      tracker.on_event(FetchState { current: partial_len, max: length });
+    }
+    if let Some((mut writer, sync)) = writer {
+      writer.shutdown().await.when(("write", tmp_filename))?;
+      sync.sync_all().await.when(("sync", tmp_filename))?;
+    }
+    Ok(Transferred { current: partial_len, max: length, sha256: Some(format!("{:x}", hasher.finalize())), validators: Default::default() })
+  }
+}
+/// reqwest only accepts urls with host, `file://` urls of local mirrors are parsed as is
 fn into_url(url: impl IntoUrl) -> Result<Url> {
   let url_string = url.as_str().to_string();
-  url.into_url().map_err(|_| Error::MalformedUrl(url_string))
+  match Url::parse(&url_string) {
+    Ok(url) if url.scheme() == "file" => Ok(url),
+    _ => url.into_url().map_err(|_| Error::MalformedUrl(url_string)),
+  }
+}
+
+#[tokio::test]
+async fn test_download_sha256() {
+  use crate::tests::*;
+  init_logger(None);
+  let body = b"hello bottle".to_vec();
+  let hash = format!("{:x}", Sha256::digest(&body));
+  let base = stand_in(move |req| match req.path.as_str() {
+    "/hello" => StandInResponse::ok(body.clone()),
+    _ => StandInResponse::status(404),
+  }).await;
+  let dir = TestDir::new("test_download_sha256");
+  let target = dir.join("hello.bottle.tar.gz");
+
+  let state = DownloadTask::new(format!("{}/hello", base), &target, Some(hash)).unwrap()
+    .force(true).run(()).await.unwrap();
+  assert_eq!(state.fetched().unwrap().current, 12);
+  assert!(target.exists());
+
+  std::fs::remove_file(&target).unwrap();
+  let result = DownloadTask::new(format!("{}/hello", base), &target, Some("0".repeat(64))).unwrap()
+    .force(true).run(()).await;
+  assert!(matches!(result, Err(Error::ChecksumMismatch { .. })), "{:?}", result);
+  assert!(!target.exists());
+  assert!(!tmp_path(&target, ".part").exists());
+  assert!(tmp_path(&target, ".broken").exists());
 }
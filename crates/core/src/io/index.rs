@@ -0,0 +1,352 @@
+//! precompiled formula index (e.g. `cache/formula.idx`), so a lookup doesn't deserialize the whole `formula.json`.
+//! the file is memory-mapped, all integers are little-endian u64:
+//!   header: magic, source len, source mtime (nanos), records, keys, flags
+//!   records: (json offset, json len, name offset, name len, desc offset, desc len) per formula
+//!   keys: (key offset, key len, record) sorted by key, keys are names, full names, aliases and old names
+//!   strings of names, keys and descriptions.
+//! the json of each formula is decoded only when asked, it's sliced from the mapped `formula.json` by offset,
+//! unless the source is compressed (`formula.json.gz`), then the json follows the strings ([`FLAG_EMBEDDED`]).
+//! the index is rebuilt when the size or mtime of the source differs from the header,
+//! opening checks nothing else, reads out of range are malformed records instead.
+
+use std::{borrow::Cow, collections::HashMap, path::{Path, PathBuf}, time::SystemTime};
+
+use memmap2::Mmap;
+use serde::Deserialize;
+use serde_json::value::RawValue;
+
+use crate::{error::{ErrorExt as _, Result}, package::formula::Formula};
+
+use super::{compress::{read_decoded, Compression}, read::tmp_path};
+
+const MAGIC: &[u8; 8] = b"PBIDX002";
+/// json offsets are in the index instead of the source
+const FLAG_EMBEDDED: u64 = 1;
+const HEADER: usize = 6;
+const RECORD: usize = 6;
+const KEY: usize = 3;
+
+/// lookup formulas by name, implemented by the slice from [`read_formulas`](super::read::read_formulas) and [`FormulaIndex`]
+pub trait FormulaSource {
+  fn len(&self) -> usize;
+  fn is_empty(&self) -> bool { self.len() == 0 }
+  /// names win over aliases, aliases win over old names
+  fn find(&self, name: &str) -> Result<Option<Cow<'_, Formula>>>;
+}
+
+impl FormulaSource for [Formula] {
+  fn len(&self) -> usize { <[Formula]>::len(self) }
+
+  fn find(&self, name: &str) -> Result<Option<Cow<'_, Formula>>> {
+    let found = self.iter().find(|f| f.name == name || f.full_name == name)
+      .or_else(|| self.iter().find(|f| f.aliases.iter().any(|i| i == name)))
+      .or_else(|| self.iter().find(|f| f.oldname.as_deref() == Some(name) || f.oldnames.iter().any(|i| i == name)));
+    Ok(found.map(Cow::Borrowed))
+  }
+}
+
+impl FormulaSource for Vec<Formula> {
+  fn len(&self) -> usize { self.as_slice().len() }
+  fn find(&self, name: &str) -> Result<Option<Cow<'_, Formula>>> { self.as_slice().find(name) }
+}
+
+/// only the fields needed by the lookup table
+#[derive(Deserialize)]
+struct Keys {
+  name: String,
+  full_name: String,
+  #[serde(default)]
+  oldname: Option<String>,
+  #[serde(default)]
+  oldnames: Vec<String>,
+  #[serde(default)]
+  aliases: Vec<String>,
+  #[serde(default)]
+  desc: Option<String>,
+}
+
+pub struct FormulaIndex {
+  pub path: PathBuf,
+  mmap: Mmap,
+  /// `formula.json` which records point into, None if [`FLAG_EMBEDDED`]
+  source: Option<Mmap>,
+}
+
+impl FormulaIndex {
+  /// `formula.json` => `formula.idx`, next to the compressed variants too
+  pub fn path(source: &Path) -> PathBuf {
+    source.with_file_name("formula.idx")
+  }
+
+  /// open the index of `source`, rebuild it first if missing or stale
+  pub fn open<P: AsRef<Path>>(source: P) -> Result<Self> {
+    let source = source.as_ref();
+    let path = Self::path(source);
+    let stamp = Self::stamp(source)?;
+    match Self::map(&path) {
+      Ok(index) if index.header(1) == stamp.0 && index.header(2) == stamp.1 => return index.map_source(source),
+      Ok(_) => debug!(path=%path.display(), "formula index stale"),
+      Err(error) => debug!(path=%path.display(), %error, "formula index unavailable"),
+    }
+    Self::build(source)?;
+    Self::map(&path)?.map_source(source)
+  }
+
+  /// write the index of `source` to [`Self::path`], replacing the old one
+  #[tracing::instrument(level = "debug", skip_all, fields(source = %source.display()))]
+  pub fn build(source: &Path) -> Result<()> {
+    let path = Self::path(source);
+    let (len, mtime) = Self::stamp(source)?;
+    let content = read_decoded(source)?;
+    let embedded = Compression::from_path(source).is_some();
+    let raws = serde_json::from_slice::<Vec<&RawValue>>(&content).when(("de", "Vec<RawValue>", None))?;
+
+    let keys = raws.iter()
+      .map(|raw| serde_json::from_str::<Keys>(raw.get()).when(("de", "Keys", Some(raw.get()))))
+      .collect::<Result<Vec<_>>>()?;
+    let mut names = HashMap::<&str, (u8, usize)>::new();
+    // lower rank wins when a key is claimed twice
+    for (i, k) in keys.iter().enumerate() {
+      let claims = [(0, &k.name), (0, &k.full_name)].into_iter()
+        .chain(k.aliases.iter().map(|a| (1, a)))
+        .chain(k.oldname.iter().chain(&k.oldnames).map(|a| (2, a)));
+      for (rank, key) in claims {
+        match names.get(key.as_str()) {
+          Some(&(old, _)) if old <= rank => {},
+          _ => { names.insert(key.as_str(), (rank, i)); },
+        }
+      }
+    }
+    let mut sorted = names.into_iter().map(|(key, (_, i))| (key, i)).collect::<Vec<_>>();
+    sorted.sort();
+
+    let mut strings = Vec::new();
+    let mut push = |s: &str| {
+      let offset = strings.len();
+      strings.extend_from_slice(s.as_bytes());
+      (offset, s.len())
+    };
+    let key_table = sorted.iter().map(|&(key, i)| { let (o, l) = push(key); [o, l, i] }).collect::<Vec<_>>();
+    let text_table = keys.iter().map(|k| (push(&k.name), push(k.desc.as_deref().unwrap_or_default()))).collect::<Vec<_>>();
+
+    let strings_start = (HEADER + keys.len() * RECORD + key_table.len() * KEY) * 8;
+    let json_start = strings_start + strings.len();
+    let mut out = Vec::with_capacity(json_start + if embedded { content.len() } else { 0 });
+    out.extend_from_slice(MAGIC);
+    for i in [len, mtime, keys.len() as u64, key_table.len() as u64, if embedded { FLAG_EMBEDDED } else { 0 }] {
+      out.extend_from_slice(&i.to_le_bytes());
+    }
+    let mut json_offset = json_start;
+    for (raw, ((name_o, name_l), (desc_o, desc_l))) in raws.iter().zip(&text_table) {
+      let json_len = raw.get().len();
+      let offset = match embedded {
+        true => json_offset,
+        // raw values borrow from content
+        false => raw.get().as_ptr() as usize - content.as_ptr() as usize,
+      };
+      for i in [offset, json_len, strings_start + name_o, *name_l, strings_start + desc_o, *desc_l] {
+        out.extend_from_slice(&(i as u64).to_le_bytes());
+      }
+      json_offset += json_len;
+    }
+    for [o, l, i] in key_table {
+      for i in [strings_start + o, l, i] {
+        out.extend_from_slice(&(i as u64).to_le_bytes());
+      }
+    }
+    out.extend_from_slice(&strings);
+    if embedded {
+      for raw in &raws {
+        out.extend_from_slice(raw.get().as_bytes());
+      }
+    }
+    debug!(records=keys.len(), size=out.len(), "formula index built");
+
+    let tmp = tmp_path(&path, ".new");
+    std::fs::write(&tmp, out).when(("write", &tmp))?;
+    std::fs::rename(&tmp, &path).when(("rename", &path))?;
+    Ok(())
+  }
+
+  fn stamp(source: &Path) -> Result<(u64, u64)> {
+    let metadata = std::fs::metadata(source).when(("stat", source))?;
+    let mtime = metadata.modified().ok()
+      .and_then(|i| i.duration_since(SystemTime::UNIX_EPOCH).ok())
+      .map(|i| i.as_nanos() as u64).unwrap_or_default();
+    Ok((metadata.len(), mtime))
+  }
+
+  fn map(path: &Path) -> Result<Self> {
+    let file = std::fs::File::open(path).when(("open", path))?;
+    // the index is only replaced by rename, so the mapped file is never modified
+    let mmap = unsafe { Mmap::map(&file) }.when(("memmap", path))?;
+    let index = Self { path: path.to_path_buf(), mmap, source: None };
+    if !index.valid() {
+      return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "malformed formula index")).when(("read", path))
+    }
+    Ok(index)
+  }
+
+  /// map the source too if records point into it, it must still be the one indexed
+  fn map_source(mut self, source: &Path) -> Result<Self> {
+    if self.header(5) & FLAG_EMBEDDED != 0 {
+      return Ok(self)
+    }
+    let file = std::fs::File::open(source).when(("open", source))?;
+    let mmap = unsafe { Mmap::map(&file) }.when(("memmap", source))?;
+    if mmap.len() as u64 != self.header(1) {
+      return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "formula.json changed while opening")).when(("read", source))
+    }
+    self.source = Some(mmap);
+    Ok(self)
+  }
+
+  /// only the header and the size of tables, so opening doesn't depend on the number of formulas
+  fn valid(&self) -> bool {
+    if self.mmap.len() < HEADER * 8 || &self.mmap[..8] != MAGIC {
+      return false
+    }
+    let tables = (self.header(3) as usize).checked_mul(RECORD)
+      .zip((self.header(4) as usize).checked_mul(KEY))
+      .and_then(|(r, k)| (HEADER + r).checked_add(k)?.checked_mul(8));
+    matches!(tables, Some(end) if end <= self.mmap.len())
+  }
+
+  fn u64_at(&self, word: usize) -> u64 {
+    let start = word * 8;
+    u64::from_le_bytes(self.mmap[start..start + 8].try_into().unwrap())
+  }
+
+  /// the magic counts as field 0
+  fn header(&self, field: usize) -> u64 {
+    self.u64_at(field)
+  }
+
+  fn record_field(&self, record: usize, field: usize) -> u64 {
+    self.u64_at(HEADER + record * RECORD + field)
+  }
+
+  fn keys(&self) -> usize {
+    self.header(4) as usize
+  }
+
+  fn key_field(&self, key: usize, field: usize) -> u64 {
+    self.u64_at(HEADER + self.len() * RECORD + key * KEY + field)
+  }
+
+  /// empty if out of range
+  fn bytes(data: &[u8], offset: u64, len: u64) -> &[u8] {
+    offset.checked_add(len).and_then(|end| data.get(offset as usize..end as usize)).unwrap_or_default()
+  }
+
+  fn str(&self, offset: u64, len: u64) -> &str {
+    std::str::from_utf8(Self::bytes(&self.mmap, offset, len)).unwrap_or_default()
+  }
+
+  fn key(&self, key: usize) -> &str {
+    self.str(self.key_field(key, 0), self.key_field(key, 1))
+  }
+
+  pub fn len(&self) -> usize {
+    self.header(3) as usize
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// binary search over the keys
+  pub fn lookup(&self, name: &str) -> Option<usize> {
+    let (mut lo, mut hi) = (0, self.keys());
+    while lo < hi {
+      let mid = (lo + hi) / 2;
+      match self.key(mid).cmp(name) {
+        std::cmp::Ordering::Less => lo = mid + 1,
+        std::cmp::Ordering::Greater => hi = mid,
+        std::cmp::Ordering::Equal => return Some(self.key_field(mid, 2) as usize).filter(|&i| i < self.len()),
+      }
+    }
+    None
+  }
+
+  pub fn name(&self, record: usize) -> &str {
+    self.str(self.record_field(record, 2), self.record_field(record, 3))
+  }
+
+  pub fn desc(&self, record: usize) -> &str {
+    self.str(self.record_field(record, 4), self.record_field(record, 5))
+  }
+
+  /// decode the full formula of a record
+  pub fn record(&self, record: usize) -> Result<Formula> {
+    let data = self.source.as_deref().unwrap_or(&self.mmap);
+    let json = Self::bytes(data, self.record_field(record, 0), self.record_field(record, 1));
+    serde_json::from_slice(json).when(("de", "Formula", std::str::from_utf8(json).ok()))
+  }
+
+  /// case-insensitive substring match on names and descriptions, in the order of formula.json
+  pub fn search(&self, pattern: &str) -> Vec<usize> {
+    let pattern = pattern.to_lowercase();
+    (0..self.len())
+      .filter(|&i| self.name(i).to_lowercase().contains(&pattern) || self.desc(i).to_lowercase().contains(&pattern))
+      .collect()
+  }
+}
+
+impl FormulaSource for FormulaIndex {
+  fn len(&self) -> usize { FormulaIndex::len(self) }
+
+  fn find(&self, name: &str) -> Result<Option<Cow<'_, Formula>>> {
+    match self.lookup(name) {
+      Some(i) => Ok(Some(Cow::Owned(self.record(i)?))),
+      None => Ok(None),
+    }
+  }
+}
+
+#[test]
+fn test_formula_index() {
+  use crate::tests::*;
+  init_logger(None);
+  let dir = &*TestDir::new("test_formula_index");
+  std::fs::create_dir_all(dir).unwrap();
+  let source = dir.join("formula.json");
+  let mut wget = sample_formula("wget", "1.24.5", &["openssl@3"]);
+  wget["aliases"] = serde_json::json!(["gnu-wget"]);
+  let mut openssl = sample_formula("openssl@3", "3.2.1", &[]);
+  openssl["oldnames"] = serde_json::json!(["openssl"]);
+  openssl["aliases"] = serde_json::json!(["openssl"]);
+  std::fs::write(&source, serde_json::json!([wget, openssl]).to_string()).unwrap();
+
+  let index = FormulaIndex::open(&source).unwrap();
+  // formulas are not copied
+  assert!(std::fs::metadata(FormulaIndex::path(&source)).unwrap().len() < std::fs::metadata(&source).unwrap().len() / 2);
+  assert_eq!(index.len(), 2);
+  assert_eq!(index.lookup("gnu-wget"), Some(0));
+  assert_eq!(index.lookup("openssl"), Some(1));
+  assert_eq!(index.lookup("curl"), None);
+  assert_eq!(index.desc(1), "sample openssl@3");
+  assert_eq!(index.find("wget").unwrap().unwrap().dependencies, ["openssl@3"]);
+  assert_eq!(index.search("SAMPLE W"), [0]);
+  let formulas = crate::io::read::read_formulas(&source).unwrap();
+  assert_eq!(formulas.find("gnu-wget").unwrap().unwrap().name, "wget");
+
+  // rebuilt when formula.json changes
+  std::fs::write(&source, serde_json::json!([sample_formula("curl", "8.7.1", &[])]).to_string()).unwrap();
+  let index = FormulaIndex::open(&source).unwrap();
+  assert_eq!(index.len(), 1);
+  assert_eq!(index.lookup("curl"), Some(0));
+  assert_eq!(index.lookup("wget"), None);
+  // malformed index is rebuilt too
+  std::fs::write(FormulaIndex::path(&source), b"PBIDX001garbage").unwrap();
+  assert_eq!(FormulaIndex::open(&source).unwrap().lookup("curl"), Some(0));
+
+  // a compressed source can't be sliced, formulas are embedded then
+  let gz = dir.join("formula.json.gz");
+  let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+  std::io::Write::write_all(&mut encoder, &std::fs::read(&source).unwrap()).unwrap();
+  std::fs::write(&gz, encoder.finish().unwrap()).unwrap();
+  std::fs::remove_file(&source).unwrap();
+  let index = FormulaIndex::open(&gz).unwrap();
+  assert_eq!(index.find("curl").unwrap().unwrap().versions.stable, "8.7.1");
+}
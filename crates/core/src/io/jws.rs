@@ -0,0 +1,165 @@
+//! verify JWS signed api files like `formula.jws.json`, in the JSON serialization of RFC 7515:
+//!   `{"payload": "...", "signatures": [{"protected": "<base64url header>", "header": {"kid": "homebrew-1"}, "signature": "<base64url>"}]}`
+//! Homebrew signs with `PS512` and an unencoded payload (`"b64": false`, RFC 7797), both forms of payload are accepted.
+//! the public keys are looked up by `kid`, a signature by any configured key is enough.
+
+use std::{collections::BTreeMap, path::Path};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use rsa::{pkcs8::DecodePublicKey as _, pss::{Signature as PssSignature, VerifyingKey}, signature::Verifier as _, RsaPublicKey};
+use serde::Deserialize;
+use sha2::Sha512;
+
+use crate::error::{Error, ErrorExt as _, Result};
+
+#[derive(Default)]
+pub struct PublicKeys {
+  keys: BTreeMap<String, RsaPublicKey>,
+}
+
+impl std::fmt::Debug for PublicKeys {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_list().entries(self.keys.keys()).finish()
+  }
+}
+
+impl PublicKeys {
+  /// the Homebrew key is `Library/Homebrew/api/homebrew-1.pem` in the brew repository
+  pub const HOMEBREW_KID: &'static str = "homebrew-1";
+  /// vendored copy of `homebrew-1.pem`
+  pub const HOMEBREW_PEM: &'static [u8] = include_bytes!("homebrew-1.pem");
+
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// `pem` is a `BEGIN PUBLIC KEY` block
+  pub fn add_pem(&mut self, kid: &str, pem: &[u8]) -> std::result::Result<(), String> {
+    let pem = std::str::from_utf8(pem).map_err(|e| e.to_string())?;
+    self.keys.insert(kid.to_string(), RsaPublicKey::from_public_key_pem(pem).map_err(|e| e.to_string())?);
+    Ok(())
+  }
+
+  /// only the vendored Homebrew key, fails if `homebrew-1.pem` was not vendored into this build
+  pub fn homebrew() -> Result<Self> {
+    let filename = Path::new("homebrew-1.pem");
+    if Self::HOMEBREW_PEM.is_empty() {
+      return Err(Error::signature_invalid(filename, "no Homebrew key vendored in this build, set api_keys to its pem file".to_string()))
+    }
+    let mut keys = Self::new();
+    keys.add_pem(Self::HOMEBREW_KID, Self::HOMEBREW_PEM).map_err(|e| Error::signature_invalid(filename, format!("malformed vendored key: {}", e)))?;
+    Ok(keys)
+  }
+
+  pub fn load<P: AsRef<Path>>(mut self, kid: &str, path: P) -> Result<Self> {
+    let path = path.as_ref();
+    let pem = std::fs::read(path).when(("read", path))?;
+    self.add_pem(kid, &pem).map_err(|e| Error::signature_invalid(path, format!("malformed public key {}: {}", kid, e)))?;
+    Ok(self)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.keys.is_empty()
+  }
+}
+
+#[derive(Deserialize)]
+struct Jws {
+  payload: String,
+  signatures: Vec<Signature>,
+}
+
+#[derive(Deserialize)]
+struct Signature {
+  protected: String,
+  #[serde(default)]
+  header: Option<Header>,
+  signature: String,
+}
+
+#[derive(Deserialize)]
+struct Header {
+  #[serde(default)]
+  alg: Option<String>,
+  #[serde(default)]
+  kid: Option<String>,
+  #[serde(default)]
+  b64: Option<bool>,
+  #[serde(default)]
+  crit: Vec<String>,
+}
+
+/// check the signatures of `content`, and return the payload signed by one of `keys`
+pub fn verify(filename: &Path, content: &[u8], keys: &PublicKeys) -> Result<Vec<u8>> {
+  let invalid = |reason: String| Error::signature_invalid(filename, reason);
+  let jws = serde_json::from_slice::<Jws>(content).map_err(|e| invalid(format!("malformed jws: {}", e)))?;
+  let mut reasons = Vec::new();
+  for signature in &jws.signatures {
+    match verify_signature(&jws.payload, signature, keys) {
+      Ok(payload) => return Ok(payload),
+      Err(reason) => reasons.push(reason),
+    }
+  }
+  if reasons.is_empty() {
+    reasons.push("no signatures".to_string());
+  }
+  Err(invalid(reasons.join("; ")))
+}
+
+fn verify_signature(payload: &str, signature: &Signature, keys: &PublicKeys) -> std::result::Result<Vec<u8>, String> {
+  let protected = URL_SAFE_NO_PAD.decode(&signature.protected).map_err(|e| format!("malformed protected header: {}", e))?;
+  let protected = serde_json::from_slice::<Header>(&protected).map_err(|e| format!("malformed protected header: {}", e))?;
+  if protected.alg.as_deref() != Some("PS512") {
+    return Err(format!("unsupported alg {:?}", protected.alg))
+  }
+  if let Some(crit) = protected.crit.iter().find(|i| *i != "b64") {
+    return Err(format!("unsupported critical header {}", crit))
+  }
+  let kid = protected.kid.as_deref().or(signature.header.as_ref().and_then(|i| i.kid.as_deref())).ok_or("missing kid")?;
+  let key = keys.keys.get(kid).ok_or_else(|| format!("unknown kid {}", kid))?;
+  let sig = URL_SAFE_NO_PAD.decode(&signature.signature).map_err(|e| format!("malformed signature: {}", e))?;
+
+  let input = format!("{}.{}", signature.protected, payload);
+  // PS512 salts with the digest length, which is the default of the verifying key
+  let verified = PssSignature::try_from(sig.as_slice())
+    .is_ok_and(|sig| VerifyingKey::<Sha512>::new(key.clone()).verify(input.as_bytes(), &sig).is_ok());
+  if !verified {
+    return Err(format!("signature by {} mismatch", kid))
+  }
+  match protected.b64.unwrap_or(true) {
+    true => URL_SAFE_NO_PAD.decode(payload).map_err(|e| format!("malformed payload: {}", e)),
+    false => Ok(payload.as_bytes().to_vec()),
+  }
+}
+
+#[test]
+fn test_homebrew_key() {
+  let keys = PublicKeys::homebrew().unwrap();
+  assert!(keys.keys.contains_key(PublicKeys::HOMEBREW_KID));
+}
+
+#[test]
+fn test_jws_verify() {
+  use crate::tests::*;
+  let (private, public) = generate_key();
+  let keys = {
+    let mut keys = PublicKeys::new();
+    keys.add_pem("homebrew-1", &public).unwrap();
+    keys
+  };
+  let path = Path::new("formula.jws.json");
+  let payload = r#"[{"name":"wget"}]"#;
+
+  let signed = sign_jws(payload, "homebrew-1", &private, false);
+  assert_eq!(verify(path, signed.as_bytes(), &keys).unwrap(), payload.as_bytes());
+  let encoded = sign_jws(payload, "homebrew-1", &private, true);
+  assert_eq!(verify(path, encoded.as_bytes(), &keys).unwrap(), payload.as_bytes());
+
+  let tampered = signed.replace("wget", "evil");
+  assert!(matches!(verify(path, tampered.as_bytes(), &keys), Err(Error::SignatureInvalid { .. })));
+  let unknown = sign_jws(payload, "homebrew-2", &private, false);
+  assert!(matches!(verify(path, unknown.as_bytes(), &keys), Err(Error::SignatureInvalid { .. })));
+  let (other, _) = generate_key();
+  let forged = sign_jws(payload, "homebrew-1", &other, false);
+  assert!(matches!(verify(path, forged.as_bytes(), &keys), Err(Error::SignatureInvalid { .. })));
+}
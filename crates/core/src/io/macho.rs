@@ -0,0 +1,503 @@
+//! rewrite load commands of thin Mach-O files in process, like
+//!   `install_name_tool -id <new_id> -change <old_lib> <new_lib> -rpath <old_path> <new_path>`
+//!   `codesign --sign - --force --preserve-metadata=entitlements,flags,runtime`
+//! so bottles could be relocated on Linux too.
+//! new paths must fit into the header padding before the first section, as install_name_tool requires,
+//! the ad-hoc signature is a CodeDirectory (sha256 of each 4K page) and empty requirements,
+//! entitlements, flags (e.g. hardened runtime) and the runtime version of the old signature are carried over,
+//! requirements are not, as an ad-hoc signature has no designated requirement.
+//! universal (fat) files are rewritten slice by slice with [`rewrite_fat`], slices are laid out again as they may grow.
+//!
+//! see also:
+//!   https://opensource.apple.com/source/cctools/cctools-795/misc/install_name_tool.c.auto.html
+//!   https://github.com/apple-oss-distributions/xnu/blob/main/osfmk/kern/cs_blobs.h
+
+use goblin::mach::MachO;
+use sha2::{Digest, Sha256};
+
+use super::relocate::Relocations;
+
+const LC_SEGMENT: u32 = 0x1;
+const LC_LOAD_DYLIB: u32 = 0xc;
+const LC_ID_DYLIB: u32 = 0xd;
+const LC_SEGMENT_64: u32 = 0x19;
+const LC_CODE_SIGNATURE: u32 = 0x1d;
+const LC_LAZY_LOAD_DYLIB: u32 = 0x20;
+const LC_LOAD_WEAK_DYLIB: u32 = 0x8000_0018;
+const LC_RPATH: u32 = 0x8000_001c;
+const LC_REEXPORT_DYLIB: u32 = 0x8000_001f;
+const LC_LOAD_UPWARD_DYLIB: u32 = 0x8000_0023;
+
+const FAT_MAGIC: u32 = 0xcafe_babe;
+const FAT_HEADER: usize = 8;
+const FAT_ARCH: usize = 20;
+
+const MH_EXECUTE: u32 = 0x2;
+const MH_DYLIB: u32 = 0x6;
+const MH_BUNDLE: u32 = 0x8;
+
+const CSMAGIC_EMBEDDED_SIGNATURE: u32 = 0xfade_0cc0;
+const CSMAGIC_CODEDIRECTORY: u32 = 0xfade_0c02;
+const CSMAGIC_REQUIREMENTS: u32 = 0xfade_0c01;
+const CSSLOT_CODEDIRECTORY: u32 = 0;
+const CSSLOT_REQUIREMENTS: u32 = 2;
+/// entitlements and DER entitlements, kept from the old signature
+const CSSLOT_PRESERVED: [u32; 2] = [5, 7];
+const CS_ADHOC: u32 = 0x2;
+/// set by ld for the signature it generates, which this is not anymore
+const CS_LINKER_SIGNED: u32 = 0x2_0000;
+const CS_EXECSEG_MAIN_BINARY: u64 = 0x1;
+const CODEDIRECTORY_VERSION: u32 = 0x20400;
+const CODEDIRECTORY_HEADER: usize = 88;
+/// adds the runtime version and preEncryptOffset
+const CODEDIRECTORY_VERSION_RUNTIME: u32 = 0x20500;
+const CODEDIRECTORY_RUNTIME_HEADER: usize = 96;
+const PAGE_SHIFT: u8 = 12;
+const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
+const HASH_SIZE: usize = 32;
+const CS_HASHTYPE_SHA256: u8 = 2;
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+  u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn set_u32(data: &mut [u8], offset: usize, value: u32) {
+  data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn set_u64(data: &mut [u8], offset: usize, value: u64) {
+  data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+fn be_u32_at(data: &[u8], offset: usize) -> Option<u32> {
+  Some(u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn align(n: usize, to: usize) -> usize {
+  n.div_ceil(to) * to
+}
+
+fn cstr(data: &[u8]) -> Option<&str> {
+  let end = data.iter().position(|&i| i == 0).unwrap_or(data.len());
+  std::str::from_utf8(&data[..end]).ok()
+}
+
+fn segname(cmd: &[u8]) -> Option<&str> {
+  cstr(cmd.get(8..24)?)
+}
+
+/// the load command with the string at `name_offset` replaced, padded to `pad`
+fn replace_string(cmd: &[u8], name_offset: usize, new: &str, pad: usize) -> Vec<u8> {
+  let mut result = cmd[..name_offset].to_vec();
+  result.extend_from_slice(new.as_bytes());
+  result.push(0);
+  result.resize(align(result.len(), pad), 0);
+  let size = result.len() as u32;
+  set_u32(&mut result, 4, size);
+  result
+}
+
+/// apply `reloc` to a thin little-endian Mach-O, and sign it ad-hoc as `identifier` if it's an executable, dylib or bundle
+pub fn rewrite(data: &[u8], reloc: &Relocations, identifier: &str) -> Result<Vec<u8>, String> {
+  let macho = MachO::parse(data, 0).map_err(|e| format!("parse mach-o: {}", e))?;
+  if !macho.little_endian {
+    return Err("big-endian mach-o is not supported".to_string())
+  }
+  let (header_size, pad) = if macho.is_64 { (32, 8) } else { (28, 4) };
+  let old_end = header_size + macho.header.sizeofcmds as usize;
+
+  let mut commands = Vec::with_capacity(macho.load_commands.len() + 1);
+  let mut signature = None;
+  for lc in &macho.load_commands {
+    let cmd = data.get(lc.offset..lc.offset + lc.command.cmdsize()).ok_or("load command out of range")?;
+    let kind = u32_at(cmd, 0);
+    let new = match kind {
+      LC_ID_DYLIB | LC_LOAD_DYLIB | LC_LOAD_WEAK_DYLIB | LC_REEXPORT_DYLIB | LC_LOAD_UPWARD_DYLIB | LC_LAZY_LOAD_DYLIB | LC_RPATH => {
+        let name_offset = u32_at(cmd, 8) as usize;
+        let name = cmd.get(name_offset..).and_then(cstr).ok_or("malformed name in load command")?;
+        let new = match kind {
+          LC_ID_DYLIB => (!reloc.id.0.is_empty() && reloc.id.0 == name).then_some(&reloc.id.1),
+          LC_RPATH => reloc.rpaths.get(name),
+          _ => reloc.links.get(name),
+        };
+        new.map(|new| replace_string(cmd, name_offset, new, pad))
+      },
+      LC_CODE_SIGNATURE => {
+        signature = Some((commands.len(), u32_at(cmd, 8) as usize, u32_at(cmd, 12) as usize));
+        None
+      },
+      _ => None,
+    };
+    commands.push(new.unwrap_or_else(|| cmd.to_vec()));
+  }
+
+  let linkedit = commands.iter().position(|i| matches!(u32_at(i, 0), LC_SEGMENT | LC_SEGMENT_64) && segname(i) == Some("__LINKEDIT"));
+  let signable = matches!(macho.header.filetype, MH_EXECUTE | MH_DYLIB | MH_BUNDLE) && linkedit.is_some();
+  let (mut body, old_signature) = match signature {
+    Some((_, dataoff, datasize)) => {
+      let old = data.get(dataoff..dataoff + datasize).ok_or("code signature out of range")?;
+      (data[..dataoff].to_vec(), Some(old))
+    },
+    None => (data.to_vec(), None),
+  };
+  let signature = match signature {
+    Some((index, ..)) => Some(index),
+    None if signable => {
+      let mut cmd = vec![0; 16];
+      set_u32(&mut cmd, 0, LC_CODE_SIGNATURE);
+      set_u32(&mut cmd, 4, 16);
+      commands.push(cmd);
+      Some(commands.len() - 1)
+    },
+    None => None,
+  };
+
+  // load commands may grow into the padding, up to the first byte of content
+  let first_content = macho.segments.iter()
+    .flat_map(|segment| segment.sections().ok().into_iter().flatten())
+    .filter(|(section, _)| section.offset > 0 && !matches!(section.flags & 0xff, 0x1 | 0xc | 0x12))
+    .map(|(section, _)| section.offset as usize)
+    .chain(macho.segments.iter().filter(|i| i.fileoff > 0 && i.filesize > 0).map(|i| i.fileoff as usize))
+    .min().unwrap_or(body.len());
+  let sizeofcmds = commands.iter().map(Vec::len).sum::<usize>();
+  if header_size + sizeofcmds > first_content {
+    return Err(format!(
+      "load commands need {} bytes but only {} bytes are available before the first section, the binary should be linked with -headerpad_max_install_names",
+      header_size + sizeofcmds, first_content))
+  }
+
+  set_u32(&mut body, 16, commands.len() as u32);
+  set_u32(&mut body, 20, sizeofcmds as u32);
+  let mut offset = header_size;
+  for cmd in &commands {
+    body[offset..offset + cmd.len()].copy_from_slice(cmd);
+    offset += cmd.len();
+  }
+  body[offset..old_end.max(offset)].fill(0);
+
+  let Some(signature) = signature.filter(|_| signable) else {
+    return Ok(body)
+  };
+  let linkedit = linkedit.unwrap();
+  let (linkedit_off, linkedit_size) = segment_range(&commands[linkedit], macho.is_64);
+  let dataoff = align(body.len(), 16);
+  if linkedit_off + linkedit_size < body.len() as u64 {
+    return Err("__LINKEDIT is not at the end of file, cannot place the code signature".to_string())
+  }
+  let text = commands.iter().find(|i| matches!(u32_at(i, 0), LC_SEGMENT | LC_SEGMENT_64) && segname(i) == Some("__TEXT"))
+    .map(|i| segment_range(i, macho.is_64)).unwrap_or_default();
+  let preserved = old_signature.map(preserved_blobs).unwrap_or_default();
+  let (flags, runtime) = old_signature.and_then(code_directory_metadata).unwrap_or_default();
+  let builder = CodeSignature {
+    identifier,
+    code_limit: dataoff,
+    exec_seg: text,
+    main_binary: macho.header.filetype == MH_EXECUTE,
+    flags: flags & !CS_LINKER_SIGNED | CS_ADHOC,
+    runtime,
+    preserved,
+  };
+  let datasize = align(builder.len(), 16);
+
+  // sizes in load commands are signed too, so they are fixed before hashing
+  body.resize(dataoff, 0);
+  let sig_offset = header_size + commands[..signature].iter().map(Vec::len).sum::<usize>();
+  set_u32(&mut body, sig_offset + 8, dataoff as u32);
+  set_u32(&mut body, sig_offset + 12, datasize as u32);
+  let linkedit_offset = header_size + commands[..linkedit].iter().map(Vec::len).sum::<usize>();
+  let filesize = (dataoff + datasize) as u64 - linkedit_off;
+  let vmsize = align(filesize as usize, 0x4000) as u64;
+  if macho.is_64 {
+    set_u64(&mut body, linkedit_offset + 32, vmsize);
+    set_u64(&mut body, linkedit_offset + 48, filesize);
+  } else {
+    set_u32(&mut body, linkedit_offset + 28, vmsize as u32);
+    set_u32(&mut body, linkedit_offset + 36, filesize as u32);
+  }
+  let mut sig = builder.build(&body);
+  sig.resize(datasize, 0);
+  body.extend_from_slice(&sig);
+  Ok(body)
+}
+
+/// (fileoff, filesize) of a segment command
+fn segment_range(cmd: &[u8], is_64: bool) -> (u64, u64) {
+  match is_64 {
+    true => (u64::from_le_bytes(cmd[40..48].try_into().unwrap()), u64::from_le_bytes(cmd[48..56].try_into().unwrap())),
+    false => (u32_at(cmd, 32) as u64, u32_at(cmd, 36) as u64),
+  }
+}
+
+/// blobs of the old signature to keep, by slot type
+fn preserved_blobs(signature: &[u8]) -> Vec<(u32, Vec<u8>)> {
+  let mut result = Vec::new();
+  if be_u32_at(signature, 0) != Some(CSMAGIC_EMBEDDED_SIGNATURE) {
+    return result
+  }
+  let count = be_u32_at(signature, 8).unwrap_or_default() as usize;
+  for i in 0..count {
+    let (Some(kind), Some(offset)) = (be_u32_at(signature, 12 + i * 8), be_u32_at(signature, 16 + i * 8)) else { break };
+    let offset = offset as usize;
+    let Some(len) = be_u32_at(signature, offset + 4) else { continue };
+    if let (true, Some(blob)) = (CSSLOT_PRESERVED.contains(&kind), signature.get(offset..offset + len as usize)) {
+      result.push((kind, blob.to_vec()));
+    }
+  }
+  result
+}
+
+/// the CodeDirectory blob of an embedded signature
+fn code_directory(signature: &[u8]) -> Option<&[u8]> {
+  if be_u32_at(signature, 0) != Some(CSMAGIC_EMBEDDED_SIGNATURE) {
+    return None
+  }
+  let count = be_u32_at(signature, 8)? as usize;
+  let offset = (0..count).find_map(|i| match be_u32_at(signature, 12 + i * 8) {
+    Some(CSSLOT_CODEDIRECTORY) => be_u32_at(signature, 16 + i * 8),
+    _ => None,
+  })?;
+  let cd = signature.get(offset as usize..)?;
+  (be_u32_at(cd, 0) == Some(CSMAGIC_CODEDIRECTORY)).then_some(cd)
+}
+
+/// flags and runtime version (if any) of the old CodeDirectory
+fn code_directory_metadata(signature: &[u8]) -> Option<(u32, Option<u32>)> {
+  let cd = code_directory(signature)?;
+  let (version, flags) = (be_u32_at(cd, 8)?, be_u32_at(cd, 12)?);
+  let runtime = match version >= CODEDIRECTORY_VERSION_RUNTIME {
+    true => Some(be_u32_at(cd, CODEDIRECTORY_HEADER)?),
+    false => None,
+  };
+  Some((flags, runtime))
+}
+
+struct CodeSignature<'a> {
+  identifier: &'a str,
+  /// bytes before this are hashed
+  code_limit: usize,
+  /// fileoff and filesize of __TEXT
+  exec_seg: (u64, u64),
+  main_binary: bool,
+  flags: u32,
+  /// hardened runtime version, the CodeDirectory is 0x20500 if set
+  runtime: Option<u32>,
+  preserved: Vec<(u32, Vec<u8>)>,
+}
+
+impl CodeSignature<'_> {
+  fn requirements() -> Vec<u8> {
+    [CSMAGIC_REQUIREMENTS, 12, 0].iter().flat_map(|i| i.to_be_bytes()).collect()
+  }
+
+  fn special_slots(&self) -> usize {
+    self.preserved.iter().map(|(kind, _)| *kind as usize).chain([CSSLOT_REQUIREMENTS as usize]).max().unwrap_or_default()
+  }
+
+  fn code_slots(&self) -> usize {
+    self.code_limit.div_ceil(PAGE_SIZE)
+  }
+
+  fn header_len(&self) -> usize {
+    match self.runtime {
+      Some(_) => CODEDIRECTORY_RUNTIME_HEADER,
+      None => CODEDIRECTORY_HEADER,
+    }
+  }
+
+  fn code_directory_len(&self) -> usize {
+    self.header_len() + self.identifier.len() + 1 + (self.special_slots() + self.code_slots()) * HASH_SIZE
+  }
+
+  fn blobs(&self) -> usize {
+    2 + self.preserved.len()
+  }
+
+  fn len(&self) -> usize {
+    12 + self.blobs() * 8 + self.code_directory_len() + Self::requirements().len()
+      + self.preserved.iter().map(|(_, blob)| blob.len()).sum::<usize>()
+  }
+
+  /// `code` is the file up to `code_limit`
+  fn build(&self, code: &[u8]) -> Vec<u8> {
+    let requirements = Self::requirements();
+    let mut special = vec![[0u8; HASH_SIZE]; self.special_slots()];
+    special[CSSLOT_REQUIREMENTS as usize - 1] = Sha256::digest(&requirements).into();
+    for (kind, blob) in &self.preserved {
+      special[*kind as usize - 1] = Sha256::digest(blob).into();
+    }
+
+    let mut cd = Vec::with_capacity(self.code_directory_len());
+    let ident_offset = self.header_len();
+    let hash_offset = ident_offset + self.identifier.len() + 1 + special.len() * HASH_SIZE;
+    let version = if self.runtime.is_some() { CODEDIRECTORY_VERSION_RUNTIME } else { CODEDIRECTORY_VERSION };
+    for i in [CSMAGIC_CODEDIRECTORY, self.code_directory_len() as u32, version, self.flags,
+      hash_offset as u32, ident_offset as u32, special.len() as u32, self.code_slots() as u32, self.code_limit as u32] {
+      cd.extend_from_slice(&i.to_be_bytes());
+    }
+    cd.extend_from_slice(&[HASH_SIZE as u8, CS_HASHTYPE_SHA256, 0, PAGE_SHIFT]);
+    // spare2, scatterOffset, teamOffset, spare3
+    cd.extend_from_slice(&[0; 16]);
+    let exec_flags = if self.main_binary { CS_EXECSEG_MAIN_BINARY } else { 0 };
+    for i in [0, self.exec_seg.0, self.exec_seg.1, exec_flags] {
+      cd.extend_from_slice(&i.to_be_bytes());
+    }
+    if let Some(runtime) = self.runtime {
+      // runtime, preEncryptOffset
+      cd.extend_from_slice(&runtime.to_be_bytes());
+      cd.extend_from_slice(&[0; 4]);
+    }
+    cd.extend_from_slice(self.identifier.as_bytes());
+    cd.push(0);
+    for hash in special.iter().rev() {
+      cd.extend_from_slice(hash);
+    }
+    for page in code[..self.code_limit].chunks(PAGE_SIZE) {
+      cd.extend_from_slice(&Sha256::digest(page));
+    }
+
+    let mut blobs = vec![(CSSLOT_CODEDIRECTORY, cd), (CSSLOT_REQUIREMENTS, requirements)];
+    blobs.extend(self.preserved.iter().cloned());
+    let mut result = Vec::with_capacity(self.len());
+    for i in [CSMAGIC_EMBEDDED_SIGNATURE, self.len() as u32, blobs.len() as u32] {
+      result.extend_from_slice(&i.to_be_bytes());
+    }
+    let mut offset = 12 + blobs.len() * 8;
+    for (kind, blob) in &blobs {
+      result.extend_from_slice(&kind.to_be_bytes());
+      result.extend_from_slice(&(offset as u32).to_be_bytes());
+      offset += blob.len();
+    }
+    for (_, blob) in &blobs {
+      result.extend_from_slice(blob);
+    }
+    result
+  }
+}
+
+/// rewrite each slice of a fat file with `f(index, slice)` (None if unchanged), and lay them out again:
+/// in the original order, each aligned to its `2^align`, starting where the first one did.
+/// returns None if no slice changed.
+pub fn rewrite_fat<F>(data: &[u8], mut f: F) -> Result<Option<Vec<u8>>, String>
+where
+  F: FnMut(usize, &[u8]) -> Result<Option<Vec<u8>>, String>,
+{
+  if be_u32_at(data, 0) != Some(FAT_MAGIC) {
+    return Err("not a fat mach-o".to_string())
+  }
+  let count = be_u32_at(data, 4).unwrap_or_default() as usize;
+  let mut slices = Vec::with_capacity(count);
+  let mut changed = false;
+  for i in 0..count {
+    let arch = FAT_HEADER + i * FAT_ARCH;
+    let field = |j: usize| be_u32_at(data, arch + j * 4).map(|i| i as usize).ok_or("fat arch out of range");
+    let (offset, size, align) = (field(2)?, field(3)?, field(4)?);
+    let slice = data.get(offset..offset + size).ok_or_else(|| format!("slice {} out of range", i))?;
+    let new = f(i, slice).map_err(|e| format!("slice {}: {}", i, e))?;
+    changed |= new.is_some();
+    slices.push((offset, align.min(31), new.unwrap_or_else(|| slice.to_vec())));
+  }
+  if !changed {
+    return Ok(None)
+  }
+  let mut result = data[..FAT_HEADER + count * FAT_ARCH].to_vec();
+  let mut cursor = slices.iter().map(|i| i.0).min().unwrap_or(result.len()).max(result.len());
+  for (i, (_, align_shift, slice)) in slices.iter().enumerate() {
+    let offset = align(cursor, 1 << align_shift);
+    let arch = FAT_HEADER + i * FAT_ARCH;
+    let offset_u32 = u32::try_from(offset).map_err(|_| "fat file too large")?;
+    result[arch + 8..arch + 12].copy_from_slice(&offset_u32.to_be_bytes());
+    result[arch + 12..arch + 16].copy_from_slice(&(slice.len() as u32).to_be_bytes());
+    result.resize(offset, 0);
+    result.extend_from_slice(slice);
+    cursor = result.len();
+  }
+  Ok(Some(result))
+}
+
+/// check the page hashes of the CodeDirectory in the embedded signature, None if not signed
+pub fn check_signature(data: &[u8]) -> Result<Option<()>, String> {
+  let macho = MachO::parse(data, 0).map_err(|e| format!("parse mach-o: {}", e))?;
+  let Some(lc) = macho.load_commands.iter().find(|i| u32_at(data, i.offset) == LC_CODE_SIGNATURE) else { return Ok(None) };
+  let (dataoff, datasize) = (u32_at(data, lc.offset + 8) as usize, u32_at(data, lc.offset + 12) as usize);
+  let signature = data.get(dataoff..dataoff + datasize).ok_or("code signature out of range")?;
+  let cd = code_directory(signature).ok_or("missing code directory")?;
+  let field = |i: usize| be_u32_at(cd, 8 + i * 4).map(|i| i as usize).ok_or("malformed code directory");
+  let (hash_offset, code_slots, code_limit) = (field(2)?, field(5)?, field(6)?);
+  let (hash_size, hash_type, page_shift) = (cd[36] as usize, cd[37], cd[39]);
+  if hash_type != CS_HASHTYPE_SHA256 || hash_size != HASH_SIZE {
+    return Err(format!("unsupported hash type {}", hash_type))
+  }
+  let page_size = 1usize << page_shift;
+  let code = data.get(..code_limit).ok_or("code limit out of range")?;
+  if code.len().div_ceil(page_size) != code_slots {
+    return Err("code slots mismatch".to_string())
+  }
+  for (i, page) in code.chunks(page_size).enumerate() {
+    let expected = cd.get(hash_offset + i * HASH_SIZE..hash_offset + (i + 1) * HASH_SIZE).ok_or("hash out of range")?;
+    if Sha256::digest(page).as_slice() != expected {
+      return Err(format!("hash of page {} mismatch", i))
+    }
+  }
+  Ok(Some(()))
+}
+
+#[test]
+fn test_macho_rewrite() {
+  use crate::tests::*;
+  let id = "@@HOMEBREW_PREFIX@@/opt/wget/lib/libwget.1.dylib";
+  let lib = "@@HOMEBREW_PREFIX@@/opt/openssl@3/lib/libssl.3.dylib";
+  let rpath = "@@HOMEBREW_CELLAR@@/wget/1.24.5/lib";
+  let data = sample_macho(Some(id), &[lib, "/usr/lib/libSystem.B.dylib"], &[rpath], 256);
+  let reloc = Relocations {
+    id: (id.to_string(), "/opt/pacbrew/opt/wget/lib/libwget.1.dylib".to_string()),
+    links: [(lib.to_string(), "/opt/pacbrew/opt/openssl@3/lib/libssl.3.dylib".to_string())].into(),
+    rpaths: [(rpath.to_string(), "/opt/pacbrew/Cellar/wget/1.24.5/lib".to_string())].into(),
+    ..Default::default()
+  };
+
+  let result = rewrite(&data, &reloc, "libwget.1.dylib").unwrap();
+  let macho = MachO::parse(&result, 0).unwrap();
+  assert_eq!(macho.name, Some("/opt/pacbrew/opt/wget/lib/libwget.1.dylib"));
+  assert!(macho.libs.contains(&"/opt/pacbrew/opt/openssl@3/lib/libssl.3.dylib"));
+  assert!(macho.libs.contains(&"/usr/lib/libSystem.B.dylib"));
+  assert_eq!(macho.rpaths, ["/opt/pacbrew/Cellar/wget/1.24.5/lib"]);
+  assert_eq!(check_signature(&result), Ok(Some(())));
+  // section content is untouched
+  let text = |data: &[u8]| {
+    let macho = MachO::parse(data, 0).unwrap();
+    let sections = macho.segments.sections().flatten().map(|i| i.unwrap().1.to_vec()).collect::<Vec<_>>();
+    sections
+  };
+  assert_eq!(text(&result), text(&data));
+
+  // signing again replaces the signature instead of appending
+  let resigned = rewrite(&result, &Relocations::default(), "libwget.1.dylib").unwrap();
+  assert_eq!(resigned.len(), result.len());
+  assert_eq!(check_signature(&resigned), Ok(Some(())));
+  let mut tampered = resigned.clone();
+  let offset = MachO::parse(&resigned, 0).unwrap().segments.sections().flatten().next().unwrap().unwrap().0.offset as usize;
+  tampered[offset] ^= 0xff;
+  assert!(check_signature(&tampered).is_err());
+
+  // flags and runtime version of the old signature are kept, but not linker-signed
+  let signature = |data: &[u8]| {
+    let lc = MachO::parse(data, 0).unwrap().load_commands.iter().find(|i| u32_at(data, i.offset) == LC_CODE_SIGNATURE).unwrap().offset;
+    (u32_at(data, lc + 8) as usize, u32_at(data, lc + 12) as usize)
+  };
+  let (dataoff, _) = signature(&resigned);
+  let cd = dataoff + be_u32_at(&resigned, dataoff + 16).unwrap() as usize;
+  let mut hardened = resigned.clone();
+  hardened[cd + 8..cd + 12].copy_from_slice(&CODEDIRECTORY_VERSION_RUNTIME.to_be_bytes());
+  hardened[cd + 12..cd + 16].copy_from_slice(&(CS_ADHOC | CS_LINKER_SIGNED | 0x1_0000).to_be_bytes());
+  hardened[cd + 88..cd + 92].copy_from_slice(&0xe_0000u32.to_be_bytes());
+  let resigned = rewrite(&hardened, &Relocations::default(), "libwget.1.dylib").unwrap();
+  assert_eq!(check_signature(&resigned), Ok(Some(())));
+  let (dataoff, datasize) = signature(&resigned);
+  assert_eq!(code_directory_metadata(&resigned[dataoff..dataoff + datasize]), Some((CS_ADHOC | 0x1_0000, Some(0xe_0000))));
+
+  // no room in the header padding
+  let tight = sample_macho(Some(id), &[lib], &[], 0);
+  let long = Relocations { links: [(lib.to_string(), format!("/{}/libssl.3.dylib", "very-long-prefix".repeat(8)))].into(), ..Default::default() };
+  let error = rewrite(&tight, &long, "libwget.1.dylib").unwrap_err();
+  assert!(error.contains("headerpad_max_install_names"), "{}", error);
+}
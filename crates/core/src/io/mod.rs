@@ -1,8 +1,17 @@
 
 pub mod http;
+pub mod auth;
 pub mod read;
+pub mod compress;
+pub mod index;
+pub mod jws;
 pub mod fetch;
+pub mod health;
+pub mod probe_cache;
 pub mod untar;
 pub mod relocate;
+pub mod macho;
+pub mod elf;
+pub mod serve;
 
 pub use fetch::FetchState;
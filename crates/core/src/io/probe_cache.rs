@@ -0,0 +1,74 @@
+//! probe results persisted in the cache dir (e.g. `cache/probe_cache.json`), keyed by sha256.
+//! bottles are content-addressed, so the size and the mirror serving it would not change,
+//! an entry is dropped only when the download disagrees with the size, or it's too old.
+//! the download tries the mirror which answered the probe first, see [`MirrorLists::url_iter`](super::fetch::MirrorLists::url_iter).
+
+use std::{collections::BTreeMap, path::{Path, PathBuf}, sync::Mutex};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{error::Result, package::package::PackageUrl};
+
+use super::{health::unix_now, read::{load_state, save_state}};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProbeEntry {
+  pub url: PackageUrl,
+  /// base_url of the mirror which answered the probe
+  pub base_url: String,
+  /// unix timestamp in seconds
+  pub probed_at: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct ProbeCache {
+  /// not persisted if None
+  pub path: Option<PathBuf>,
+  entries: Mutex<BTreeMap<String, ProbeEntry>>,
+}
+
+impl ProbeCache {
+  /// mirrors may be reorganized, so probe again once in a while
+  pub const MAX_AGE_SECS: u64 = 30 * 24 * 3600;
+
+  /// see [`load_state`]
+  pub fn load<P: AsRef<Path>>(path: P) -> Self {
+    let path = path.as_ref();
+    Self { path: Some(path.to_path_buf()), entries: Mutex::new(load_state(path)) }
+  }
+
+  pub fn save(&self) -> Result<()> {
+    let Some(path) = &self.path else { return Ok(()) };
+    save_state(path, &*self.entries.lock().unwrap())
+  }
+
+  /// only entries probed from one of `base_urls` are returned, removed mirrors should not be used
+  pub fn get<'a>(&self, sha256: &str, base_urls: impl Iterator<Item = &'a str>) -> Option<PackageUrl> {
+    self.entry(sha256, base_urls, |entry| entry.url.clone())
+  }
+
+  /// base_url of the mirror which answered the probe, like [`get`](Self::get)
+  pub fn mirror<'a>(&self, sha256: &str, base_urls: impl Iterator<Item = &'a str>) -> Option<String> {
+    self.entry(sha256, base_urls, |entry| entry.base_url.clone())
+  }
+
+  fn entry<'a, T>(&self, sha256: &str, mut base_urls: impl Iterator<Item = &'a str>, f: impl FnOnce(&ProbeEntry) -> T) -> Option<T> {
+    let entries = self.entries.lock().unwrap();
+    let entry = entries.get(sha256)?;
+    if unix_now().saturating_sub(entry.probed_at) > Self::MAX_AGE_SECS || !base_urls.any(|i| i == entry.base_url) {
+      return None
+    }
+    Some(f(entry))
+  }
+
+  pub fn insert(&self, sha256: &str, base_url: &str, url: &PackageUrl) {
+    let entry = ProbeEntry { url: url.clone(), base_url: base_url.to_string(), probed_at: unix_now() };
+    self.entries.lock().unwrap().insert(sha256.to_string(), entry);
+  }
+
+  pub fn invalidate(&self, sha256: &str) {
+    if self.entries.lock().unwrap().remove(sha256).is_some() {
+      debug!(sha256, "probe cache invalidated");
+    }
+  }
+}
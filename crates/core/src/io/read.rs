@@ -2,7 +2,7 @@ use std::path::{Path, PathBuf};
 
 use serde::{de::DeserializeOwned, Serialize};
 
-use crate::error::{ErrorExt, Result};
+use crate::error::{ErrorExt, IoErrorExt as _, Result};
 
 /// append `suffix` to `path`
 pub fn tmp_path(path: &Path, suffix: &'static str) -> PathBuf {
@@ -13,9 +13,23 @@ pub fn tmp_path(path: &Path, suffix: &'static str) -> PathBuf {
   tmp
 }
 
+/// canonicalized if it exists, otherwise made absolute against the current dir,
+/// None if it's relative and the current dir is gone
+pub fn try_abs_path<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
+  let path = path.as_ref();
+  let path = match path.canonicalize() {
+    Ok(path) => path,
+    Err(_) if path.is_absolute() => path.to_path_buf(),
+    Err(_) => Path::new(".").canonicalize().ok()?.join(path),
+  };
+  Some(path_clean::clean(path))
+}
+
+/// `.gz` and `.br` files are decoded transparently
 pub fn read_json<T: DeserializeOwned, P: AsRef<Path>>(path: P) -> Result<T> {
   let path = path.as_ref();
-  let s = std::fs::read_to_string(path).when(("read", path))?;
+  let s = String::from_utf8(super::compress::read_decoded(path)?)
+    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)).when(("read", path))?;
   Ok(serde_json::from_str(&s).when(("de", std::any::type_name::<T>(), Some(&s)))?)
 }
 
@@ -30,6 +44,32 @@ pub fn read_formulas<P: AsRef<Path>>(path: P) -> Result<Vec<crate::package::form
   read_json(path)
 }
 
+/// a state file in the cache dir (e.g. `mirror_health.json`), a missing or malformed one starts from default
+pub fn load_state<T: DeserializeOwned + Default, P: AsRef<Path>>(path: P) -> T {
+  let path = path.as_ref();
+  match std::fs::read(path).ok_not_found() {
+    Ok(Some(content)) => serde_json::from_slice(&content).map_err(|error| warn!(path=%path.display(), %error, "malformed state file")).unwrap_or_default(),
+    Ok(None) => Default::default(),
+    Err(error) => {
+      warn!(path=%path.display(), %error, "read state file failed");
+      Default::default()
+    }
+  }
+}
+
+/// write a state file through a temporary one, so a crash never leaves it half written
+pub fn save_state<T: Serialize, P: AsRef<Path>>(path: P, state: &T) -> Result<()> {
+  let path = path.as_ref();
+  if let Some(i) = path.parent() {
+    std::fs::create_dir_all(i).when(("create_dir_all", i))?;
+  }
+  let content = serde_json::to_vec_pretty(state).when(("ser", std::any::type_name::<T>(), None))?;
+  let tmp = tmp_path(path, ".new");
+  std::fs::write(&tmp, content).when(("write", &tmp))?;
+  std::fs::rename(&tmp, path).when(("rename", path))?;
+  Ok(())
+}
+
 pub fn write_to_file<P: AsRef<Path>>(path: P, content: &[u8], force: bool) -> Result<u64> {
   let path = path.as_ref();
   if path.exists() && !force {
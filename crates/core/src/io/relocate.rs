@@ -8,34 +8,65 @@
 ///!     LC_LAZY_LOAD_DYLIB, LC_PREBOUND_DYLIB change_install_name,
 ///!   and for LC_RPATH change rpath
 ///! replace_text_in_files
+///!   all placeholders are found in a single scan (aho-corasick), files without any are skipped before decoding,
+///!   text is streamed into a new file which keeps the permissions and mtime of the old one.
+///!   for binary files (not utf-8), placeholders inside NUL terminated strings are replaced in place,
+///!   the rest of the string is moved forward and padded with NUL, like homebrew's `binary_replace`
+///!   static archives are replaced the same way member by member, so the symbol table stays valid
+///!   (objects have no dylib load commands to change).
+///! universal binaries are relocated slice by slice, see [`super::macho::rewrite_fat`].
 ///! when HOMEBREW_RELOCATE_BUILD_PREFIX is set, `relocate_build_prefix` would be additionally called.
 ///!
 ///! otool -l cache/a.out | grep -B3 "@@"
 ///! install_name_tool -id <new_id> -change <old_lib> <new_lib> -rpath <old_path> <new_path> cache/a.out
 ///! codesign --sign - --force --preserve-metadata=entitlements,requirements,flags,runtime cache/a.out
+///! both are done in process by [`super::macho`], so it works on Linux too.
 ///!
 ///! see also:
 ///!   https://github.com/Homebrew/brew/blob/master/Library/Homebrew/keg_relocate.rb
 ///!   https://github.com/Homebrew/brew/blob/master/Library/Homebrew/extend/os/mac/keg_relocate.rb
 ///!   https://opensource.apple.com/source/cctools/cctools-795/misc/install_name_tool.c.auto.html
+///!
+///! for Linux bottles, `PT_INTERP`, `DT_NEEDED` and `DT_RUNPATH`/`DT_RPATH` of ELF files are rewritten
+///! by [`super::elf`] like `patchelf` does.
+
+use std::{borrow::Cow, collections::{BTreeMap, BTreeSet}, fs::File, io::{BufWriter, Read, Write}, path::Path, sync::OnceLock};
+
+use aho_corasick::AhoCorasick;
+use goblin::{archive::Archive, elf::Elf, mach::{Mach, MachO, MultiArch, SingleArch}};
+use memchr::memmem;
+use memmap2::{Mmap, MmapOptions};
 
-use std::{borrow::Cow, collections::BTreeMap, path::{Path, PathBuf}};
+use crate::error::{Error, ErrorExt, Result};
 
-use goblin::mach::MachO;
-use memmap2::MmapOptions;
+use super::read::{tmp_path, try_abs_path};
 
-use crate::error::{ErrorExt, Result};
+const PLACEHOLDER_PREFIX: &[u8] = b"@@HOMEBREW_";
+pub const PREFIX: &str = "@@HOMEBREW_PREFIX@@";
+pub const CELLAR: &str = "@@HOMEBREW_CELLAR@@";
+pub const REPOSITORY: &str = "@@HOMEBREW_REPOSITORY@@";
+pub const LIBRARY: &str = "@@HOMEBREW_LIBRARY@@";
+pub const PERL: &str = "@@HOMEBREW_PERL@@";
+pub const JAVA: &str = "@@HOMEBREW_JAVA@@";
 
-/// We found these replacement in homebrew
-/// TODO: link here
-/// @@HOMEBREW_PREFIX@@ => ${prefix}/
-/// @@HOMEBREW_CELLAR@@ => ${prefix}/Cellar
-/// @@HOMEBREW_PERL@@ => /usr/bin/perl
-/// @@HOMEBREW_JAVA@@ => ${prefix}/opt/openjdk/libexec
-/// and would like to read prefix and cellar folder from config
+/// placeholders of homebrew bottles, see `Keg::Relocation` in keg_relocate.rb
+/// @@HOMEBREW_PREFIX@@ => ${prefix}
+/// @@HOMEBREW_CELLAR@@ => ${cellar}
+/// @@HOMEBREW_REPOSITORY@@ => ${prefix} on arm64 macOS, or ${prefix}/Homebrew on intel macOS and linux
+/// @@HOMEBREW_LIBRARY@@ => ${repository}/Library
+/// @@HOMEBREW_PERL@@ => /usr/bin/perl, or ${prefix}/opt/perl/bin/perl on linux
+/// @@HOMEBREW_JAVA@@ => ${prefix}/opt/openjdk/libexec/openjdk.jdk/Contents/Home, or ${prefix}/opt/openjdk/libexec on linux
+/// defaults could be changed by [`placeholder`](Self::placeholder), e.g. from `placeholders` in pacbrew.toml
 pub struct RelocationPattern {
   pub install_name: BTreeMap<String, String>,
   pub extra_name: BTreeMap<String, String>,
+  /// all placeholders of both tables, built on first use
+  matcher: OnceLock<Matcher>,
+}
+
+struct Matcher {
+  ac: AhoCorasick,
+  replacements: Vec<String>,
 }
 
 impl RelocationPattern {
@@ -44,16 +75,71 @@ impl RelocationPattern {
     let prefix = try_abs_path(&prefix).unwrap().to_str().unwrap().to_string();
     let cellar = try_abs_path(&cellar).unwrap().to_str().unwrap().to_string();
     let mut install_name = BTreeMap::new();
-    install_name.insert("@@HOMEBREW_PREFIX@@".to_string(), prefix.clone());
-    install_name.insert("@@HOMEBREW_CELLAR@@".to_string(), cellar.clone());
+    install_name.insert(PREFIX.to_string(), prefix.clone());
+    install_name.insert(CELLAR.to_string(), cellar.clone());
     let mut extra_name = BTreeMap::new();
-    extra_name.insert("@@HOMEBREW_PERL@@".to_string(), "/usr/bin/perl".to_string());
-    extra_name.insert("@@HOMEBREW_JAVA@@".to_string(), format!("{}/opt/openjdk/libexec", prefix));
+    extra_name.insert(REPOSITORY.to_string(), prefix.clone());
+    extra_name.insert(LIBRARY.to_string(), format!("{}/Library", prefix));
+    extra_name.insert(PERL.to_string(), "/usr/bin/perl".to_string());
+    extra_name.insert(JAVA.to_string(), format!("{}/opt/openjdk/libexec/openjdk.jdk/Contents/Home", prefix));
     Self {
-      install_name, extra_name,
+      install_name, extra_name, matcher: OnceLock::new(),
     }
   }
 
+  /// defaults for bottles of `arch`, e.g. `x86_64_linux` uses perl and openjdk from the prefix
+  pub fn target(mut self, arch: &str) -> Self {
+    let linux = arch.ends_with("_linux");
+    let prefix = &self.install_name[PREFIX];
+    let repository = match arch.starts_with("arm64_") && !linux {
+      true => prefix.clone(),
+      false => format!("{}/Homebrew", prefix),
+    };
+    self.extra_name.insert(LIBRARY.to_string(), format!("{}/Library", repository));
+    self.extra_name.insert(REPOSITORY.to_string(), repository);
+    if linux {
+      self.extra_name.insert(PERL.to_string(), format!("{}/opt/perl/bin/perl", prefix));
+      self.extra_name.insert(JAVA.to_string(), format!("{}/opt/openjdk/libexec", prefix));
+    }
+    self.matcher = OnceLock::new();
+    self
+  }
+
+  /// set or add a placeholder, `name` could be `HOMEBREW_PERL` or `@@HOMEBREW_PERL@@`
+  pub fn placeholder(mut self, name: &str, value: &str) -> Self {
+    let name = match name.starts_with("@@") {
+      true => name.to_string(),
+      false => format!("@@{}@@", name),
+    };
+    match self.install_name.get_mut(&name) {
+      Some(v) => *v = value.to_string(),
+      None => { self.extra_name.insert(name, value.to_string()); },
+    }
+    self.matcher = OnceLock::new();
+    self
+  }
+
+  /// `@@HOMEBREW_*@@` tokens in `data` which are not in the table, they would be left in place
+  pub fn unknown_placeholders(&self, data: &[u8]) -> BTreeSet<String> {
+    let mut result = placeholders(data);
+    result.retain(|token| !self.install_name.contains_key(token) && !self.extra_name.contains_key(token));
+    result
+  }
+
+  fn matcher(&self) -> &Matcher {
+    self.matcher.get_or_init(|| {
+      let (patterns, replacements): (Vec<_>, Vec<_>) = self.install_name.iter().chain(&self.extra_name).map(|(k, v)| (k.clone(), v.clone())).unzip();
+      // the default match kind is required by try_stream_replace_all, placeholders never overlap anyway
+      let ac = AhoCorasick::new(patterns).expect("placeholders too large");
+      Matcher { ac, replacements }
+    })
+  }
+
+  /// whether `data` contains any placeholder
+  pub fn is_match(&self, data: &[u8]) -> bool {
+    self.matcher().ac.is_match(data)
+  }
+
   pub fn replace_dylib<'a>(&self, name: &'a str) -> Cow<'a, str> {
     for (i, v) in &self.install_name {
       if name.starts_with(i) {
@@ -64,54 +150,174 @@ impl RelocationPattern {
   }
 
   pub fn replace_text<'a>(&self, s: &'a str) -> Cow<'a, str> {
-    let mut t = Cow::Borrowed(s);
-    // TODO: scan str only once
-    for (i, v) in self.install_name.iter().chain(&self.extra_name) {
-      t = Cow::Owned(t.replace(i, v));
+    let matcher = self.matcher();
+    match matcher.ac.is_match(s) {
+      true => Cow::Owned(matcher.ac.replace_all(s, &matcher.replacements)),
+      false => Cow::Borrowed(s),
     }
-    if t == s {
-      Cow::Borrowed(s)
-    } else {
-      t
+  }
+
+  /// copy `reader` to `writer` with placeholders replaced, without holding the content in memory
+  pub fn replace_stream<R: Read, W: Write>(&self, reader: R, writer: W) -> std::io::Result<()> {
+    let matcher = self.matcher();
+    matcher.ac.try_stream_replace_all(reader, writer, &matcher.replacements)
+  }
+
+  /// replace placeholders in each NUL terminated string of `data` without changing its size,
+  /// returns None if nothing found, or the offsets of strings that would grow
+  pub fn replace_binary(&self, data: &[u8]) -> std::result::Result<Option<Vec<u8>>, Vec<usize>> {
+    if !self.is_match(data) {
+      return Ok(None)
+    }
+    let mut result = None::<Vec<u8>>;
+    let mut overflow = Vec::new();
+    let mut start = 0;
+    for string in data.split(|&i| i == 0) {
+      let offset = start;
+      start += string.len() + 1;
+      let Some(replaced) = self.replace_bytes(string) else { continue };
+      if replaced.len() > string.len() {
+        overflow.push(offset);
+        continue
+      }
+      let result = result.get_or_insert_with(|| data.to_vec());
+      let region = &mut result[offset..offset + string.len()];
+      region.fill(0);
+      region[..replaced.len()].copy_from_slice(&replaced);
+    }
+    match overflow.is_empty() {
+      true => Ok(result),
+      false => Err(overflow),
+    }
+  }
+
+  /// like [`replace_binary`](Self::replace_binary) but only inside the members of a static archive (`.a`),
+  /// offsets are in `data`
+  pub fn replace_archive(&self, data: &[u8]) -> std::result::Result<Option<Vec<u8>>, Vec<usize>> {
+    let Ok(archive) = Archive::parse(data) else { return self.replace_binary(data) };
+    let mut result = None::<Vec<u8>>;
+    let mut overflow = Vec::new();
+    for member in (0..archive.len()).filter_map(|i| archive.get_at(i)) {
+      let offset = member.offset as usize;
+      let Some(content) = data.get(offset..offset + member.size()) else { continue };
+      match self.replace_binary(content) {
+        Ok(Some(replaced)) => result.get_or_insert_with(|| data.to_vec())[offset..offset + replaced.len()].copy_from_slice(&replaced),
+        Ok(None) => {},
+        Err(offsets) => overflow.extend(offsets.into_iter().map(|i| offset + i)),
+      }
+    }
+    match overflow.is_empty() {
+      true => Ok(result),
+      false => Err(overflow),
     }
   }
+
+  fn replace_bytes(&self, s: &[u8]) -> Option<Vec<u8>> {
+    let matcher = self.matcher();
+    matcher.ac.is_match(s).then(|| matcher.ac.replace_all_bytes(s, &matcher.replacements))
+  }
 }
 
 
-pub fn try_abs_path<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
-  let path = path.as_ref();
-  let path = match path.canonicalize() {
-    Ok(path) => path,
-    Err(_) => if path.is_absolute() {
-      path.to_path_buf()
-    } else {
-      match Path::new(".").canonicalize() {
-        Ok(cur) => cur.join(path),
-        Err(_) => unreachable!("current dir does not exist"),
-      }
+/// all `@@HOMEBREW_*@@` tokens in `data`
+pub fn placeholders(data: &[u8]) -> BTreeSet<String> {
+  let mut result = BTreeSet::new();
+  for start in memmem::find_iter(data, PLACEHOLDER_PREFIX) {
+    let rest = &data[start + PLACEHOLDER_PREFIX.len()..];
+    let len = rest.iter().take_while(|i| i.is_ascii_uppercase() || i.is_ascii_digit() || **i == b'_').count();
+    if len == 0 || !rest[len..].starts_with(b"@@") {
+      continue
     }
+    result.insert(String::from_utf8_lossy(&data[start..start + PLACEHOLDER_PREFIX.len() + len + 2]).into_owned());
+  }
+  result
+}
+
+/// write a new `filename` through `f` and move it over the old one, keeping its permissions and mtime
+pub fn replace_file<P, F>(filename: P, f: F) -> Result<()>
+where
+  P: AsRef<Path>,
+  F: FnOnce(&mut BufWriter<File>) -> std::io::Result<()>,
+{
+  let filename = filename.as_ref();
+  let metadata = std::fs::metadata(filename).when(("metadata", filename))?;
+  let tmp = tmp_path(filename, ".reloc");
+  let write = || -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    f(&mut writer)?;
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.set_modified(metadata.modified()?)?;
+    drop(file);
+    std::fs::set_permissions(&tmp, metadata.permissions())
   };
-  Some(path_clean::clean(path))
+  if let Err(e) = write() {
+    std::fs::remove_file(&tmp).ok();
+    return Err(e).when(("write", &tmp))
+  }
+  std::fs::rename(&tmp, filename).when(("rename", filename))?;
+  Ok(())
 }
 
-pub fn with_permission<P: AsRef<Path>, F: FnOnce() -> R, R>(filename: P, f: F) -> std::io::Result<R> {
-  let permission = std::fs::metadata(filename.as_ref())?.permissions();
-  let mut new_permission = permission.clone();
-  new_permission.set_readonly(false);
-  let result;
-  if permission.readonly() {
-    std::fs::set_permissions(filename.as_ref(), new_permission)?;
-    result = f();
-    std::fs::set_permissions(filename.as_ref(), permission)?;
-  } else {
-    result = f();
+/// a path in load commands of Mach-O, or in the dynamic section of ELF
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadPath<'a> {
+  /// `LC_ID_DYLIB`
+  Id(&'a str),
+  /// `PT_INTERP` of ELF
+  Interpreter(&'a str),
+  /// dylibs, or `DT_NEEDED` of ELF
+  Link(&'a str),
+  /// `LC_RPATH`, or `DT_RUNPATH`/`DT_RPATH` of ELF which is a `:` separated list
+  Rpath(&'a str),
+}
+
+impl<'a> LoadPath<'a> {
+  pub fn from_macho(file: &MachO<'a>) -> Vec<Self> {
+    let mut result = file.name.map(Self::Id).into_iter().collect::<Vec<_>>();
+    // the first one is always "self"
+    result.extend(file.libs.iter().skip(1).map(|&i| Self::Link(i)));
+    result.extend(file.rpaths.iter().map(|&i| Self::Rpath(i)));
+    result
   }
-  Ok(result)
+
+  pub fn from_elf(file: &Elf<'a>) -> Vec<Self> {
+    let mut result = file.interpreter.map(Self::Interpreter).into_iter().collect::<Vec<_>>();
+    result.extend(file.libraries.iter().map(|&i| Self::Link(i)));
+    result.extend(file.runpaths.iter().chain(&file.rpaths).map(|&i| Self::Rpath(i)));
+    result
+  }
+
+  /// load paths of a thin Mach-O, of every Mach-O slice of a universal binary, or of an ELF, None if it's none of them
+  pub fn parse(data: &'a [u8]) -> Option<Vec<Self>> {
+    match Mach::parse(data) {
+      Ok(Mach::Binary(file)) => Some(Self::from_macho(&file)),
+      Ok(Mach::Fat(fat)) if is_universal(&fat, data) => Some(fat.into_iter()
+        .filter_map(|slice| match slice {
+          Ok(SingleArch::MachO(file)) => Some(Self::from_macho(&file)),
+          _ => None,
+        })
+        .flatten().collect()),
+      _ => Elf::parse(data).ok().map(|file| Self::from_elf(&file)),
+    }
+  }
+
+  pub fn path(&self) -> &'a str {
+    match *self {
+      Self::Id(i) | Self::Interpreter(i) | Self::Link(i) | Self::Rpath(i) => i,
+    }
+  }
+}
+
+/// java class files share the magic, their version makes an absurd number of arches
+fn is_universal(fat: &MultiArch, data: &[u8]) -> bool {
+  fat.arches().is_ok_and(|i| i.len() < 45 && i.iter().all(|i| (i.offset as usize + i.size as usize) <= data.len()))
 }
 
 #[derive(Default, Clone, Debug)]
 pub struct Relocations {
   pub id: (String, String),
+  /// `PT_INTERP` of ELF
+  pub interpreter: (String, String),
   pub links: BTreeMap<String, String>,
   pub rpaths: BTreeMap<String, String>,
 }
@@ -119,108 +325,207 @@ pub struct Relocations {
 impl Relocations {
   pub fn from_macho(file: &MachO, pattern: &RelocationPattern) -> Result<Self> {
     let mut result = Self::default();
-    if let Some(name) = file.name {
-      if let Cow::Owned(new_name) = pattern.replace_dylib(name) {
-        // TODO: check size
-        // let new_len = new_name.len() as u32 / 8 + 1;
-        // new_sizeofcmds = new_sizeofcmds + new_len - name.len() as u32;
-        result.id = (name.to_string(), new_name);
-      }
-    }
-    for &name in &file.libs {
+    for path in LoadPath::from_macho(file) {
       // TODO check if these command all processed
       // LoadCommand::LoadDyLib(dylib) | LoadCommand::LoadWeakDyLib(dylib) |
       // LoadCommand::ReexportDyLib(dylib) | LoadCommand::LoadUpwardDylib(dylib) |
       // LoadCommand::LazyLoadDylib(dylib) => {
-      if let Cow::Owned(new_name) = pattern.replace_dylib(name) {
-        result.links.insert(name.to_string(), new_name);
+      // the size is checked against header padding when applied
+      if let Cow::Owned(new_name) = pattern.replace_dylib(path.path()) {
+        result.insert(path, new_name);
       }
     }
-    for &name in &file.rpaths {
-      if let Cow::Owned(new_name) = pattern.replace_dylib(name) {
-        result.rpaths.insert(name.to_string(), new_name);
+    Ok(result)
+  }
+
+  /// needed libraries are put in `links`, and runpaths (a `:` separated list) in `rpaths`
+  pub fn from_elf(file: &Elf, pattern: &RelocationPattern) -> Result<Self> {
+    let mut result = Self::default();
+    for path in LoadPath::from_elf(file) {
+      let new_name = match path {
+        LoadPath::Rpath(name) => pattern.replace_text(name),
+        _ => pattern.replace_dylib(path.path()),
+      };
+      if let Cow::Owned(new_name) = new_name {
+        result.insert(path, new_name);
       }
     }
-    // TODO: check if processed OFile::FatFile { files, .. } | OFile::ArFile { files }
     Ok(result)
   }
 
+  fn insert(&mut self, path: LoadPath, new_name: String) {
+    let name = path.path().to_string();
+    match path {
+      LoadPath::Id(_) => self.id = (name, new_name),
+      LoadPath::Interpreter(_) => self.interpreter = (name, new_name),
+      LoadPath::Link(_) => { self.links.insert(name, new_name); },
+      LoadPath::Rpath(_) => { self.rpaths.insert(name, new_name); },
+    }
+  }
+
   pub fn is_empty(&self) -> bool {
-    return self.id.0.is_empty() && self.links.is_empty() && self.rpaths.is_empty()
+    return self.id.0.is_empty() && self.interpreter.0.is_empty() && self.links.is_empty() && self.rpaths.is_empty()
   }
 
-  pub fn apply_file<P: AsRef<Path>>(&self, filename: P) -> Result<(), std::io::Error> {
+  /// rewrite load commands and sign ad-hoc in process, see [`macho::rewrite`](super::macho::rewrite),
+  /// or the dynamic section of ELF, see [`elf::rewrite`](super::elf::rewrite)
+  pub fn apply_file<P: AsRef<Path>>(&self, filename: P) -> Result<()> {
+    let filename = filename.as_ref();
     if self.is_empty() {
       return Ok(())
     }
-    trace!(filename=%filename.as_ref().display(), "patch macho file");
-
-    let mut cmd = &mut std::process::Command::new("install_name_tool");
-    if self.id.0 != "" {
-      cmd = cmd.args(["-id", &self.id.1]);
-    }
-    for (i, v) in &self.links {
-      cmd = cmd.args(["-change", i, v])
-    }
-    for (i, v) in &self.rpaths {
-      cmd = cmd.args(["-rpath", i, v])
-    }
-    cmd = cmd.arg(filename.as_ref());
-    debug!(?cmd, "install_name_tool");
-    let result = cmd.output()?;
-    for i in String::from_utf8_lossy(&result.stderr).lines() {
-      if i.is_empty() || i.contains("invalidate the code signature") {
-        continue;
-      }
-      warn!("stderr: {}", i);
-    }
-    if !result.status.success() {
-      return Err(std::io::Error::other("install_name_tool"));
-    }
-
-    let result = &mut std::process::Command::new("codesign")
-        .args(["--sign", "-", "--force", "--preserve-metadata=entitlements,requirements,flags,runtime"])
-        .arg(filename.as_ref()).output()?;
-    if !result.status.success() {
-      return Err(std::io::Error::other("codesign"));
-    }
-    for i in String::from_utf8_lossy(&result.stderr).lines() {
-      if i.is_empty() || i.contains("replacing existing signature") {
-        continue;
-      }
-      warn!("stderr: {}", i);
-    }
-    Ok(())
+    trace!(filename=%filename.display(), "patch binary file");
+    let data = std::fs::read(filename).when(("read", filename))?;
+    let identifier = filename.file_name().unwrap_or_default().to_string_lossy();
+    let patched = match data.starts_with(b"\x7fELF") {
+      true => super::elf::rewrite(&data, self),
+      false => super::macho::rewrite(&data, self, &identifier),
+    };
+    let patched = patched.map_err(|reason| Error::relocate_failed(filename, reason))?;
+    replace_file(filename, |w| w.write_all(&patched))
   }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum RelocateType {
-  MachO, Text, None
+  MachO, Elf, Text, Binary, None
+}
+
+fn replace_binary(pattern: &RelocationPattern, data: &[u8]) -> std::result::Result<Option<Vec<u8>>, String> {
+  let replaced = match data.starts_with(goblin::archive::MAGIC) {
+    true => pattern.replace_archive(data),
+    false => pattern.replace_binary(data),
+  };
+  replaced.map_err(|offsets| {
+    let offsets = offsets.iter().map(|i| format!("{:#x}", i)).collect::<Vec<_>>();
+    format!("replacement longer than placeholder at {}", offsets.join(", "))
+  })
+}
+
+/// rewrite load commands of a thin Mach-O, then placeholders in the rest of it (e.g. strings in `__cstring`),
+/// which are replaced before signing again, so the signature covers them
+fn relocate_macho(macho: &MachO, data: &[u8], pattern: &RelocationPattern, identifier: &str) -> std::result::Result<Option<Vec<u8>>, String> {
+  let reloc = Relocations::from_macho(macho, pattern).map_err(|e| e.to_string())?;
+  let rewritten = match reloc.is_empty() {
+    true => None,
+    false => Some(super::macho::rewrite(data, &reloc, identifier)?),
+  };
+  match replace_binary(pattern, rewritten.as_deref().unwrap_or(data))? {
+    Some(replaced) => super::macho::rewrite(&replaced, &Relocations::default(), identifier).map(Some),
+    None => Ok(rewritten),
+  }
+}
+
+/// rewrite the dynamic section of an ELF, then placeholders in strings elsewhere (e.g. default config paths),
+/// the string tables in use are left to the rewrite, a grown `.dynstr` keeps the old strings at their offsets
+fn relocate_elf(elf: &Elf, data: &[u8], pattern: &RelocationPattern) -> std::result::Result<Option<(Vec<u8>, RelocateType)>, String> {
+  let reloc = Relocations::from_elf(elf, pattern).map_err(|e| e.to_string())?;
+  if reloc.is_empty() && !pattern.is_match(data) {
+    return Ok(None)
+  }
+  let rewritten = match reloc.is_empty() {
+    true => None,
+    false => Some(super::elf::rewrite(data, &reloc)?),
+  };
+  let current = rewritten.as_deref().unwrap_or(data);
+  let skip = match &rewritten {
+    Some(rewritten) => super::elf::string_ranges(&Elf::parse(rewritten).map_err(|e| format!("parse elf: {}", e))?, rewritten.len()),
+    None => super::elf::string_ranges(elf, data.len()),
+  };
+  let mut masked = current.to_vec();
+  skip.iter().for_each(|i| masked[i.clone()].fill(0));
+  match replace_binary(pattern, &masked)? {
+    Some(mut replaced) => {
+      skip.iter().for_each(|i| replaced[i.clone()].copy_from_slice(&current[i.clone()]));
+      let kind = if rewritten.is_some() { RelocateType::Elf } else { RelocateType::Binary };
+      Ok(Some((replaced, kind)))
+    },
+    None => Ok(rewritten.map(|i| (i, RelocateType::Elf))),
+  }
+}
+
+/// relocate every slice of a universal binary: Mach-O slices as [`relocate_macho`], members of archive slices
+fn relocate_fat(filename: &Path, data: &[u8], pattern: &RelocationPattern) -> Result<Option<Vec<u8>>> {
+  let identifier = filename.file_name().unwrap_or_default().to_string_lossy();
+  super::macho::rewrite_fat(data, |_, slice| match MachO::parse(slice, 0) {
+    Ok(macho) => relocate_macho(&macho, slice, pattern, &identifier),
+    Err(_) => replace_binary(pattern, slice),
+  }).map_err(|reason| Error::relocate_failed(filename, reason))
+}
+
+/// replace placeholders in strings of a binary file, see [`RelocationPattern::replace_binary`]
+fn relocate_binary<D: AsRef<[u8]>>(filename: &Path, data: D, pattern: &RelocationPattern) -> Result<bool> {
+  let replaced = replace_binary(pattern, data.as_ref()).map_err(|reason| Error::relocate_failed(filename, reason))?;
+  drop(data);
+  let Some(data) = replaced else { return Ok(false) };
+  replace_file(filename, |w| w.write_all(&data))?;
+  Ok(true)
 }
 
 pub fn relocate<P: AsRef<Path>>(filename: P, pattern: &RelocationPattern) -> Result<RelocateType> {
+  let filename = filename.as_ref();
+  let (result, unknown) = relocate_report(filename, pattern)?;
+  if !unknown.is_empty() {
+    warn!(filename=%filename.display(), ?unknown, "unknown placeholders left in place");
+  }
+  Ok(result)
+}
+
+/// like [`relocate`], also returns the unknown placeholders found
+pub fn relocate_report<P: AsRef<Path>>(filename: P, pattern: &RelocationPattern) -> Result<(RelocateType, BTreeSet<String>)> {
   let filename = filename.as_ref();
   if !filename.exists() || filename.is_symlink() {
-    return Ok(RelocateType::None);
+    return Ok((RelocateType::None, BTreeSet::new()));
   }
   let file = std::fs::File::open(filename).when(("open", filename))?;
   let mmap = unsafe { MmapOptions::new().map(&file) }.when(("memmap", filename))?;
-  if let Ok(macho) = MachO::parse(&mmap, 0) {
-    let reloc = Relocations::from_macho(&macho, &pattern)?;
-    if !reloc.is_empty() {
+  let unknown = match memmem::find(&mmap, PLACEHOLDER_PREFIX) {
+    Some(start) => pattern.unknown_placeholders(&mmap[start..]),
+    None => BTreeSet::new(),
+  };
+  Ok((relocate_mapped(filename, mmap, pattern)?, unknown))
+}
+
+fn relocate_mapped(filename: &Path, mmap: Mmap, pattern: &RelocationPattern) -> Result<RelocateType> {
+  match Mach::parse(&mmap) {
+    Ok(Mach::Binary(macho)) => {
+      let identifier = filename.file_name().unwrap_or_default().to_string_lossy();
+      let data = relocate_macho(&macho, &mmap, pattern, &identifier).map_err(|reason| Error::relocate_failed(filename, reason))?;
+      let Some(data) = data else { return Ok(RelocateType::None) };
       debug!(filename=%filename.display(), "reloc macho");
-      reloc.apply_file(filename).when(("otool", filename))?;
-      return Ok(RelocateType::MachO);
-    }
-  } else if let Ok(text) = std::fs::read_to_string(&filename) {
-    if let Cow::Owned(text) = pattern.replace_text(&text) {
-      debug!(filename=%filename.display(), "reloc text");
-      with_permission(&filename, ||
-        std::fs::write(filename, text)
-      ).when(("write", &filename))?.when(("permission", &filename))?;
-      return Ok(RelocateType::Text)
+      drop(mmap);
+      replace_file(filename, |w| w.write_all(&data))?;
+      return Ok(RelocateType::MachO)
+    },
+    Ok(Mach::Fat(fat)) if is_universal(&fat, &mmap) => {
+      let Some(data) = relocate_fat(filename, &mmap, pattern)? else { return Ok(RelocateType::None) };
+      debug!(filename=%filename.display(), "reloc fat macho");
+      replace_file(filename, |w| w.write_all(&data))?;
+      return Ok(RelocateType::MachO)
+    },
+    _ => {},
+  }
+  if let Ok(elf) = Elf::parse(&mmap) {
+    let data = relocate_elf(&elf, &mmap, pattern).map_err(|reason| Error::relocate_failed(filename, reason))?;
+    drop(mmap);
+    let Some((data, result)) = data else { return Ok(RelocateType::None) };
+    debug!(filename=%filename.display(), ?result, "reloc elf");
+    replace_file(filename, |w| w.write_all(&data))?;
+    return Ok(result)
+  } else if !pattern.is_match(&mmap) {
+    return Ok(RelocateType::None)
+  } else if mmap.starts_with(goblin::archive::MAGIC) {
+    if relocate_binary(filename, mmap, pattern)? {
+      debug!(filename=%filename.display(), "reloc archive");
+      return Ok(RelocateType::Binary)
     }
+  } else if std::str::from_utf8(&mmap).is_ok() {
+    debug!(filename=%filename.display(), "reloc text");
+    replace_file(filename, |w| pattern.replace_stream(&mmap[..], w))?;
+    return Ok(RelocateType::Text)
+  } else if relocate_binary(filename, mmap, pattern)? {
+    debug!(filename=%filename.display(), "reloc binary");
+    return Ok(RelocateType::Binary)
   }
   return Ok(RelocateType::None)
 }
@@ -245,3 +550,193 @@ fn test_relocate() {
     }
   }
 }
+
+#[test]
+fn test_relocate_binary() {
+  let dest = &*crate::tests::TestDir::new("test_relocate_binary");
+  std::fs::create_dir_all(dest).unwrap();
+  let filename = dest.join("data.bin");
+  let data = b"\xff\x00@@HOMEBREW_PREFIX@@/share/x\x00keep\x00@@HOMEBREW_PERL@@ -w\x00";
+  std::fs::write(&filename, data).unwrap();
+  let pattern = RelocationPattern::new("/opt/pb", "/opt/pb/Cellar");
+  assert_eq!(relocate(&filename, &pattern).unwrap(), RelocateType::Binary);
+  let result = std::fs::read(&filename).unwrap();
+  assert_eq!(result.len(), data.len());
+  let strings = result.split(|&i| i == 0).filter(|i| !i.is_empty()).collect::<Vec<_>>();
+  assert_eq!(strings, [&b"\xff"[..], b"/opt/pb/share/x", b"keep", b"/usr/bin/perl -w"]);
+  assert_eq!(relocate(&filename, &pattern).unwrap(), RelocateType::None);
+
+  std::fs::write(&filename, data).unwrap();
+  let pattern = RelocationPattern::new(format!("/{}", "long".repeat(8)), "/opt/pb/Cellar");
+  let error = relocate(&filename, &pattern).unwrap_err();
+  assert!(matches!(&error, Error::RelocateFailed { reason, .. } if reason.ends_with("at 0x2")), "{:?}", error);
+  assert_eq!(std::fs::read(&filename).unwrap(), data);
+}
+
+#[test]
+fn test_relocate_elf_long_prefix() {
+  use crate::tests::*;
+  let dest = &*TestDir::new("test_relocate_elf_long_prefix");
+  std::fs::create_dir_all(dest).unwrap();
+  let data = sample_elf("@@HOMEBREW_PREFIX@@/lib/ld.so", &["@@HOMEBREW_PREFIX@@/opt/openssl@3/lib/libssl.so.3", "libc.so.6"], "@@HOMEBREW_PREFIX@@/lib");
+  let filename = dest.join("wget");
+  std::fs::write(&filename, &data).unwrap();
+
+  // longer than the placeholder, the dynamic section grows, and the old strings are not replaced as binary
+  let pattern = RelocationPattern::new("/home/linuxbrew/.linuxbrew", "/home/linuxbrew/.linuxbrew/Cellar").target("x86_64_linux");
+  assert_eq!(relocate(&filename, &pattern).unwrap(), RelocateType::Elf);
+  let result = std::fs::read(&filename).unwrap();
+  let elf = Elf::parse(&result).unwrap();
+  assert_eq!(elf.interpreter, Some("/home/linuxbrew/.linuxbrew/lib/ld.so"));
+  assert_eq!(elf.libraries, ["/home/linuxbrew/.linuxbrew/opt/openssl@3/lib/libssl.so.3", "libc.so.6"]);
+  assert_eq!(elf.runpaths, ["/home/linuxbrew/.linuxbrew/lib"]);
+  assert_eq!(relocate(&filename, &pattern).unwrap(), RelocateType::None);
+
+  // a string elsewhere which doesn't fit fails the whole file, which is kept as it was
+  let mut data = data;
+  data.extend_from_slice(b"@@HOMEBREW_PREFIX@@/etc/wgetrc\0");
+  std::fs::write(&filename, &data).unwrap();
+  let error = relocate(&filename, &pattern).unwrap_err();
+  assert!(matches!(&error, Error::RelocateFailed { reason, .. } if reason.contains("longer than placeholder")), "{:?}", error);
+  assert_eq!(std::fs::read(&filename).unwrap(), data);
+
+  // which fits with a shorter prefix
+  let pattern = RelocationPattern::new("/opt/pb", "/opt/pb/Cellar").target("x86_64_linux");
+  assert_eq!(relocate(&filename, &pattern).unwrap(), RelocateType::Elf);
+  let result = std::fs::read(&filename).unwrap();
+  assert!(result.ends_with(b"/opt/pb/etc/wgetrc\0\0\0\0\0\0\0\0\0\0\0\0\0"));
+  assert_eq!(Elf::parse(&result).unwrap().interpreter, Some("/opt/pb/lib/ld.so"));
+}
+
+#[test]
+fn test_relocate_macho_strings() {
+  use crate::tests::*;
+  let dest = &*TestDir::new("test_relocate_macho_strings");
+  std::fs::create_dir_all(dest).unwrap();
+  let lib = "@@HOMEBREW_PREFIX@@/opt/openssl@3/lib/libssl.3.dylib";
+  let wgetrc = b"@@HOMEBREW_PREFIX@@/etc/wgetrc\0";
+  let mut data = sample_macho(None, &[lib, "/usr/lib/libSystem.B.dylib"], &[], 256);
+  // a default config path compiled into __TEXT
+  data[0x3000..0x3000 + wgetrc.len()].copy_from_slice(wgetrc);
+  let filename = dest.join("wget");
+  std::fs::write(&filename, &data).unwrap();
+
+  let pattern = RelocationPattern::new("/opt/pb", "/opt/pb/Cellar");
+  assert_eq!(relocate(&filename, &pattern).unwrap(), RelocateType::MachO);
+  let data = std::fs::read(&filename).unwrap();
+  assert!(data[0x3000..].starts_with(b"/opt/pb/etc/wgetrc\0"));
+  assert!(!data.windows(11).any(|i| i == b"@@HOMEBREW_"));
+  assert_eq!(MachO::parse(&data, 0).unwrap().libs[1], "/opt/pb/opt/openssl@3/lib/libssl.3.dylib");
+  assert_eq!(super::macho::check_signature(&data), Ok(Some(())));
+
+  // only strings to replace, the load commands are kept
+  let mut data = sample_macho(None, &["/usr/lib/libSystem.B.dylib"], &[], 256);
+  data[0x3000..0x3000 + wgetrc.len()].copy_from_slice(wgetrc);
+  std::fs::write(&filename, &data).unwrap();
+  assert_eq!(relocate(&filename, &pattern).unwrap(), RelocateType::MachO);
+  let data = std::fs::read(&filename).unwrap();
+  assert!(data[0x3000..].starts_with(b"/opt/pb/etc/wgetrc\0"));
+  assert_eq!(super::macho::check_signature(&data), Ok(Some(())));
+}
+
+#[test]
+fn test_relocate_fat() {
+  use goblin::mach::SingleArch;
+  use crate::tests::*;
+  let dest = &*TestDir::new("test_relocate_fat");
+  std::fs::create_dir_all(dest).unwrap();
+  let id = "@@HOMEBREW_PREFIX@@/opt/wget/lib/libwget.1.dylib";
+  let lib = "@@HOMEBREW_CELLAR@@/openssl@3/3.2.1/lib/libssl.3.dylib";
+  let arm64 = sample_macho(Some(id), &[lib, "/usr/lib/libSystem.B.dylib"], &["@@HOMEBREW_PREFIX@@/lib"], 256);
+  let mut x86_64 = sample_macho(Some(id), &[lib], &[], 256);
+  x86_64[4..12].copy_from_slice(&[7, 0, 0, 1, 3, 0, 0, 0]);
+  let archive = sample_archive(&[("a.o", b"\xcf\xfa\xed\xfe@@HOMEBREW_PREFIX@@/share\0x"), ("b.o", b"plain\0")]);
+  let filename = dest.join("libwget.1.dylib");
+  std::fs::write(&filename, sample_fat(&[&arm64, &x86_64, &archive])).unwrap();
+
+  let pattern = RelocationPattern::new("/opt/pb", "/opt/pb/Cellar");
+  assert_eq!(relocate(&filename, &pattern).unwrap(), RelocateType::MachO);
+  let data = std::fs::read(&filename).unwrap();
+  assert!(!data.windows(11).any(|i| i == b"@@HOMEBREW_"));
+  let Mach::Fat(fat) = Mach::parse(&data).unwrap() else { panic!("not fat") };
+  let arches = fat.arches().unwrap();
+  assert_eq!(arches.len(), 3);
+  for (i, arch) in arches.iter().enumerate() {
+    assert_eq!(arch.offset % 0x4000, 0);
+    match fat.get(i).unwrap() {
+      SingleArch::MachO(macho) => {
+        assert_eq!(macho.name, Some("/opt/pb/opt/wget/lib/libwget.1.dylib"));
+        assert_eq!(macho.libs[1], "/opt/pb/Cellar/openssl@3/3.2.1/lib/libssl.3.dylib");
+        assert!(macho.rpaths.iter().all(|i| *i == "/opt/pb/lib"));
+        assert_eq!(super::macho::check_signature(arch.slice(&data)), Ok(Some(())));
+      },
+      SingleArch::Archive(archive) => {
+        assert_eq!(archive.extract("a.o", arch.slice(&data)).unwrap(), b"\xcf\xfa\xed\xfe/opt/pb/share\0\0\0\0\0\0\0\0\0\0\0\0\0x");
+      },
+    }
+  }
+  assert_eq!(relocate(&filename, &pattern).unwrap(), RelocateType::None);
+
+  let filename = dest.join("libwget.a");
+  std::fs::write(&filename, &archive).unwrap();
+  assert_eq!(relocate(&filename, &pattern).unwrap(), RelocateType::Binary);
+  let data = std::fs::read(&filename).unwrap();
+  assert_eq!(data.len(), archive.len());
+  assert_eq!(Archive::parse(&data).unwrap().extract("b.o", &data).unwrap(), b"plain\0");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_relocate_text() {
+  use std::os::unix::fs::{MetadataExt as _, PermissionsExt as _};
+  let dest = &*crate::tests::TestDir::new("test_relocate_text");
+  std::fs::create_dir_all(dest).unwrap();
+  let pattern = RelocationPattern::new("/opt/pb", "/opt/pb/Cellar");
+  let script = dest.join("wget-config");
+  let text = "#!@@HOMEBREW_PERL@@\nprefix=@@HOMEBREW_PREFIX@@\nlib=@@HOMEBREW_CELLAR@@/wget/1.24.5/lib\n@@HOMEBREW_UNKNOWN@@\n".repeat(2000);
+  std::fs::write(&script, &text).unwrap();
+  let mtime = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+  File::options().write(true).open(&script).unwrap().set_modified(mtime).unwrap();
+  std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o555)).unwrap();
+
+  assert_eq!(relocate(&script, &pattern).unwrap(), RelocateType::Text);
+  let expected = "#!/usr/bin/perl\nprefix=/opt/pb\nlib=/opt/pb/Cellar/wget/1.24.5/lib\n@@HOMEBREW_UNKNOWN@@\n".repeat(2000);
+  assert_eq!(std::fs::read_to_string(&script).unwrap(), expected);
+  let metadata = std::fs::metadata(&script).unwrap();
+  assert_eq!(metadata.permissions().mode() & 0o777, 0o555);
+  assert_eq!(metadata.modified().unwrap(), mtime);
+  assert!(!tmp_path(&script, ".reloc").exists());
+  assert_eq!(pattern.replace_text("lib=@@HOMEBREW_PREFIX@@/lib"), "lib=/opt/pb/lib");
+
+  // files without placeholders are not rewritten
+  let plain = dest.join("README");
+  std::fs::write(&plain, "@@ not a placeholder @@").unwrap();
+  let inode = std::fs::metadata(&plain).unwrap().ino();
+  assert_eq!(relocate(&plain, &pattern).unwrap(), RelocateType::None);
+  assert_eq!(std::fs::metadata(&plain).unwrap().ino(), inode);
+}
+
+#[test]
+fn test_relocate_placeholders() {
+  let dest = &*crate::tests::TestDir::new("test_relocate_placeholders");
+  std::fs::create_dir_all(dest).unwrap();
+  let mac = RelocationPattern::new("/opt/pb", "/opt/pb/Cellar").target("arm64_sonoma");
+  assert_eq!(mac.replace_text("@@HOMEBREW_PERL@@ @@HOMEBREW_LIBRARY@@"), "/usr/bin/perl /opt/pb/Library");
+  let intel = RelocationPattern::new("/opt/pb", "/opt/pb/Cellar").target("sonoma");
+  assert_eq!(intel.replace_text("@@HOMEBREW_REPOSITORY@@ @@HOMEBREW_LIBRARY@@"), "/opt/pb/Homebrew /opt/pb/Homebrew/Library");
+  let linux = RelocationPattern::new("/opt/pb", "/opt/pb/Cellar").target("x86_64_linux");
+  assert_eq!(linux.replace_text("@@HOMEBREW_PERL@@ @@HOMEBREW_JAVA@@"), "/opt/pb/opt/perl/bin/perl /opt/pb/opt/openjdk/libexec");
+  assert_eq!(linux.replace_text("@@HOMEBREW_LIBRARY@@"), "/opt/pb/Homebrew/Library");
+  let pattern = linux.placeholder("HOMEBREW_PERL", "/usr/local/bin/perl").placeholder("@@HOMEBREW_RUBY@@", "/usr/bin/ruby");
+  assert_eq!(pattern.replace_text("@@HOMEBREW_PERL@@ @@HOMEBREW_RUBY@@"), "/usr/local/bin/perl /usr/bin/ruby");
+
+  let filename = dest.join("config.sh");
+  std::fs::write(&filename, "prefix=@@HOMEBREW_PREFIX@@\nshell=@@HOMEBREW_SHELL@@ @@HOMEBREW_@@ @@HOMEBREW_lower@@\n").unwrap();
+  let (result, unknown) = relocate_report(&filename, &pattern).unwrap();
+  assert_eq!(result, RelocateType::Text);
+  assert_eq!(unknown.into_iter().collect::<Vec<_>>(), ["@@HOMEBREW_SHELL@@"]);
+  assert_eq!(std::fs::read_to_string(&filename).unwrap(), "prefix=/opt/pb\nshell=@@HOMEBREW_SHELL@@ @@HOMEBREW_@@ @@HOMEBREW_lower@@\n");
+  // only unknown ones: reported but nothing to replace
+  let (result, unknown) = relocate_report(&filename, &pattern).unwrap();
+  assert_eq!((result, unknown.len()), (RelocateType::None, 1));
+}
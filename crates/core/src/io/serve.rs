@@ -0,0 +1,276 @@
+//! serve the download cache as a [`MirrorType::Bottle`](crate::package::mirror::MirrorType::Bottle) mirror
+//!   `GET /{filename}` => `{root}/{filename}`, e.g. `/wget-1.24.5.arm64_sonoma.bottle.tar.gz`
+//!   `GET /api/{target}` => `{root}/{target}`, e.g. `/api/formula.json`
+//! `HEAD` and single range requests like `Range: bytes=0-1023` are supported, so probe and resumed downloads work.
+//! a missing file is served from its compressed variant (`formula.json.gz`) with `Content-Encoding`, if the client accepts it.
+
+use std::{net::SocketAddr, path::{Path, PathBuf}, sync::Arc};
+
+use futures::TryStreamExt as _;
+use http_body_util::{combinators::BoxBody, BodyExt as _, Empty, Full, StreamBody};
+use hyper::{body::{Bytes, Frame, Incoming}, header, Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::{io::{AsyncReadExt as _, AsyncSeekExt as _}, net::TcpListener};
+
+use crate::error::{Error, Result};
+
+use super::compress::Compression;
+
+pub type Body = BoxBody<Bytes, std::io::Error>;
+
+pub struct Server {
+  pub root: PathBuf,
+}
+
+impl Server {
+  pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+    Self { root: root.into() }
+  }
+
+  pub async fn bind(addr: &str) -> Result<TcpListener> {
+    TcpListener::bind(addr).await.map_err(|error| Error::ServeFailed { addr: addr.to_string(), error })
+  }
+
+  /// serve until the listener fails, each connection is handled in its own task
+  pub async fn run(self, listener: TcpListener) -> Result<()> {
+    let addr = listener.local_addr().map(|i| i.to_string()).unwrap_or_default();
+    info!(addr, root=%self.root.display(), "serving");
+    let server = Arc::new(self);
+    loop {
+      let (stream, peer) = listener.accept().await.map_err(|error| Error::ServeFailed { addr: addr.clone(), error })?;
+      let server = server.clone();
+      tokio::spawn(async move {
+        let service = hyper::service::service_fn(move |req| {
+          let server = server.clone();
+          async move { Ok::<_, std::convert::Infallible>(server.handle(req, peer).await) }
+        });
+        if let Err(error) = hyper::server::conn::http1::Builder::new().serve_connection(TokioIo::new(stream), service).await {
+          debug!(%peer, %error, "connection closed");
+        }
+      });
+    }
+  }
+
+  #[tracing::instrument(level = "debug", skip_all, fields(%peer, method = %req.method(), path = req.uri().path()))]
+  async fn handle(&self, req: Request<Incoming>, peer: SocketAddr) -> Response<Body> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+      return Response::builder().status(StatusCode::METHOD_NOT_ALLOWED).header(header::ALLOW, "GET, HEAD").body(empty()).unwrap()
+    }
+    let Some(path) = self.local_path(req.uri().path()) else {
+      return status(StatusCode::NOT_FOUND)
+    };
+    let range = req.headers().get(header::RANGE).and_then(|i| i.to_str().ok()).map(str::to_string);
+    let accept = req.headers().get(header::ACCEPT_ENCODING).and_then(|i| i.to_str().ok()).unwrap_or_default();
+    let encoding = match path.exists() {
+      true => None,
+      false => [Compression::Gzip, Compression::Brotli].into_iter()
+        .find(|c| accept.split(',').any(|i| Compression::from_encoding(i.split(';').next().unwrap_or_default()) == Some(*c)) && Compression::path(&path, Some(*c)).is_file()),
+    };
+    match self.file_response(&path, encoding, range.as_deref(), req.method() == Method::HEAD).await {
+      Ok(resp) => {
+        debug!(status=%resp.status(), "response");
+        resp
+      },
+      Err(error) if error.kind() == std::io::ErrorKind::NotFound => status(StatusCode::NOT_FOUND),
+      Err(error) => {
+        warn!(path=%path.display(), %error, "serve failed");
+        status(StatusCode::INTERNAL_SERVER_ERROR)
+      },
+    }
+  }
+
+  /// map the request path to a file in root, only files of the bottle mirror layout are allowed:
+  /// `/{name}.bottle*.tar.gz`, `/api/formula.json`, `/api/formula.jws.json` and `/api/formula/{name}.json`,
+  /// the api files also as `.gz` or `.br`. anything else like state files and `..` is never served.
+  pub fn local_path(&self, path: &str) -> Option<PathBuf> {
+    let path = path.strip_prefix('/')?;
+    let segments = path.split('/')
+      .map(|i| percent_encoding::percent_decode_str(i).decode_utf8().ok())
+      .collect::<Option<Vec<_>>>()?;
+    if segments.iter().any(|i| i.is_empty() || i.starts_with('.') || i.contains(['/', '\\', '\0'])) {
+      return None
+    }
+    let segments = segments.iter().map(|i| i.as_ref()).collect::<Vec<&str>>();
+    let allowed = match segments.as_slice() {
+      [name] => name.contains(".bottle") && name.ends_with(".tar.gz"),
+      ["api", name] => ["formula.json", "formula.jws.json"].contains(&strip_compression(name)),
+      ["api", "formula", name] => strip_compression(name).strip_suffix(".json").is_some_and(|i| !i.is_empty()),
+      _ => false,
+    };
+    if !allowed {
+      return None
+    }
+    let segments = segments.strip_prefix(&["api"]).unwrap_or(&segments);
+    Some(segments.iter().fold(self.root.clone(), |result, i| result.join(i)))
+  }
+
+  /// `encoding` serves the compressed variant of path instead
+  async fn file_response(&self, path: &Path, encoding: Option<Compression>, range: Option<&str>, head_only: bool) -> std::io::Result<Response<Body>> {
+    let mut file = tokio::fs::File::open(Compression::path(path, encoding)).await?;
+    let metadata = file.metadata().await?;
+    if !metadata.is_file() {
+      return Err(std::io::ErrorKind::NotFound.into())
+    }
+    let length = metadata.len();
+    let content_type = match path.extension().and_then(|i| i.to_str()) {
+      Some("json") => "application/json",
+      Some("gz") => "application/gzip",
+      _ => "application/octet-stream",
+    };
+    let builder = Response::builder()
+      .header(header::ACCEPT_RANGES, "bytes")
+      .header(header::CONTENT_TYPE, content_type);
+    let builder = match encoding {
+      Some(encoding) => builder.header(header::CONTENT_ENCODING, encoding.encoding()),
+      None => builder,
+    };
+    let (builder, start, len) = match range.map(|range| parse_range(range, length)) {
+      None | Some(Range::Ignored) => (builder.status(StatusCode::OK), 0, length),
+      Some(Range::Unsatisfiable) => {
+        let resp = builder.status(StatusCode::RANGE_NOT_SATISFIABLE)
+          .header(header::CONTENT_RANGE, format!("bytes */{}", length))
+          .body(empty()).unwrap();
+        return Ok(resp)
+      },
+      Some(Range::Bytes(start, end)) => {
+        let builder = builder.status(StatusCode::PARTIAL_CONTENT)
+          .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, length));
+        (builder, start, end - start + 1)
+      },
+    };
+    let builder = builder.header(header::CONTENT_LENGTH, len);
+    if head_only {
+      return Ok(builder.body(empty()).unwrap())
+    }
+    file.seek(std::io::SeekFrom::Start(start)).await?;
+    let stream = tokio_util::io::ReaderStream::new(file.take(len)).map_ok(Frame::data);
+    Ok(builder.body(StreamBody::new(stream).boxed()).unwrap())
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Range {
+  /// inclusive range `start..=end` in the file
+  Bytes(u64, u64),
+  Unsatisfiable,
+  /// malformed or multiple ranges, the whole file is sent
+  Ignored,
+}
+
+/// parse `Range: bytes=0-99`, `bytes=100-` or `bytes=-100` against the file length
+pub fn parse_range(range: &str, length: u64) -> Range {
+  let Some(spec) = range.trim().strip_prefix("bytes=") else { return Range::Ignored };
+  if spec.contains(',') {
+    return Range::Ignored
+  }
+  let Some((start, end)) = spec.trim().split_once('-') else { return Range::Ignored };
+  let (start, end) = match (start.trim(), end.trim()) {
+    ("", "") => return Range::Ignored,
+    ("", suffix) => match suffix.parse::<u64>() {
+      Ok(0) => return Range::Unsatisfiable,
+      Ok(suffix) => (length.saturating_sub(suffix), length.saturating_sub(1)),
+      Err(_) => return Range::Ignored,
+    },
+    (start, end) => {
+      let Ok(start) = start.parse::<u64>() else { return Range::Ignored };
+      let end = match end {
+        "" => length.saturating_sub(1),
+        end => match end.parse::<u64>() {
+          Ok(end) if end >= start => end.min(length.saturating_sub(1)),
+          _ => return Range::Ignored,
+        },
+      };
+      (start, end)
+    },
+  };
+  if length == 0 || start >= length {
+    return Range::Unsatisfiable
+  }
+  Range::Bytes(start, end)
+}
+
+/// `formula.json.gz` => `formula.json`
+fn strip_compression(name: &str) -> &str {
+  [Compression::Gzip, Compression::Brotli].into_iter()
+    .find_map(|i| name.strip_suffix(i.suffix()))
+    .unwrap_or(name)
+}
+
+fn empty() -> Body {
+  Empty::new().map_err(|never| match never {}).boxed()
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+  let body = Full::new(Bytes::from(code.canonical_reason().unwrap_or_default())).map_err(|never| match never {}).boxed();
+  Response::builder().status(code).body(body).unwrap()
+}
+
+#[test]
+fn test_parse_range() {
+  assert_eq!(parse_range("bytes=0-99", 1000), Range::Bytes(0, 99));
+  assert_eq!(parse_range("bytes=900-", 1000), Range::Bytes(900, 999));
+  assert_eq!(parse_range("bytes=-100", 1000), Range::Bytes(900, 999));
+  assert_eq!(parse_range("bytes=990-2000", 1000), Range::Bytes(990, 999));
+  assert_eq!(parse_range("bytes=1000-", 1000), Range::Unsatisfiable);
+  assert_eq!(parse_range("bytes=0-1,5-6", 1000), Range::Ignored);
+  assert_eq!(parse_range("items=0-1", 1000), Range::Ignored);
+}
+
+#[test]
+fn test_local_path() {
+  let server = Server::new("cache");
+  assert_eq!(server.local_path("/api/formula.json"), Some(PathBuf::from("cache/formula.json")));
+  assert_eq!(server.local_path("/openssl%403-3.2.1.bottle.tar.gz"), Some(PathBuf::from("cache/openssl@3-3.2.1.bottle.tar.gz")));
+  assert_eq!(server.local_path("/../pacbrew.toml"), None);
+  assert_eq!(server.local_path("/%2e%2e%2fpacbrew.toml"), None);
+  assert_eq!(server.local_path("/api/.hidden"), None);
+  assert_eq!(server.local_path("/api//formula.json"), None);
+  assert_eq!(server.local_path("/wget.bottle.tar.gz.part"), None);
+  assert_eq!(server.local_path("/wget.bottle.tar.gz.broken"), None);
+  assert_eq!(server.local_path("/api/formula.json.validators"), None);
+  assert_eq!(server.local_path("/api/formula.provenance.json"), None);
+  assert_eq!(server.local_path("/api/formula.json.gz"), Some(PathBuf::from("cache/formula.json.gz")));
+  assert_eq!(server.local_path("/api/formula.jws.json"), Some(PathBuf::from("cache/formula.jws.json")));
+  assert_eq!(server.local_path("/api/formula/openssl%403.json"), Some(PathBuf::from("cache/formula/openssl@3.json")));
+  assert_eq!(server.local_path("/api/formula/.json"), None);
+  assert_eq!(server.local_path("/formula.json"), None);
+  assert_eq!(server.local_path("/api/wget.bottle.tar.gz"), None);
+  assert_eq!(server.local_path("/api/mirror_health.json"), None);
+  assert_eq!(server.local_path("/api/probe_cache.json"), None);
+  assert_eq!(server.local_path("/api/formula.idx"), None);
+  assert_eq!(server.local_path("/pacbrew.toml"), None);
+}
+
+#[tokio::test]
+async fn test_serve() {
+  use crate::{io::fetch::{fetch_remote, FetchReq, MirrorLists}, package::mirror::{MirrorServer, MirrorType}};
+  use crate::tests::*;
+  init_logger(None);
+  let root = &*TestDir::new("test_serve");
+  let blob = (0..=255u8).cycle().take(100_000).collect::<Vec<_>>();
+  std::fs::write(root.join("wget.bottle.tar.gz"), &blob).unwrap();
+  std::fs::write(root.join("formula.json"), "[]").unwrap();
+
+  let listener = Server::bind("127.0.0.1:0").await.unwrap();
+  let base = format!("http://{}", listener.local_addr().unwrap());
+  tokio::spawn(Server::new(root).run(listener));
+  let client = reqwest::Client::new();
+
+  let resp = client.head(format!("{}/wget.bottle.tar.gz", base)).send().await.unwrap();
+  assert_eq!(resp.status(), reqwest::StatusCode::OK);
+  assert_eq!(resp.headers()[header::CONTENT_LENGTH], "100000");
+  let resp = client.get(format!("{}/wget.bottle.tar.gz", base)).header(header::RANGE, "bytes=1000-1999").send().await.unwrap();
+  assert_eq!(resp.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+  assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes 1000-1999/100000");
+  assert_eq!(resp.bytes().await.unwrap().as_ref(), &blob[1000..2000]);
+  let resp = client.get(format!("{}/wget.bottle.tar.gz", base)).header(header::RANGE, "bytes=100000-").send().await.unwrap();
+  assert_eq!(resp.status(), reqwest::StatusCode::RANGE_NOT_SATISFIABLE);
+  let resp = client.get(format!("{}/missing.bottle.tar.gz", base)).send().await.unwrap();
+  assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+
+  let mirrors = MirrorLists::new(vec![MirrorServer::new(MirrorType::Bottle, &base, None)]);
+  let client_dir = TestDir::new("test_serve_client");
+  let target = client_dir.join("formula.json");
+  fetch_remote(&mirrors, FetchReq::Api("formula.json".to_string()), &target, ()).await.unwrap();
+  assert_eq!(std::fs::read_to_string(&target).unwrap(), "[]");
+}
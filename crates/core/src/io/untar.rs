@@ -1,17 +1,18 @@
-use std::{collections::BTreeMap, path::{Path, PathBuf}, sync::atomic::{AtomicU64, AtomicUsize}};
+use std::{path::{Component, Path, PathBuf}, pin::Pin, sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Arc}, task::{Context, Poll}};
 
 use async_compression::tokio::bufread::GzipDecoder;
 use futures::StreamExt;
 
-use crate::{error::{ErrorExt, Result}, ui::EventListener};
+use crate::{error::{Error, ErrorExt, Result}, ui::EventListener};
 
+/// `pos` and `total_size` are compressed bytes, so the progress is known without decompressing twice
 #[derive(Debug, Clone, Default)]
 pub struct UnpackEvent {
   pub current_entry: Option<PathBuf>,
   pub pos: u64,
+  /// 0 if unknown, e.g. a download stream without content length
   pub total_size: u64,
   pub item_current: usize,
-  pub item_count: usize,
 }
 
 pub trait Transformer<R> {
@@ -40,69 +41,120 @@ impl<R: tokio::io::AsyncBufRead + Unpin> Transformer<R> for GzipTransformer {
   }
 }
 
-// TODO: futures::io::AsyncRead?
+/// counts bytes read from `inner`
+pub struct Counted<R> {
+  inner: R,
+  read: Arc<AtomicU64>,
+}
+
+impl<R: tokio::io::AsyncRead + Unpin> tokio::io::AsyncRead for Counted<R> {
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut tokio::io::ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+    let before = buf.filled().len();
+    let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+    self.read.fetch_add((buf.filled().len() - before) as u64, Ordering::AcqRel);
+    result
+  }
+}
+
+/// a compressed tar read in a single pass, `Reader` could be a file or a download stream
 pub struct Archive<Reader, Decoder> {
   inner: Reader,
   decoder: Decoder,
+  read: Arc<AtomicU64>,
 }
 
 impl<Reader, Decoder> Archive<Reader, Decoder>
 where
-  Reader: tokio::io::AsyncRead + tokio::io::AsyncSeek + Unpin,
-  Decoder: for<'a>Transformer<tokio::io::BufReader<&'a mut Reader>>,
+  Reader: tokio::io::AsyncRead + Unpin,
+  Decoder: Transformer<tokio::io::BufReader<Counted<Reader>>>,
 {
   pub fn new(reader: Reader, decoder: Decoder) -> Self {
-    Self { inner: reader, decoder }
+    Self { inner: reader, decoder, read: Arc::new(AtomicU64::new(0)) }
   }
 
-  pub async fn reset(&mut self) -> Result<()> {
-    use tokio::io::AsyncSeekExt;
-    self.inner.seek(std::io::SeekFrom::Start(0)).await.when(("Archive.reset", Path::new("")))?;
-    Ok(())
+  /// compressed bytes read so far, keeps counting after [`get`](Self::get)
+  pub fn position(&self) -> Arc<AtomicU64> {
+    self.read.clone()
   }
 
-  pub async fn get<'a>(&'a mut self) -> Result<tokio_tar::Archive<<Decoder as Transformer<tokio::io::BufReader<&'a mut Reader>>>::Reader>> {
-    self.reset().await?;
-    let decoder = &mut self.decoder;
-    let archive = tokio_tar::Archive::new(decoder.transform(tokio::io::BufReader::new(&mut self.inner)));
-    Ok(archive)
+  pub fn get(mut self) -> tokio_tar::Archive<<Decoder as Transformer<tokio::io::BufReader<Counted<Reader>>>>::Reader> {
+    let counted = Counted { inner: self.inner, read: self.read };
+    tokio_tar::Archive::new(self.decoder.transform(tokio::io::BufReader::new(counted)))
   }
 }
 
 pub(crate) trait ArchiveExt {
   fn name(&self) -> &Path { Path::new("") }
-  async fn uncompressed_size(self) -> Result<BTreeMap<PathBuf, u64>>;
   async fn unpack<P: AsRef<Path>>(self, dest: P, tracker: impl EventListener<(PathBuf, u64)>) -> Result<()>;
 }
 
-impl<R: tokio::io::AsyncRead + Unpin> ArchiveExt for tokio_tar::Archive<R> {
-  async fn uncompressed_size(mut self) -> Result<BTreeMap<PathBuf, u64>> {
-    let tar = self.name().to_path_buf();
-    let mut entries = self.entries().when(("total_size.read_entries", &tar))?;
-    let mut result = BTreeMap::new();
-    while let Some(entry) = entries.next().await {
-      let entry = entry.when(("total_size.get_entry", &tar))?;
-      let entry_path = entry.path().when(("total_size.get_entry_path", &tar))?;
-      if entry_path.as_os_str().as_encoded_bytes().ends_with(b"/") {
-        continue;
-      }
-      result.insert(
-        entry_path.into_owned(),
-        entry.header().size().when(("total_size.get_entry_size", &tar))?,
-      );
+/// resolve `target` from `base` like the kernel does, following links unpacked so far,
+/// None if it leaves `root` at any step, e.g. `lib/up -> ..` where `lib` itself links to `..`
+fn resolve_in(root: &Path, base: &Path, target: &Path, depth: usize) -> Option<PathBuf> {
+  if depth > 40 {
+    return None
+  }
+  let mut result = base.to_path_buf();
+  for component in target.components() {
+    match component {
+      Component::Prefix(_) | Component::RootDir => return None,
+      Component::CurDir => continue,
+      Component::ParentDir => { result.pop(); },
+      Component::Normal(name) => {
+        result.push(name);
+        if let Ok(link) = std::fs::read_link(&result) {
+          result.pop();
+          result = resolve_in(root, &result, &link, depth + 1)?;
+        }
+      },
+    }
+    if !result.starts_with(root) {
+      return None
     }
-    Ok(result)
   }
+  Some(result)
+}
 
+/// reject entries which would write or link outside `root` (canonical), checked before each entry is unpacked
+fn validate_entry(root: &Path, entry_path: &Path, kind: tokio_tar::EntryType, link_name: Option<&Path>) -> Result<()> {
+  let unsafe_entry = |reason: String| Error::unsafe_entry(entry_path, reason);
+  if let Some(component) = entry_path.components().find(|i| !matches!(i, Component::Normal(_) | Component::CurDir)) {
+    return Err(unsafe_entry(format!("path component {:?}", component.as_os_str())))
+  }
+  let parent = entry_path.parent().unwrap_or(Path::new(""));
+  let parent = resolve_in(root, root, parent, 0).ok_or_else(|| unsafe_entry("parent dir links outside".to_string()))?;
+  if kind.is_symlink() || kind.is_hard_link() {
+    let link_name = link_name.ok_or_else(|| unsafe_entry("link without target".to_string()))?;
+    // hard links are relative to the archive root, symlinks to the dir containing them
+    let (base, kind) = match kind.is_symlink() {
+      true => (parent.as_path(), "symlink"),
+      false => (root, "hardlink"),
+    };
+    if resolve_in(root, base, link_name, 0).is_none() {
+      return Err(unsafe_entry(format!("{} to {}", kind, link_name.display())))
+    }
+  } else if let Some(name) = entry_path.file_name() {
+    // an existing symlink would be written through
+    if resolve_in(root, &parent, Path::new(name), 0).is_none() {
+      return Err(unsafe_entry("writes through a link to outside".to_string()))
+    }
+  }
+  Ok(())
+}
+
+impl<R: tokio::io::AsyncRead + Unpin> ArchiveExt for tokio_tar::Archive<R> {
   async fn unpack<P: AsRef<Path>>(mut self, dest: P, tracker: impl EventListener<(PathBuf, u64)>) -> Result<()> {
     let tar = self.name().to_path_buf();
     tokio::fs::create_dir_all(dest.as_ref()).await.when(("create_dir_all", &tar))?;
     let dest = dest.as_ref();
+    let root = tokio::fs::canonicalize(dest).await.when(("canonicalize", dest))?;
     let mut entries = self.entries().when(("untar.read_entries", &tar))?;
     while let Some(entry) = entries.next().await {
       let mut entry = entry.when(("untar.get_entry", &tar))?;
       let entry_path = entry.path().when(("untar.get_entry_path", &tar))?.into_owned();
-      let entry_size = entry.header().size().when(("total_size.get_entry_size", &tar))?;
+      let entry_size = entry.header().size().when(("untar.get_entry_size", &tar))?;
+      let link_name = entry.link_name().when(("untar.get_entry_link_name", &tar))?;
+      validate_entry(&root, &entry_path, entry.header().entry_type(), link_name.as_deref())?;
       let path = dest.join(&entry_path);
       if entry_path.as_os_str().as_encoded_bytes().ends_with(b"/") {
         tokio::fs::create_dir_all(&path).await.when(("untar.create_dir_for_entry", &path))?;
@@ -118,23 +170,34 @@ impl<R: tokio::io::AsyncRead + Unpin> ArchiveExt for tokio_tar::Archive<R> {
   }
 }
 
+/// unpack a gzipped tar from `reader` while it's read, `total_size` is its length if known (0 otherwise).
+/// returns the count and uncompressed size of entries
+pub async fn untar_stream<R, P>(reader: R, total_size: u64, dest: P, tracker: impl EventListener<UnpackEvent>) -> Result<(usize, u64)>
+where
+  R: tokio::io::AsyncRead + Unpin,
+  P: AsRef<Path>,
+{
+  let archive = Archive::new(reader, GzipTransformer);
+  let read = archive.position();
+  tracker.on_event(UnpackEvent { current_entry: None, pos: 0, total_size, item_current: 0 });
+  debug!(total_size, "start unpack");
+  let size = AtomicU64::new(0);
+  let idx = AtomicUsize::new(0);
+  archive.get().unpack(dest, |(entry, entry_size)| {
+    size.fetch_add(entry_size, Ordering::AcqRel);
+    let idx = idx.fetch_add(1, Ordering::AcqRel);
+    tracker.on_event(UnpackEvent { current_entry: Some(entry), pos: read.load(Ordering::Acquire), total_size, item_current: idx+1 })
+  }).await?;
+  let (idx, size) = (idx.load(Ordering::Acquire), size.load(Ordering::Acquire));
+  // the end of the tar is not read, nor the gzip trailer
+  tracker.on_event(UnpackEvent { current_entry: None, pos: total_size.max(read.load(Ordering::Acquire)), total_size, item_current: idx });
+  Ok((idx, size))
+}
 
 pub async fn untar_gz<P1: AsRef<Path>, P2: AsRef<Path>>(tar: P1, dest: P2, tracker: impl EventListener<UnpackEvent>) -> Result<(usize, u64)> {
   let file = tokio::fs::File::open(tar.as_ref()).await.when(("untar.open", tar.as_ref()))?;
-  let mut archive = Archive::new(file, GzipTransformer);
-  let entries_size = archive.get().await?.uncompressed_size().await?;
-  let total_size = entries_size.values().sum();
-  let item_count = entries_size.len();
-  tracker.on_event(UnpackEvent { current_entry: None, pos: 0, total_size, item_current: 0, item_count });
-  debug!(total_size, item_count, "start unpack");
-  let pos = AtomicU64::new(0);
-  let idx = AtomicUsize::new(0);
-  archive.get().await?.unpack(dest, |(entry, size)| {
-    let pos = pos.fetch_add(size, std::sync::atomic::Ordering::AcqRel);
-    let idx = idx.fetch_add(1, std::sync::atomic::Ordering::AcqRel);
-    tracker.on_event(UnpackEvent { current_entry: Some(entry), pos: pos+size, total_size, item_current: idx+1, item_count })
-  }).await?;
-  Ok((idx.load(std::sync::atomic::Ordering::Acquire), pos.load(std::sync::atomic::Ordering::Acquire)))
+  let total_size = file.metadata().await.when(("untar.metadata", tar.as_ref()))?.len();
+  untar_stream(file, total_size, dest, tracker).await
 }
 
 #[tokio::test]
@@ -146,3 +209,86 @@ async fn test_untar() {
   untar_gz(tar, dest, |e| debug!(?e)).await.unwrap();
   assert!(dest.exists());
 }
+
+#[tokio::test]
+async fn test_untar_stream() {
+  use crate::tests::*;
+  let bottle = sample_bottle(&[("wget/1.24.5/bin/wget", &[7; 100_000]), ("wget/1.24.5/README", b"wget")]);
+  // a download stream, neither seekable nor sized
+  let chunks = bottle.chunks(1000).map(|i| Ok::<_, std::io::Error>(std::io::Cursor::new(i.to_vec()))).collect::<Vec<_>>();
+  let reader = tokio_util::io::StreamReader::new(futures::stream::iter(chunks));
+  let dest = &*TestDir::new("test_untar_stream");
+  let events = std::sync::Mutex::new(Vec::new());
+  let (count, size) = untar_stream(reader, bottle.len() as u64, dest, |e: UnpackEvent| events.lock().unwrap().push(e)).await.unwrap();
+  assert_eq!((count, size), (2, 100_004));
+  assert_eq!(std::fs::read(dest.join("wget/1.24.5/README")).unwrap(), b"wget");
+  let events = events.into_inner().unwrap();
+  let entries = events.iter().filter_map(|i| i.current_entry.clone()).collect::<Vec<_>>();
+  assert_eq!(entries, [PathBuf::from("wget/1.24.5/bin/wget"), PathBuf::from("wget/1.24.5/README")]);
+  assert!(events.windows(2).all(|i| i[0].pos <= i[1].pos));
+  assert!(events.iter().all(|i| i.total_size == bottle.len() as u64));
+  assert_eq!(events.last().unwrap().pos, bottle.len() as u64);
+}
+
+#[tokio::test]
+async fn test_untar_download() {
+  use futures::TryStreamExt as _;
+  use crate::tests::*;
+  init_logger(None);
+  let blob = (0..200_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect::<Vec<_>>();
+  let bottle = sample_bottle(&[("wget/1.24.5/bin/wget", &blob)]);
+  let body = bottle.clone();
+  let base = stand_in(move |_| StandInResponse::ok(body.clone())).await;
+  let dest = &*TestDir::new("test_untar_download");
+
+  // chunks as they arrive from the socket, unpacked without a file in between
+  let resp = reqwest::get(format!("{}/wget.bottle.tar.gz", base)).await.unwrap().error_for_status().unwrap();
+  let reader = tokio_util::io::StreamReader::new(resp.bytes_stream().map_err(std::io::Error::other));
+  let (count, size) = untar_stream(reader, 0, dest, ()).await.unwrap();
+  assert_eq!((count, size), (1, blob.len() as u64));
+  assert_eq!(std::fs::read(dest.join("wget/1.24.5/bin/wget")).unwrap(), blob);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_untar_unsafe() {
+  use tar::EntryType::{Directory, Link, Regular, Symlink};
+  use crate::tests::*;
+  type Entries<'a> = &'a [(&'a str, tar::EntryType, &'a str, &'a [u8])];
+  let root = TestDir::new("test_untar_unsafe");
+  let dest = root.join("keg");
+  let unpack = |entries: Entries| {
+    std::fs::remove_dir_all(&dest).ok();
+    let bottle = sample_tar(entries);
+    let dest = dest.clone();
+    async move { untar_stream(&bottle[..], bottle.len() as u64, dest, ()).await }
+  };
+  let cases: &[(Entries, &str)] = &[
+    (&[("../outside", Regular, "", b"x")], "../outside"),
+    (&[("wget/../../outside", Regular, "", b"x")], "wget/../../outside"),
+    (&[("/tmp/outside", Regular, "", b"x")], "/tmp/outside"),
+    (&[("wget/", Directory, "", b""), ("wget/passwd", Symlink, "/etc/passwd", b"")], "wget/passwd"),
+    (&[("wget/", Directory, "", b""), ("wget/up", Symlink, "../../outside", b"")], "wget/up"),
+    // each link stays inside on its own, but `up` is outside following `parent`
+    (&[("wget/", Directory, "", b""), ("wget/parent", Symlink, "..", b""), ("wget/parent/up", Symlink, "..", b"")], "wget/parent/up"),
+    (&[("wget/", Directory, "", b""), ("wget/out", Symlink, "../..", b""), ("wget/out/outside", Regular, "", b"x")], "wget/out"),
+    (&[("wget/", Directory, "", b""), ("wget/passwd", Link, "../etc/passwd", b"")], "wget/passwd"),
+  ];
+  for (entries, expected) in cases {
+    let error = unpack(entries).await.unwrap_err();
+    assert!(matches!(&error, Error::UnsafeEntry { entry, .. } if entry == Path::new(expected)), "{}", error);
+    assert!(!root.join("outside").exists());
+  }
+
+  let (count, _) = unpack(&[
+    ("wget/1.24.5/lib/", Directory, "", b""),
+    ("wget/1.24.5/lib/libwget.1.dylib", Regular, "", b"lib"),
+    ("wget/1.24.5/lib/libwget.dylib", Symlink, "libwget.1.dylib", b""),
+    ("wget/1.24.5/bin/", Directory, "", b""),
+    ("wget/1.24.5/bin/libs", Symlink, "../lib", b""),
+    ("wget/1.24.5/bin/libwget.dylib", Link, "wget/1.24.5/lib/libwget.1.dylib", b""),
+  ]).await.unwrap();
+  assert_eq!(count, 4);
+  assert_eq!(std::fs::read(dest.join("wget/1.24.5/bin/libs/libwget.dylib")).unwrap(), b"lib");
+  assert_eq!(std::fs::read(dest.join("wget/1.24.5/bin/libwget.dylib")).unwrap(), b"lib");
+}
@@ -10,7 +10,7 @@ pub mod io;
 
 #[cfg(test)]
 mod tests {
-  use std::{str::FromStr, sync::{Arc, RwLock}};
+  use std::{path::{Path, PathBuf}, str::FromStr, sync::{atomic::{AtomicUsize, Ordering}, Arc, RwLock}};
   use crate::{io::fetch::MirrorLists, package::{formula::Formula, mirror::{MirrorServer, MirrorType}}, ui::bar::{PbWriter, Suspendable}};
 
   pub static FORMULA_FILE: &str = "cache/formula.json";
@@ -21,15 +21,287 @@ mod tests {
   pub static MIRROR: (MirrorType, &str) = (MirrorType::Bottle, "https://mirrors.ustc.edu.cn/homebrew-bottles");
 
   pub fn get_mirrors() -> MirrorLists {
-    MirrorLists {
-      lists: vec![MirrorServer::new(MIRROR.0, MIRROR.1, None)]
-    }
+    MirrorLists::new(vec![MirrorServer::new(MIRROR.0, MIRROR.1, None)])
   }
 
   pub fn get_formulas() -> Vec<Formula> {
     crate::io::read::read_formulas(FORMULA_FILE).unwrap()
   }
 
+  /// a minimal formula in the format of `formula.json`, with one bottle for ARCH
+  pub fn sample_formula(name: &str, version: &str, deps: &[&str]) -> serde_json::Value {
+    serde_json::json!({
+      "name": name, "full_name": name, "tap": "homebrew/core",
+      "oldname": null, "oldnames": [], "aliases": [], "versioned_formulae": [],
+      "desc": format!("sample {}", name), "license": null, "homepage": "https://example.com",
+      "versions": { "stable": version, "head": null, "bottle": true },
+      "urls": { "stable": { "url": "https://example.com/src.tar.gz", "tag": null, "revision": null, "using": null, "checksum": null } },
+      "revision": 0, "version_scheme": 0,
+      "bottle": { "stable": { "rebuild": 0, "root_url": "https://ghcr.io/v2/homebrew/core", "files": {
+        ARCH: { "cellar": "/opt/homebrew/Cellar", "url": format!("https://ghcr.io/v2/homebrew/core/{}/blobs/sha256:{}", name, "0".repeat(64)), "sha256": "0".repeat(64) },
+      } } },
+      "pour_bottle_only_if": null, "keg_only": false, "keg_only_reason": null, "options": [],
+      "build_dependencies": [], "dependencies": deps, "test_dependencies": [], "recommended_dependencies": [], "optional_dependencies": [],
+      "uses_from_macos": [], "uses_from_macos_bounds": [], "requirements": [], "conflicts_with": [], "link_overwrite": [],
+      "caveats": null, "deprecated": false, "deprecation_date": null, "deprecation_reason": null,
+      "disabled": false, "disable_date": null, "disable_reason": null, "post_install_defined": false,
+    })
+  }
+
+  /// the bottle of `name` for ARCH, with the sha256 of `blob`
+  pub fn sample_pkg(name: &str, version: &str, blob: &[u8]) -> crate::package::package::PkgBuild {
+    use sha2::{Digest, Sha256};
+    crate::package::package::PkgBuild {
+      name: name.to_string(),
+      version: version.to_string(),
+      arch: ARCH.to_string(),
+      rebuild: 0,
+      filename: format!("{}-{}.{}.bottle.tar.gz", name, version, ARCH),
+      url: String::new(),
+      sha256: format!("{:x}", Sha256::digest(blob)),
+      cellar: ":any".to_string(),
+    }
+  }
+
+  /// RSA key pair for signing test api files, the public key in pem
+  pub fn generate_key() -> (rsa::RsaPrivateKey, Vec<u8>) {
+    use rsa::pkcs8::EncodePublicKey as _;
+    let key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+    let public = key.to_public_key().to_public_key_pem(rsa::pkcs8::LineEnding::LF).unwrap();
+    (key, public.into_bytes())
+  }
+
+  /// sign like `formula.jws.json` with PS512, `b64` tells whether the payload is base64url encoded
+  pub fn sign_jws(payload: &str, kid: &str, key: &rsa::RsaPrivateKey, b64: bool) -> String {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+    use rsa::signature::{RandomizedSigner as _, SignatureEncoding as _};
+    let header = match b64 {
+      true => serde_json::json!({ "alg": "PS512" }),
+      false => serde_json::json!({ "alg": "PS512", "b64": false, "crit": ["b64"] }),
+    };
+    let protected = URL_SAFE_NO_PAD.encode(header.to_string());
+    let payload = if b64 { URL_SAFE_NO_PAD.encode(payload) } else { payload.to_string() };
+    let signer = rsa::pss::BlindedSigningKey::<sha2::Sha512>::new(key.clone());
+    let signature = signer.sign_with_rng(&mut rand::thread_rng(), format!("{}.{}", protected, payload).as_bytes()).to_vec();
+    serde_json::json!({
+      "payload": payload,
+      "signatures": [{ "protected": protected, "header": { "kid": kid }, "signature": URL_SAFE_NO_PAD.encode(signature) }],
+    }).to_string()
+  }
+
+  /// a minimal arm64 dylib with `headerpad` bytes free after load commands, the single `__text` section starts after that
+  pub fn sample_macho(id: Option<&str>, libs: &[&str], rpaths: &[&str], headerpad: usize) -> Vec<u8> {
+    fn put(buf: &mut [u8], offset: usize, value: &[u8]) { buf[offset..offset + value.len()].copy_from_slice(value) }
+    fn name16(name: &str) -> [u8; 16] { let mut result = [0; 16]; result[..name.len()].copy_from_slice(name.as_bytes()); result }
+    let string_cmd = |cmd: u32, fixed: usize, name: &str| {
+      let mut result = vec![0u8; fixed];
+      put(&mut result, 0, &cmd.to_le_bytes());
+      put(&mut result, 8, &(fixed as u32).to_le_bytes());
+      if fixed == 24 {
+        put(&mut result, 12, &2u32.to_le_bytes());
+        put(&mut result, 16, &0x10000u32.to_le_bytes());
+        put(&mut result, 20, &0x10000u32.to_le_bytes());
+      }
+      result.extend_from_slice(name.as_bytes());
+      result.push(0);
+      result.resize(result.len().div_ceil(8) * 8, 0);
+      let size = result.len() as u32;
+      put(&mut result, 4, &size.to_le_bytes());
+      result
+    };
+    let mut strings = Vec::new();
+    strings.extend(id.map(|i| string_cmd(0xd, 24, i)));
+    strings.extend(libs.iter().map(|i| string_cmd(0xc, 24, i)));
+    strings.extend(rpaths.iter().map(|i| string_cmd(0x8000_001c, 12, i)));
+    let sizeofcmds = 152 + 72 + strings.iter().map(Vec::len).sum::<usize>();
+    let section_off = (32 + sizeofcmds + headerpad).div_ceil(16) * 16;
+    let (text_size, linkedit_off, linkedit_size) = (100usize, 0x4000usize, 64usize);
+
+    let mut text = vec![0u8; 152];
+    put(&mut text, 0, &0x19u32.to_le_bytes());
+    put(&mut text, 4, &152u32.to_le_bytes());
+    put(&mut text, 8, &name16("__TEXT"));
+    put(&mut text, 32, &(linkedit_off as u64).to_le_bytes());
+    put(&mut text, 48, &(linkedit_off as u64).to_le_bytes());
+    put(&mut text, 56, &5u32.to_le_bytes());
+    put(&mut text, 60, &5u32.to_le_bytes());
+    put(&mut text, 64, &1u32.to_le_bytes());
+    put(&mut text, 72, &name16("__text"));
+    put(&mut text, 88, &name16("__TEXT"));
+    put(&mut text, 104, &(section_off as u64).to_le_bytes());
+    put(&mut text, 112, &(text_size as u64).to_le_bytes());
+    put(&mut text, 120, &(section_off as u32).to_le_bytes());
+    put(&mut text, 124, &2u32.to_le_bytes());
+    put(&mut text, 136, &0x8000_0400u32.to_le_bytes());
+    let mut linkedit = vec![0u8; 72];
+    put(&mut linkedit, 0, &0x19u32.to_le_bytes());
+    put(&mut linkedit, 4, &72u32.to_le_bytes());
+    put(&mut linkedit, 8, &name16("__LINKEDIT"));
+    put(&mut linkedit, 24, &(linkedit_off as u64).to_le_bytes());
+    put(&mut linkedit, 32, &0x4000u64.to_le_bytes());
+    put(&mut linkedit, 40, &(linkedit_off as u64).to_le_bytes());
+    put(&mut linkedit, 48, &(linkedit_size as u64).to_le_bytes());
+    put(&mut linkedit, 56, &1u32.to_le_bytes());
+    put(&mut linkedit, 60, &1u32.to_le_bytes());
+
+    let mut result = vec![0u8; linkedit_off + linkedit_size];
+    for (i, v) in [0xfeed_facfu32, 0x0100_000c, 0, 0x6, 2 + strings.len() as u32, sizeofcmds as u32, 0x85, 0].into_iter().enumerate() {
+      put(&mut result, i * 4, &v.to_le_bytes());
+    }
+    let mut offset = 32;
+    for cmd in [text, linkedit].iter().chain(&strings) {
+      put(&mut result, offset, cmd);
+      offset += cmd.len();
+    }
+    result[section_off..section_off + text_size].iter_mut().enumerate().for_each(|(i, v)| *v = i as u8);
+    result[linkedit_off..].fill(0xaa);
+    result
+  }
+
+  /// a gzipped bottle with `files` as (path, content), e.g. `("wget/1.24.5/bin/wget", ..)`, parent dirs are added
+  pub fn sample_bottle(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default()));
+    let mut dirs = std::collections::BTreeSet::new();
+    for (path, content) in files {
+      for dir in std::path::Path::new(path).ancestors().skip(1).filter(|i| !i.as_os_str().is_empty()).collect::<Vec<_>>().into_iter().rev() {
+        if dirs.insert(dir.to_path_buf()) {
+          let mut header = tar::Header::new_gnu();
+          header.set_entry_type(tar::EntryType::Directory);
+          header.set_mode(0o755);
+          header.set_size(0);
+          builder.append_data(&mut header, format!("{}/", dir.display()), std::io::empty()).unwrap();
+        }
+      }
+      let mut header = tar::Header::new_gnu();
+      header.set_mode(0o755);
+      header.set_size(content.len() as u64);
+      builder.append_data(&mut header, path, *content).unwrap();
+    }
+    builder.into_inner().unwrap().finish().unwrap()
+  }
+
+  /// a gzipped tar of raw `(path, type, link name, content)` entries, paths are not checked so they could be malicious
+  pub fn sample_tar(entries: &[(&str, tar::EntryType, &str, &[u8])]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default()));
+    for (path, kind, link_name, content) in entries {
+      let mut header = tar::Header::new_old();
+      header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+      header.as_old_mut().linkname[..link_name.len()].copy_from_slice(link_name.as_bytes());
+      header.set_entry_type(*kind);
+      header.set_mode(0o755);
+      header.set_size(content.len() as u64);
+      header.set_cksum();
+      builder.append(&header, *content).unwrap();
+    }
+    builder.into_inner().unwrap().finish().unwrap()
+  }
+
+  /// a universal binary of `slices` aligned to 16K, the cpu type is taken from each slice header (0 for archives)
+  pub fn sample_fat(slices: &[&[u8]]) -> Vec<u8> {
+    let mut result = [0xcafe_babeu32, slices.len() as u32].iter().flat_map(|i| i.to_be_bytes()).collect::<Vec<_>>();
+    let mut offset = 0x4000;
+    for slice in slices {
+      let (cputype, cpusubtype) = match slice.starts_with(&0xfeed_facfu32.to_le_bytes()) {
+        true => (u32::from_le_bytes(slice[4..8].try_into().unwrap()), u32::from_le_bytes(slice[8..12].try_into().unwrap())),
+        false => (0, 0),
+      };
+      result.extend([cputype, cpusubtype, offset as u32, slice.len() as u32, 14].iter().flat_map(|i| i.to_be_bytes()));
+      offset = (offset + slice.len()).div_ceil(0x4000) * 0x4000;
+    }
+    for slice in slices {
+      result.resize(result.len().div_ceil(0x4000) * 0x4000, 0);
+      result.extend_from_slice(slice);
+    }
+    result
+  }
+
+  /// a BSD style static archive with `members` as (name, content)
+  pub fn sample_archive(members: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut result = b"!<arch>\n".to_vec();
+    for (name, content) in members {
+      result.extend(format!("{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n", format!("{}/", name), 0, 0, 0, 644, content.len()).bytes());
+      result.extend_from_slice(content);
+      if result.len() % 2 == 1 {
+        result.push(b'\n');
+      }
+    }
+    result
+  }
+
+  /// a minimal x86_64 shared object with `PT_INTERP`, `DT_NEEDED` and `DT_RUNPATH`, all in a single `PT_LOAD` at vaddr 0
+  pub fn sample_elf(interp: &str, needed: &[&str], runpath: &str) -> Vec<u8> {
+    fn put(buf: &mut Vec<u8>, offset: usize, value: &[u8]) {
+      if buf.len() < offset + value.len() { buf.resize(offset + value.len(), 0) }
+      buf[offset..offset + value.len()].copy_from_slice(value)
+    }
+    let u64s = |values: &[u64]| values.iter().flat_map(|i| i.to_le_bytes()).collect::<Vec<_>>();
+    let interp_off = 64 + 4 * 56;
+    let mut dynstr = vec![0u8];
+    let mut strings = Vec::new();
+    // each needed library requires version `V1`, in .gnu.version_r
+    for name in needed.iter().chain([&runpath, &"V1"]) {
+      strings.push(dynstr.len() as u64);
+      dynstr.extend_from_slice(name.as_bytes());
+      dynstr.push(0);
+    }
+    let dynstr_off = interp_off + interp.len() + 1;
+    let dynamic_off = (dynstr_off + dynstr.len()).div_ceil(8) * 8;
+    let verneed_off = dynamic_off + (needed.len() + 6) * 16;
+    let mut dynamic = needed.iter().enumerate().flat_map(|(i, _)| [1, strings[i]]).collect::<Vec<_>>();
+    dynamic.extend([29, strings[needed.len()], 5, dynstr_off as u64, 10, dynstr.len() as u64]);
+    dynamic.extend([0x6ffffffe, verneed_off as u64, 0x6fffffff, needed.len() as u64, 0, 0]);
+    let dynamic = u64s(&dynamic);
+    // Elf64_Verneed (version, cnt, file, aux, next) followed by its Elf64_Vernaux (hash, flags, other, name, next)
+    let verneed = (0..needed.len()).flat_map(|i| {
+      let next = if i + 1 < needed.len() { 32u32 } else { 0 };
+      [&1u16.to_le_bytes()[..], &1u16.to_le_bytes(), &(strings[i] as u32).to_le_bytes(), &16u32.to_le_bytes(), &next.to_le_bytes(),
+        &0x5631u32.to_le_bytes(), &0u16.to_le_bytes(), &2u16.to_le_bytes(), &(strings[needed.len() + 1] as u32).to_le_bytes(), &0u32.to_le_bytes()].concat()
+    }).collect::<Vec<_>>();
+    let shstrtab_off = verneed_off + verneed.len();
+    let shstrtab = b"\0.interp\0.dynstr\0.dynamic\0.shstrtab\0.gnu.version_r\0";
+    let shoff = (shstrtab_off + shstrtab.len()).div_ceil(8) * 8;
+
+    let mut result = Vec::new();
+    put(&mut result, 0, b"\x7fELF\x02\x01\x01");
+    put(&mut result, 16, &[3, 0, 62, 0, 1, 0, 0, 0]);
+    put(&mut result, 32, &u64s(&[64, shoff as u64]));
+    put(&mut result, 52, &[64, 0, 56, 0, 4, 0, 64, 0, 6, 0, 5, 0]);
+    let phdrs: [(u32, u64, u64, u64); 4] = [
+      (6, 64, 4 * 56, 8),
+      (3, interp_off as u64, interp.len() as u64 + 1, 1),
+      (1, 0, shstrtab_off as u64, 0x1000),
+      (2, dynamic_off as u64, dynamic.len() as u64, 8),
+    ];
+    for (i, (kind, offset, size, align)) in phdrs.into_iter().enumerate() {
+      put(&mut result, 64 + i * 56, &[kind.to_le_bytes(), 4u32.to_le_bytes()].concat());
+      put(&mut result, 64 + i * 56 + 8, &u64s(&[offset, offset, offset, size, size, align]));
+    }
+    put(&mut result, interp_off, interp.as_bytes());
+    put(&mut result, dynstr_off, &dynstr);
+    put(&mut result, dynamic_off, &dynamic);
+    put(&mut result, verneed_off, &verneed);
+    put(&mut result, shstrtab_off, shstrtab);
+    // name, type, flags, offset, size, link, info, entsize
+    type Shdr = (u32, u32, u64, usize, usize, u32, u32, u64);
+    let shdrs: [Shdr; 6] = [
+      (0, 0, 0, 0, 0, 0, 0, 0),
+      (1, 1, 2, interp_off, interp.len() + 1, 0, 0, 0),
+      (9, 3, 2, dynstr_off, dynstr.len(), 0, 0, 0),
+      (17, 6, 3, dynamic_off, dynamic.len(), 2, 0, 16),
+      (36, 0x6ffffffe, 2, verneed_off, verneed.len(), 2, needed.len() as u32, 0),
+      (26, 3, 0, shstrtab_off, shstrtab.len(), 0, 0, 0),
+    ];
+    for (i, (name, kind, flags, offset, size, link, info, entsize)) in shdrs.into_iter().enumerate() {
+      let s = shoff + i * 64;
+      let addr = if flags == 0 { 0 } else { offset as u64 };
+      put(&mut result, s, &[name.to_le_bytes(), kind.to_le_bytes()].concat());
+      put(&mut result, s + 8, &u64s(&[flags, addr, offset as u64, size as u64]));
+      put(&mut result, s + 40, &[link.to_le_bytes(), info.to_le_bytes()].concat());
+      put(&mut result, s + 48, &u64s(&[1, entsize]));
+    }
+    result
+  }
+
   pub fn init_logger(env_filter: Option<&str>) -> Arc<RwLock<Option<Suspendable>>> {
     use tracing_subscriber::fmt::format::FmtSpan;
     let active_pb = Arc::new(RwLock::new(None));
@@ -38,7 +310,134 @@ mod tests {
       .with_env_filter(tracing_subscriber::EnvFilter::from_str(env_filter.unwrap_or("info,pacbrew_core=debug")).unwrap())
       .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
       .with_writer(move || PbWriter::new(active_pb.read().unwrap().clone(), std::io::stderr()))
-      .init();
+      .try_init();
     result
   }
+
+  #[derive(Debug, Clone)]
+  pub struct StandInRequest {
+    pub method: String,
+    pub path: String,
+    /// keys are lowercase
+    pub headers: std::collections::HashMap<String, String>,
+  }
+
+  pub struct StandInResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+  }
+
+  impl StandInResponse {
+    pub fn ok(body: impl Into<Vec<u8>>) -> Self {
+      Self { status: 200, headers: vec![], body: body.into() }
+    }
+    pub fn status(status: u16) -> Self {
+      Self { status, headers: vec![], body: vec![] }
+    }
+    pub fn header(mut self, key: &str, value: &str) -> Self {
+      self.headers.push((key.to_string(), value.to_string()));
+      self
+    }
+  }
+
+  /// a minimal http/1.1 server on localhost, every connection is answered by `handler` once and closed.
+  /// returns the base url like `http://127.0.0.1:12345`
+  pub async fn stand_in<F>(handler: F) -> String
+  where
+    F: Fn(&StandInRequest) -> StandInResponse + Send + Sync + 'static,
+  {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handler = Arc::new(handler);
+    tokio::spawn(async move {
+      while let Ok((stream, _)) = listener.accept().await {
+        let handler = handler.clone();
+        tokio::spawn(async move {
+          let mut stream = tokio::io::BufReader::new(stream);
+          let mut line = String::new();
+          stream.read_line(&mut line).await?;
+          let mut parts = line.split_whitespace();
+          let method = parts.next().unwrap_or_default().to_string();
+          let path = parts.next().unwrap_or_default().to_string();
+          let mut headers = std::collections::HashMap::new();
+          loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await? == 0 || line.trim().is_empty() { break }
+            if let Some((k, v)) = line.split_once(':') {
+              headers.insert(k.trim().to_ascii_lowercase(), v.trim().to_string());
+            }
+          }
+          let req = StandInRequest { method, path, headers };
+          let resp = handler(&req);
+          let mut head = format!("HTTP/1.1 {} STAND-IN\r\nconnection: close\r\n", resp.status);
+          if !resp.headers.iter().any(|(k, _)| k.eq_ignore_ascii_case("content-length")) {
+            head.push_str(&format!("content-length: {}\r\n", resp.body.len()));
+          }
+          for (k, v) in &resp.headers {
+            head.push_str(&format!("{}: {}\r\n", k, v));
+          }
+          head.push_str("\r\n");
+          let stream = stream.get_mut();
+          stream.write_all(head.as_bytes()).await?;
+          if req.method != "HEAD" {
+            stream.write_all(&resp.body).await?;
+          }
+          stream.shutdown().await
+        });
+      }
+    });
+    format!("http://{}", addr)
+  }
+
+  /// a stand-in bottle mirror answered by `handler`, and the count of requests it got
+  pub async fn counting_server<F>(handler: F) -> (MirrorServer, Arc<AtomicUsize>)
+  where
+    F: Fn(&StandInRequest) -> StandInResponse + Send + Sync + 'static,
+  {
+    let count = Arc::new(AtomicUsize::new(0));
+    let counter = count.clone();
+    let base = stand_in(move |req| {
+      counter.fetch_add(1, Ordering::SeqCst);
+      handler(req)
+    }).await;
+    (MirrorServer::new(MirrorType::Bottle, &base, None), count)
+  }
+
+  /// [`counting_server`] as the only mirror
+  pub async fn counting_mirror<F>(handler: F) -> (MirrorLists, Arc<AtomicUsize>)
+  where
+    F: Fn(&StandInRequest) -> StandInResponse + Send + Sync + 'static,
+  {
+    let (server, count) = counting_server(handler).await;
+    (MirrorLists::new(vec![server]), count)
+  }
+
+  /// an empty `cache/{name}`, removed again when dropped, so a failed assertion doesn't leave it to later runs
+  pub struct TestDir(PathBuf);
+
+  impl TestDir {
+    pub fn new(name: &str) -> Self {
+      let path = Path::new("cache").join(name);
+      std::fs::remove_dir_all(&path).ok();
+      std::fs::create_dir_all(&path).unwrap();
+      Self(path)
+    }
+  }
+
+  impl std::ops::Deref for TestDir {
+    type Target = Path;
+    fn deref(&self) -> &Path { &self.0 }
+  }
+
+  impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path { &self.0 }
+  }
+
+  impl Drop for TestDir {
+    fn drop(&mut self) {
+      std::fs::remove_dir_all(&self.0).ok();
+    }
+  }
 }
@@ -361,7 +361,6 @@ pub struct Formula {
 #[test]
 fn test_formula() {
   crate::tests::init_logger(None);
-  // TODO: enable brotli?
   let filename = "cache/formula.json";
   let formulas = crate::io::read::read_formulas(filename).unwrap();
   info!(message="parsed", formula.len=formulas.len());
@@ -1,14 +1,66 @@
+use std::{collections::{BTreeMap, HashMap}, path::PathBuf, sync::{Arc, Mutex, OnceLock}, time::Duration};
+
+use crate::{error::{Error, ErrorExt as _, Result}, io::{auth::{Credentials, RegistryAuth}, fetch::Resolved, http::MirrorClient}};
+
 use super::package::PkgBuild;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum MirrorType {
   Ghcr, Oci, Bottle,
+  /// a directory (or `file://` url) of `*.bottle.tar.gz` and `api/formula.json`, e.g. on a NFS share
+  Local,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum TlsVersion {
+  #[serde(rename = "1.0")] Tls1_0,
+  #[serde(rename = "1.1")] Tls1_1,
+  #[serde(rename = "1.2")] Tls1_2,
+  #[serde(rename = "1.3")] Tls1_3,
+}
+
+impl From<TlsVersion> for reqwest::tls::Version {
+  fn from(value: TlsVersion) -> Self {
+    match value {
+      TlsVersion::Tls1_0 => Self::TLS_1_0,
+      TlsVersion::Tls1_1 => Self::TLS_1_1,
+      TlsVersion::Tls1_2 => Self::TLS_1_2,
+      TlsVersion::Tls1_3 => Self::TLS_1_3,
+    }
+  }
+}
+
+/// options applied when building the http client of a mirror
+#[derive(Debug, Clone, Default)]
+pub struct NetworkOptions {
+  /// timeout for establishing the connection
+  pub connect_timeout: Option<Duration>,
+  /// timeout between two reads of the response body
+  pub read_timeout: Option<Duration>,
+  /// timeout of the whole request, including the body
+  pub timeout: Option<Duration>,
+  /// proxy for both http and https, e.g. `http://proxy.corp:3128`
+  pub proxy: Option<String>,
+  /// comma separated hosts bypassing the proxy, same format as `NO_PROXY`
+  pub no_proxy: Option<String>,
+  /// extra pem files (may contain several certificates) trusted as root CA
+  pub ca_certs: Vec<PathBuf>,
+  pub min_tls_version: Option<TlsVersion>,
 }
 
 pub struct MirrorServer {
   pub server_type: MirrorType,
   pub base_url: String,
   pub api_base_url: Option<String>,
+  pub network: NetworkOptions,
+  /// extra headers sent with every request to this mirror
+  pub headers: BTreeMap<String, String>,
+  /// token cache of registries, shared like the client
+  pub auth: Option<Arc<RegistryAuth>>,
+  /// built on first use and shared by all requests to this mirror, so keep-alive connections are reused
+  client: OnceLock<reqwest::Client>,
+  /// packages resolved from OCI manifests, keyed by sha256, so probe and download share the lookup
+  pub(crate) resolved: Mutex<HashMap<String, Resolved>>,
 }
 
 impl MirrorServer {
@@ -16,50 +68,149 @@ impl MirrorServer {
     if server_type == MirrorType::Ghcr {
       warn!("should not use ghcr with custom base_url, please use MirrorServer::ghcr() instead");
     }
-    Self { server_type, base_url: base_url.to_string(), api_base_url: api_base_url.map(|s| s.to_string()) }
+    let base_url = match server_type {
+      MirrorType::Local => Self::local_url(base_url),
+      _ => base_url.to_string(),
+    };
+    Self {
+      server_type,
+      base_url,
+      api_base_url: api_base_url.map(|s| s.to_string()),
+      network: Default::default(),
+      headers: Default::default(),
+      auth: Self::default_auth(server_type),
+      client: OnceLock::new(),
+      resolved: Default::default(),
+    }
   }
   pub fn ghcr() -> Self {
     Self {
       server_type: MirrorType::Ghcr,
       api_base_url: Some("https://formulae.brew.sh/api/".to_string()),
       base_url: "https://ghcr.io/v2/homebrew/core/".to_string(),
+      network: Default::default(),
+      headers: Default::default(),
+      auth: Self::default_auth(MirrorType::Ghcr),
+      client: OnceLock::new(),
+      resolved: Default::default(),
+    }
+  }
+
+  fn default_auth(server_type: MirrorType) -> Option<Arc<RegistryAuth>> {
+    match server_type {
+      MirrorType::Ghcr | MirrorType::Oci => Some(Default::default()),
+      MirrorType::Bottle | MirrorType::Local => None,
+    }
+  }
+
+  /// plain paths are made absolute `file://` urls, so the mirror does not depend on the working directory
+  fn local_url(base_url: &str) -> String {
+    if base_url.starts_with("file://") {
+      return base_url.to_string()
     }
+    crate::io::read::try_abs_path(base_url)
+      .and_then(|path| reqwest::Url::from_directory_path(path).ok())
+      .map(|url| url.to_string())
+      .unwrap_or_else(|| base_url.to_string())
+  }
+
+  /// basic credentials, used to request tokens of registries, or answer basic challenges
+  pub fn credentials(self, credentials: Option<Credentials>) -> Self {
+    match credentials {
+      Some(credentials) => Self { auth: Some(Arc::new(RegistryAuth::new(Some(credentials)))), ..self },
+      None => self,
+    }
+  }
+
+  pub fn network(self, network: NetworkOptions) -> Self {
+    Self { network, client: OnceLock::new(), ..self }
+  }
+
+  pub fn headers(self, headers: BTreeMap<String, String>) -> Self {
+    Self { headers, client: OnceLock::new(), ..self }
   }
 
   pub fn api_url(&self, target: &str) -> Option<String> {
     match (self.server_type, &self.api_base_url) {
       (_, Some(api_base_url)) => Some(format!("{}/{}", api_base_url.trim_end_matches('/'), target)),
-      (MirrorType::Bottle, _) => Some(format!("{}/api/{}", self.base_url.trim_end_matches('/'), target)),
+      (MirrorType::Bottle | MirrorType::Local, _) => Some(format!("{}/api/{}", self.base_url.trim_end_matches('/'), target)),
       _ => None
     }
   }
 
+  /// guessed from name and sha256, OCI mirrors should resolve the blob through manifests, see [`crate::io::fetch::MirrorUrl::resolve`]
   pub fn package_url(&self, build: &PkgBuild) -> String {
     match self.server_type {
-      MirrorType::Oci | MirrorType::Ghcr => format!("{}/{}/blobs/sha256:{}", self.base_url, build.name.replace("@", "/").replace("+", "x"), build.sha256),
+      MirrorType::Oci | MirrorType::Ghcr => format!("{}/blobs/sha256:{}", self.repository_url(build), build.sha256),
       MirrorType::Bottle => format!("{}/{}", self.base_url, build.filename),
+      MirrorType::Local => format!("{}/{}", self.base_url.trim_end_matches('/'), build.filename),
     }
   }
 
-  pub fn client(&self) -> reqwest::Client {
-    let builder = reqwest::Client::builder();
+  /// `{base_url}/{name}` of the package in OCI registries, `@` and `+` are not allowed in repository names
+  pub fn repository_url(&self, build: &PkgBuild) -> String {
+    format!("{}/{}", self.base_url.trim_end_matches('/'), build.name.replace("@", "/").replace("+", "x"))
+  }
+
+  pub fn client(&self) -> Result<MirrorClient> {
+    let client = match self.client.get() {
+      Some(client) => client.clone(),
+      None => {
+        let client = self.build_client()?;
+        self.client.get_or_init(|| client).clone()
+      }
+    };
+    Ok(MirrorClient::new(client, self.auth.clone()))
+  }
+
+  fn build_client(&self) -> Result<reqwest::Client> {
+    use reqwest::header;
+    let url = &self.base_url;
+    let mut builder = reqwest::Client::builder();
+    if let Some(timeout) = self.network.connect_timeout {
+      builder = builder.connect_timeout(timeout);
+    }
+    if let Some(timeout) = self.network.read_timeout {
+      builder = builder.read_timeout(timeout);
+    }
+    if let Some(timeout) = self.network.timeout {
+      builder = builder.timeout(timeout);
+    }
+    if let Some(proxy) = &self.network.proxy {
+      let proxy = reqwest::Proxy::all(proxy).map_err(Error::build_client(url, "proxy"))?
+        .no_proxy(self.network.no_proxy.as_deref().and_then(reqwest::NoProxy::from_string));
+      builder = builder.proxy(proxy);
+    }
+    for ca in &self.network.ca_certs {
+      let pem = std::fs::read(ca).when(("read", ca))?;
+      for cert in reqwest::Certificate::from_pem_bundle(&pem).map_err(Error::build_client(url, "ca_certs"))? {
+        builder = builder.add_root_certificate(cert);
+      }
+    }
+    if let Some(version) = self.network.min_tls_version {
+      builder = builder.min_tls_version(version.into());
+    }
+    let mut headers = header::HeaderMap::new();
     let builder = match self.server_type {
       MirrorType::Ghcr => {
-        use reqwest::header;
-        let mut headers = header::HeaderMap::new();
-        let mut auth_value = header::HeaderValue::from_static("Bearer QQ==");
-        auth_value.set_sensitive(true);
-        headers.insert(header::AUTHORIZATION, auth_value);
         builder
           .user_agent("pacbrew/0.1")
-          .default_headers(headers)
         },
-      MirrorType::Oci | MirrorType::Bottle => {
+      MirrorType::Oci | MirrorType::Bottle | MirrorType::Local => {
         builder
           .user_agent("Wget/1.21.3")
       },
     };
-    builder.build().expect("build client")
+    for (k, v) in &self.headers {
+      let name = header::HeaderName::from_bytes(k.as_bytes()).map_err(Error::build_client(url, "header name"))?;
+      let mut value = header::HeaderValue::from_str(v).map_err(Error::build_client(url, "header value"))?;
+      if name == header::AUTHORIZATION {
+        value.set_sensitive(true);
+      }
+      headers.insert(name, value);
+    }
+    debug!(base_url=self.base_url, network=?self.network, headers=?self.headers.keys().collect::<Vec<_>>(), "build client");
+    builder.default_headers(headers).build().map_err(Error::build_client(url, "build"))
   }
 }
 
@@ -1,3 +1,4 @@
 pub mod formula;
 pub mod package;
 pub mod mirror;
+pub mod oci;
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+
+use serde::{Serialize, Deserialize};
+
+// GET /v2/homebrew/core/wget/manifests/1.24.5
+// {
+//   "schemaVersion": 2,
+//   "mediaType": "application/vnd.oci.image.index.v1+json",
+//   "manifests": [
+//     {
+//       "mediaType": "application/vnd.oci.image.manifest.v1+json",
+//       "digest": "sha256:...",
+//       "size": 2041,
+//       "platform": {
+//         "architecture": "arm64",
+//         "os": "darwin",
+//         "os.version": "macOS 14"
+//       },
+//       "annotations": {
+//         "org.opencontainers.image.ref.name": "1.24.5.arm64_sonoma",
+//         "sh.brew.bottle.digest": "...",
+//         "sh.brew.tab": "{\"homebrew_version\":\"4.2.11\",\"changed_files\":[...],...}"
+//       }
+//     }
+//   ]
+// }
+//
+// GET /v2/homebrew/core/wget/manifests/sha256:...
+// {
+//   "schemaVersion": 2,
+//   "mediaType": "application/vnd.oci.image.manifest.v1+json",
+//   "config": { ... },
+//   "layers": [
+//     {
+//       "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+//       "digest": "sha256:...",
+//       "size": 1450237,
+//       "annotations": {
+//         "org.opencontainers.image.title": "wget--1.24.5.arm64_sonoma.bottle.tar.gz"
+//       }
+//     }
+//   ],
+//   "annotations": { ... }
+// }
+
+pub const MEDIA_TYPES: &str = "application/vnd.oci.image.index.v1+json, application/vnd.oci.image.manifest.v1+json";
+pub const REF_NAME: &str = "org.opencontainers.image.ref.name";
+pub const BREW_TAB: &str = "sh.brew.tab";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+  pub media_type: Option<String>,
+  pub digest: String,
+  pub size: u64,
+  #[serde(default)]
+  pub annotations: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageIndex {
+  pub manifests: Vec<Descriptor>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageManifest {
+  pub layers: Vec<Descriptor>,
+  #[serde(default)]
+  pub annotations: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuntimeDependency {
+  pub full_name: String,
+  pub version: Option<String>,
+  pub revision: Option<u32>,
+  pub pkg_version: Option<String>,
+  pub declared_directly: Option<bool>,
+}
+
+/// the `sh.brew.tab` annotation, which is the INSTALL_RECEIPT of the bottle
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BottleTab {
+  pub homebrew_version: Option<String>,
+  /// files contain placeholders, relative to the keg
+  #[serde(default)]
+  pub changed_files: Vec<String>,
+  pub source_modified_time: Option<u64>,
+  pub compiler: Option<String>,
+  #[serde(default)]
+  pub runtime_dependencies: Vec<RuntimeDependency>,
+  pub arch: Option<String>,
+}
+
+impl ImageIndex {
+  /// homebrew tags each platform manifest as `{version}.{bottle tag}[.{rebuild}]`
+  pub fn find(&self, ref_name: &str) -> Option<&Descriptor> {
+    self.manifests.iter().find(|i| i.annotations.get(REF_NAME).map(String::as_str) == Some(ref_name))
+  }
+}
@@ -1,6 +1,6 @@
-use std::{collections::BTreeMap, path::PathBuf};
+use std::{collections::{BTreeMap, BTreeSet}, path::PathBuf};
 
-use super::formula::Formula;
+use super::{formula::Formula, oci::BottleTab};
 use crate::io::relocate::RelocateType;
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
@@ -17,26 +17,73 @@ pub struct Package {
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct PkgBuild {
   pub name: String,
+  /// version with revision, e.g. `1.2.3_1`
+  #[serde(default)]
+  pub version: String,
   pub arch: String,
   pub rebuild: u32,
   pub filename: String,
   pub url: String,
   pub sha256: String,
+  /// `cellar` of the bottle, see [`Cellar`]
+  #[serde(default)]
+  pub cellar: String,
+}
+
+/// where a bottle could be poured, parsed from `Bottle.cellar`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Cellar {
+  /// `:any`, relocatable to any cellar
+  Any,
+  /// `:any_skip_relocation`, nothing inside needs to be relocated
+  AnySkipRelocation,
+  /// built against this cellar, e.g. `/opt/homebrew/Cellar`, could only be poured there
+  Fixed(PathBuf),
+}
+
+impl Cellar {
+  pub fn parse(s: &str) -> Self {
+    match s {
+      ":any_skip_relocation" => Self::AnySkipRelocation,
+      "" | ":any" => Self::Any,
+      path => Self::Fixed(PathBuf::from(path)),
+    }
+  }
 }
 
 impl std::fmt::Debug for PkgBuild {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     f.debug_struct("PkgBuild")
       .field("name", &self.name)
+      .field("version", &self.version)
       .field("arch", &self.arch)
       .field("rebuild", &self.rebuild)
       .field("filename", &self.filename)
       // .field("url", &self.url)
       .field("sha256", &self.sha256)
+      .field("cellar", &self.cellar)
       .finish()
   }
 }
 
+impl PkgBuild {
+  /// tag of the image in OCI registries, `{version}` or `{version}-{rebuild}`
+  pub fn oci_tag(&self) -> String {
+    match self.rebuild {
+      0 => self.version.clone(),
+      rebuild => format!("{}-{}", self.version, rebuild),
+    }
+  }
+
+  /// name of the platform manifest in the image index, `{version}.{arch}` or `{version}.{arch}.{rebuild}`
+  pub fn oci_ref_name(&self) -> String {
+    match self.rebuild {
+      0 => format!("{}.{}", self.version, self.arch),
+      rebuild => format!("{}.{}.{}", self.version, self.arch, rebuild),
+    }
+  }
+}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct PackageVersion {
   pub name: String,
@@ -56,6 +103,7 @@ impl From<Formula> for PackageVersion {
       .map(|(arch, meta, bottle)|
         PkgBuild {
           name: f.name.clone(),
+          version: version_full.clone(),
           arch: arch.to_string(),
           rebuild: meta.rebuild,
           filename: if meta.rebuild == 0 {
@@ -64,7 +112,8 @@ impl From<Formula> for PackageVersion {
             format!("{}-{}.{}.bottle.{}.tar.gz", f.name, version_full, arch, meta.rebuild)
           },
           url: bottle.url.clone(),
-          sha256: bottle.sha256.clone()
+          sha256: bottle.sha256.clone(),
+          cellar: bottle.cellar.clone(),
         })
       .collect::<Vec<_>>();
     Self {
@@ -100,11 +149,14 @@ impl PackageVersion {
   }
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct PackageUrl {
   pub name: String,
   pub pkg_url: String,
   pub pkg_size: u64,
+  /// only available from OCI mirrors
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub tab: Option<BottleTab>,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -112,6 +164,9 @@ pub struct PackageCache {
   pub name: String,
   pub cache_pkg: PathBuf,
   pub cache_size: u64,
+  /// `cellar` of the bottle, see [`Cellar`]
+  #[serde(default)]
+  pub cellar: String,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -120,6 +175,9 @@ pub struct PackageInstalled {
   pub dest: PathBuf,
   pub version: String,
   pub reloc: BTreeMap<PathBuf, RelocateType>,
+  /// `@@HOMEBREW_*@@` tokens without a configured value, left in these files
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub unknown_placeholders: BTreeMap<PathBuf, BTreeSet<String>>,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -128,3 +186,33 @@ pub struct PackageLinked {
   pub dest: PathBuf,
   pub version: String,
 }
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PackageChecked {
+  pub name: String,
+  pub dest: PathBuf,
+  pub version: String,
+  /// by file relative to `dest`, empty if the keg is fine
+  pub issues: Vec<(PathBuf, Issue)>,
+}
+
+/// what relocation missed in a file of an installed keg
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Issue {
+  /// `@@HOMEBREW_*@@` tokens left in the file
+  Placeholder(BTreeSet<String>),
+  /// a Mach-O/ELF load path outside prefix, cellar and system dirs, e.g. `/opt/homebrew/lib/libintl.8.dylib`
+  ForeignPath(String),
+  /// a symlink to the target which does not exist
+  BrokenLink(PathBuf),
+}
+
+impl std::fmt::Display for Issue {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Placeholder(tokens) => write!(f, "placeholders {} left", tokens.iter().map(String::as_str).collect::<Vec<_>>().join(", ")),
+      Self::ForeignPath(path) => write!(f, "load path {} outside prefix", path),
+      Self::BrokenLink(target) => write!(f, "broken symlink to {}", target.display()),
+    }
+  }
+}
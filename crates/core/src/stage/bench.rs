@@ -0,0 +1,100 @@
+//! measure each mirror against a known bottle, results are recorded into [`MirrorHealth`](crate::io::health::MirrorHealth)
+
+use std::{path::Path, time::{Duration, Instant}};
+
+use crate::{error::{Error, ErrorExt as _, Result}, io::{fetch::{FetchReq, MirrorLists, MirrorUrl}, http::DownloadTask}, package::{mirror::{MirrorServer, MirrorType}, package::PkgBuild}, ui::{event::ItemEvent, EventListener}};
+
+pub struct Value {
+  pub base_url: String,
+  pub server_type: MirrorType,
+  /// time until the size of the bottle is known
+  pub latency: Option<Duration>,
+  pub size: Option<u64>,
+  /// time of the whole download
+  pub elapsed: Option<Duration>,
+  pub error: Option<Error>,
+}
+
+impl Value {
+  /// bytes per second
+  pub fn throughput(&self) -> Option<f64> {
+    let elapsed = self.elapsed?.as_secs_f64();
+    Some(self.size? as f64 / elapsed).filter(|_| elapsed > 0.)
+  }
+}
+
+pub async fn step(mirrors: &MirrorLists, mirror: &MirrorServer, pkg: &PkgBuild, tmp_dir: &Path) -> Value {
+  let mut value = Value {
+    base_url: mirror.base_url.clone(),
+    server_type: mirror.server_type,
+    latency: None,
+    size: None,
+    elapsed: None,
+    error: None,
+  };
+  let req = FetchReq::Package(pkg.clone());
+  let target = req.target(tmp_dir);
+  let result: Result<_> = async {
+    let start = Instant::now();
+    let mirror_url = MirrorUrl { mirror, client: mirror.client()?, url: mirror.package_url(pkg) };
+    let resolved = mirror_url.resolve(&req).await?;
+    value.size = Some(mirror_url.content_length(&resolved).await?);
+    value.latency = Some(start.elapsed());
+    mirrors.health.record_latency(&mirror.base_url, start.elapsed());
+    let start = Instant::now();
+    let state = DownloadTask::new(resolved.url, &target, Some(pkg.sha256.clone()))?
+      .client(Some(mirror_url.client.clone())).force(true).run(()).await?.fetched().unwrap_or_default();
+    value.size = Some(state.current);
+    value.elapsed = Some(start.elapsed());
+    mirrors.health.record_transfer(&mirror.base_url, state.current, start.elapsed());
+    Ok(())
+  }.await;
+  if let Err(error) = result {
+    warn!(base_url=mirror.base_url, %error, "bench failed");
+    if error.is_mirror_down() {
+      mirrors.health.record_failure(&mirror.base_url);
+    }
+    value.error = Some(error);
+  }
+  std::fs::remove_file(&target).ok();
+  value
+}
+
+/// mirrors are measured one by one in config order, the bottle is downloaded to `tmp_dir` and removed afterwards
+#[tracing::instrument(level = "debug", skip_all, fields(mirrors.len = mirrors.len(), package = %pkg.name))]
+pub async fn exec<P: AsRef<Path>>(mirrors: &MirrorLists, pkg: &PkgBuild, tmp_dir: P, tracker: impl EventListener<ItemEvent>) -> Result<Vec<Value>> {
+  let tmp_dir = tmp_dir.as_ref();
+  std::fs::create_dir_all(tmp_dir).when(("create_dir_all", tmp_dir))?;
+  let mut result = Vec::new();
+  for (i, mirror) in mirrors.lists.iter().enumerate() {
+    tracker.on_event(ItemEvent::Progress { current: i, max: Some(mirrors.len()) });
+    tracker.on_event(ItemEvent::Message { name: format!("bench {}", mirror.base_url) });
+    result.push(step(mirrors, mirror, pkg, tmp_dir).await);
+  }
+  tracker.on_event(ItemEvent::Finish);
+  Ok(result)
+}
+
+#[tokio::test]
+async fn test_bench() {
+  use crate::{io::serve::Server, tests::*};
+  init_logger(None);
+  let root = TestDir::new("test_bench");
+  std::fs::create_dir_all(root.join("mirror")).unwrap();
+  let blob = vec![7u8; 4096];
+  let pkg = sample_pkg("wget", "1.24.5", &blob);
+  std::fs::write(root.join("mirror").join(&pkg.filename), &blob).unwrap();
+  let listener = Server::bind("127.0.0.1:0").await.unwrap();
+  let base = format!("http://{}", listener.local_addr().unwrap());
+  tokio::spawn(Server::new(root.join("mirror")).run(listener));
+  let (dead, _) = counting_server(|_| StandInResponse::status(503)).await;
+
+  let mirrors = MirrorLists::new(vec![dead, MirrorServer::new(MirrorType::Bottle, &base, None)]);
+  let result = exec(&mirrors, &pkg, root.join("tmp"), ()).await.unwrap();
+  assert!(result[0].error.is_some());
+  assert!(result[1].error.is_none(), "{:?}", result[1].error);
+  assert_eq!(result[1].size, Some(4096));
+  assert!(!root.join("tmp").join(&pkg.filename).exists());
+  let ranked = mirrors.health.rank(&mirrors.lists);
+  assert_eq!(ranked[0].base_url, base);
+}
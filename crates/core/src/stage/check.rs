@@ -0,0 +1,141 @@
+use std::{collections::BTreeSet, path::{Path, PathBuf}};
+
+use memmap2::MmapOptions;
+
+use crate::{error::{Error, ErrorExt, Result}, io::{read::try_abs_path, relocate::{placeholders, LoadPath}}, package::package::PackageChecked, ui::{event::ItemEvent, EventListener}};
+
+pub use crate::package::package::Issue;
+
+/// load paths under these are provided by the system, not by bottles
+pub const SYSTEM_PATHS: &[&str] = &["/usr/lib", "/System/Library", "/lib", "/lib64", "/usr/lib64"];
+
+pub struct Args<'a> {
+  pub prefix: &'a Path,
+  pub cellar: &'a Path,
+  /// dirs of load paths which are not reported, [`SYSTEM_PATHS`] by default
+  pub system: Vec<PathBuf>,
+}
+impl<'a> Args<'a> {
+  pub fn new<P1: AsRef<Path>, P2: AsRef<Path>>(prefix: &'a P1, cellar: &'a P2) -> Self {
+    Self { prefix: prefix.as_ref(), cellar: cellar.as_ref(), system: SYSTEM_PATHS.iter().map(PathBuf::from).collect() }
+  }
+  pub fn system(self, system: Vec<PathBuf>) -> Self {
+    Self { system, ..self }
+  }
+}
+
+/// issues of files in `keg`, paths are relative to it
+pub fn step<P: AsRef<Path>>(args: &Args, keg: P) -> Result<Vec<(PathBuf, Issue)>> {
+  let keg = keg.as_ref();
+  let mut allowed = Vec::new();
+  for dir in [args.prefix, args.cellar] {
+    let abs = try_abs_path(dir).ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound)).when(("canonicalize", dir))?;
+    allowed.push(abs);
+  }
+  allowed.extend(args.system.iter().cloned());
+  let mut result = Vec::new();
+  for entry in walkdir::WalkDir::new(keg).sort_by_file_name() {
+    let entry = entry.map_err(std::io::Error::from).when(("walk", keg))?;
+    let path = entry.path();
+    let name = path.strip_prefix(keg).unwrap_or(path).to_path_buf();
+    if entry.path_is_symlink() {
+      if std::fs::metadata(path).is_err() {
+        let target = std::fs::read_link(path).when(("read_link", path))?;
+        result.push((name, Issue::BrokenLink(target)));
+      }
+      continue
+    }
+    if !entry.file_type().is_file() || entry.metadata().is_ok_and(|i| i.len() == 0) {
+      continue
+    }
+    let file = std::fs::File::open(path).when(("open", path))?;
+    let mmap = unsafe { MmapOptions::new().map(&file) }.when(("memmap", path))?;
+    let tokens = placeholders(&mmap);
+    if !tokens.is_empty() {
+      result.push((name.clone(), Issue::Placeholder(tokens)));
+    }
+    let foreign = LoadPath::parse(&mmap).unwrap_or_default().into_iter()
+      .flat_map(|i| match i {
+        LoadPath::Rpath(paths) => paths.split(':').collect(),
+        i => vec![i.path()],
+      })
+      .filter(|i| i.starts_with('/') && !allowed.iter().any(|dir| Path::new(i).starts_with(dir)))
+      .collect::<BTreeSet<_>>();
+    result.extend(foreign.into_iter().map(|i| (name.clone(), Issue::ForeignPath(i.to_string()))));
+  }
+  Ok(result)
+}
+
+/// check every installed version of `names` in the cellar
+pub async fn exec<'a, I: IntoIterator<Item = &'a str>>(
+  args: Args<'a>,
+  names: I,
+  tracker: impl EventListener<ItemEvent>,
+) -> Result<Vec<PackageChecked>> {
+  let mut result = Vec::new();
+  for (i, name) in names.into_iter().enumerate() {
+    tracker.on_event(ItemEvent::Message { name: format!("checking {}", name) });
+    let dir = args.cellar.join(name);
+    let mut versions = match std::fs::read_dir(&dir) {
+      Ok(versions) => versions.collect::<std::io::Result<Vec<_>>>().when(("read_dir", &dir))?,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(Error::package_not_found(name)),
+      Err(e) => return Err(e).when(("read_dir", &dir)),
+    };
+    versions.sort_by_key(|i| i.file_name());
+    // `tmp` is left by an interrupted unpack
+    for version in versions.into_iter().filter(|i| i.file_name() != "tmp") {
+      let dest = version.path();
+      let issues = step(&args, &dest)?;
+      debug!(name, dest=%dest.display(), issues=issues.len(), "checked");
+      result.push(PackageChecked {
+        name: name.to_string(),
+        version: version.file_name().to_string_lossy().to_string(),
+        dest,
+        issues,
+      });
+    }
+    tracker.on_event(ItemEvent::Progress { current: i, max: None });
+  }
+  tracker.on_event(ItemEvent::Message { name: "check finished".to_string() });
+  tracker.on_event(ItemEvent::Finish);
+  Ok(result)
+}
+
+#[tokio::test]
+async fn test_check() {
+  use crate::tests::*;
+  let root = &*TestDir::new("test_check");
+  let cellar = root.join("Cellar");
+  let keg = cellar.join("wget/1.24.5");
+  std::fs::create_dir_all(keg.join("bin")).unwrap();
+  std::fs::create_dir_all(keg.join("lib")).unwrap();
+  let prefix = try_abs_path(root).unwrap();
+  let prefix = prefix.to_str().unwrap();
+
+  std::fs::write(keg.join("bin/wget-config"), "perl=@@HOMEBREW_PERL@@\nprefix=/usr/local\n").unwrap();
+  let libs = [&format!("{}/opt/libidn2/lib/libidn2.0.dylib", prefix), "/opt/homebrew/opt/gettext/lib/libintl.8.dylib", "/usr/lib/libSystem.B.dylib"];
+  std::fs::write(keg.join("lib/libwget.dylib"), sample_macho(Some(&format!("{}/lib/libwget.dylib", prefix)), &libs, &["@loader_path/../lib"], 0x100)).unwrap();
+  std::fs::write(keg.join("lib/libwget.so"), sample_elf("/lib64/ld-linux-x86-64.so.2", &["libc.so.6"], "$ORIGIN:/home/linuxbrew/.linuxbrew/lib")).unwrap();
+  #[cfg(unix)] {
+    std::os::unix::fs::symlink("libwget.dylib", keg.join("lib/libwget.1.dylib")).unwrap();
+    std::os::unix::fs::symlink("libwget.2.dylib", keg.join("lib/libwget.3.dylib")).unwrap();
+  }
+  // left by an interrupted unpack
+  std::fs::create_dir_all(cellar.join("wget/tmp")).unwrap();
+
+  let result = exec(Args::new(&root, &cellar), ["wget"], ()).await.unwrap();
+  assert_eq!(result.len(), 1);
+  assert_eq!(result[0].version, "1.24.5");
+  let mut expected = vec![
+    (PathBuf::from("bin/wget-config"), Issue::Placeholder(BTreeSet::from(["@@HOMEBREW_PERL@@".to_string()]))),
+    (PathBuf::from("lib/libwget.dylib"), Issue::ForeignPath("/opt/homebrew/opt/gettext/lib/libintl.8.dylib".to_string())),
+    (PathBuf::from("lib/libwget.so"), Issue::ForeignPath("/home/linuxbrew/.linuxbrew/lib".to_string())),
+  ];
+  if cfg!(unix) {
+    expected.insert(1, (PathBuf::from("lib/libwget.3.dylib"), Issue::BrokenLink(PathBuf::from("libwget.2.dylib"))));
+  }
+  assert_eq!(result[0].issues, expected);
+
+  let error = exec(Args::new(&root, &cellar), ["curl"], ()).await.unwrap_err();
+  assert!(matches!(&error, Error::PackageNotFound { name, .. } if name == "curl"), "{}", error);
+}
@@ -88,11 +88,13 @@ pub async fn exec<'a, P: AsRef<Path>, I: IntoIterator<Item = (&'a PkgBuild, &'a
     let cache_size = std::fs::metadata(&value).when(("metadata", &value))?.len();
     if cache_size != url.pkg_size {
       warn!(cache_size, url.pkg_size, "size not match");
+      mirrors.probed.invalidate(&pkg.sha256);
     }
     result.push(PackageCache {
       name: pkg.name.clone(),
       cache_pkg: value,
       cache_size,
+      cellar: pkg.cellar.clone(),
     })
   }
   tracker.on_event(DetailEvent::Overall(BytesEvent::Finish));
@@ -119,3 +121,71 @@ pub async fn test_download() {
   info!(len=result.len());
   assert_eq!(result.len(), resolved.len());
 }
+
+#[tokio::test]
+async fn test_download_invalidate_probe() {
+  use crate::tests::*;
+  init_logger(None);
+  let blob = b"bottle of wget".to_vec();
+  let body = blob.clone();
+  let (mirrors, _) = counting_mirror(move |_| StandInResponse::ok(body.clone())).await;
+  let base = mirrors.lists[0].base_url.clone();
+  let pkg = sample_pkg("wget", "1.24.5", &blob);
+  // a stale probe result
+  let url = PackageUrl { name: pkg.name.clone(), pkg_url: mirrors.lists[0].package_url(&pkg), pkg_size: 1, tab: None };
+  mirrors.probed.insert(&pkg.sha256, &base, &url);
+  let cache_dir = TestDir::new("test_download_invalidate_probe");
+  let result = exec(&mirrors, &cache_dir, [(&pkg, &url)], ()).await.unwrap();
+  assert_eq!(result[0].cache_size, blob.len() as u64);
+  assert!(mirrors.probed.get(&pkg.sha256, [base.as_str()].into_iter()).is_none());
+}
+
+#[tokio::test]
+async fn test_download_probed_mirror() {
+  use std::sync::atomic::Ordering;
+  use crate::tests::*;
+  init_logger(None);
+  let blob = b"bottle of wget".to_vec();
+  let pkg = sample_pkg("wget", "1.24.5", &blob);
+  let serve = || {
+    let body = blob.clone();
+    counting_server(move |_| StandInResponse::ok(body.clone()))
+  };
+  let ((first_server, first), (second_server, second)) = (serve().await, serve().await);
+  let second_base = second_server.base_url.clone();
+  let mirrors = MirrorLists::new(vec![first_server, second_server]);
+  // the second mirror won the probe
+  let url = PackageUrl { name: pkg.name.clone(), pkg_url: mirrors.lists[1].package_url(&pkg), pkg_size: blob.len() as u64, tab: None };
+  mirrors.probed.insert(&pkg.sha256, &second_base, &url);
+  let cache_dir = TestDir::new("test_download_probed_mirror");
+  exec(&mirrors, &cache_dir, [(&pkg, &url)], ()).await.unwrap();
+  assert_eq!((first.load(Ordering::SeqCst), second.load(Ordering::SeqCst)), (0, 1));
+
+  // not while it's failing
+  mirrors.health.record_failure(&second_base);
+  exec(&mirrors, &cache_dir, [(&pkg, &url)], ()).await.unwrap();
+  assert_eq!((first.load(Ordering::SeqCst), second.load(Ordering::SeqCst)), (1, 1));
+}
+
+#[tokio::test]
+async fn test_download_health() {
+  use crate::tests::*;
+  init_logger(None);
+  let blob = b"bottle of wget".to_vec();
+  let pkg = sample_pkg("wget", "1.24.5", &blob);
+  let body = blob.clone();
+  let servers = [
+    counting_server(|_| StandInResponse::status(404)).await.0,
+    counting_server(|_| StandInResponse::status(503)).await.0,
+    counting_server(move |_| StandInResponse::ok(body.clone())).await.0,
+  ];
+  let mirrors = MirrorLists::new(servers.into());
+  let (lacking, broken) = (&mirrors.lists[0].base_url, &mirrors.lists[1].base_url);
+  let cache_dir = TestDir::new("test_download_health");
+
+  let target = step(&mirrors, &pkg, &cache_dir, ()).await.unwrap();
+  assert_eq!(std::fs::read(target).unwrap(), blob);
+  // a bottle missing from the mirror doesn't lower its health, a server error does
+  assert!(mirrors.health.get(lacking).is_none());
+  assert_eq!(mirrors.health.get(broken).unwrap().consecutive_failures, 1);
+}
@@ -77,6 +77,7 @@ fn guess_installed(path: &Path) -> Option<PackageInstalled> {
     dest: path.join(&version),
     version,
     reloc: Default::default(),
+    unknown_placeholders: Default::default(),
   })
 }
 
@@ -2,9 +2,12 @@ pub mod resolve;
 pub mod probe;
 pub mod download;
 pub mod update_db;
+pub mod update_formula;
 pub mod verify;
 pub mod unpack;
 pub mod link;
+pub mod check;
+pub mod bench;
 
 #[derive(Debug, Clone)]
 pub struct Event {
@@ -1,33 +1,45 @@
-use std::path::Path;
-
-use reqwest::header;
+use std::{path::Path, time::Instant};
 
 use crate::{error::{Error, ErrorExt, Result}, io::fetch::{FetchReq, MirrorLists}, package::package::{PackageUrl, PackageVersion, PkgBuild}, ui::{event::ItemEvent, EventListener}};
 
 #[tracing::instrument(level = "trace", skip_all, fields(mirrors.len = mirrors.len(), package = %pkg.name, arch = %pkg.arch))]
 pub async fn step(mirrors: &MirrorLists, pkg: &PkgBuild) -> Result<PackageUrl> {
   trace!(?pkg);
+  if let Some(url) = mirrors.probed.get(&pkg.sha256, mirrors.lists.iter().map(|i| i.base_url.as_str())) {
+    debug!(url.pkg_url, url.pkg_size, "probe cached");
+    return Ok(url)
+  }
   let req = FetchReq::Package(pkg.clone());
-  for (client, url) in mirrors.url_iter(req.clone()) {
-    let result: Result<_> = async move {
-      let resp = client.head(&url).send().await.when(("head", &url))?;
-      let size = resp.headers()
-        .get(header::CONTENT_LENGTH).ok_or_else(|| Error::parse_response_error("head", &url, "CONTENT_LENGTH"))?
-        .to_str().map_err(Error::parse_response("head", &url, "CONTENT_LENGTH.to_str"))?
-        .parse::<u64>().map_err(Error::parse_response("head", &url, "CONTENT_LENGTH.parse"))?;
+  let mut tried = false;
+  for mirror_url in mirrors.url_iter(req.clone()) {
+    tried = true;
+    let start = Instant::now();
+    let result: Result<_> = async {
+      let resolved = mirror_url.resolve(&req).await?;
+      let size = mirror_url.content_length(&resolved).await?;
       Ok(PackageUrl {
         name: pkg.name.clone(),
-        pkg_url: url.to_string(),
+        pkg_url: resolved.url,
         pkg_size: size,
+        tab: resolved.tab,
       })
     }.await;
     match result {
-      Ok(url) => return Ok(url),
-      _ => {
-        warn!(?result, "failed to head");
-      }
+      Ok(url) => {
+        mirrors.health.record_latency(&mirror_url.mirror.base_url, start.elapsed());
+        mirrors.probed.insert(&pkg.sha256, &mirror_url.mirror.base_url, &url);
+        return Ok(url)
+      },
+      Err(e) if e.is_mirror_down() => {
+        warn!(error=%e, "failed to head");
+        mirrors.health.record_failure(&mirror_url.mirror.base_url);
+      },
+      Err(e) => warn!(error=%e, "not on this mirror"),
     }
   }
+  if !tried {
+    return Err(mirrors.unavailable(req))
+  }
   Err(Error::MirrorFailed(req))
 }
 
@@ -42,16 +54,22 @@ pub struct Args<'a> {
   pub mirrors: &'a MirrorLists,
   pub cache_dir: Option<&'a Path>,
   pub filter_cached: bool,
+  /// only use bottles in cache_dir, never touch mirrors, so it requires cache_dir
+  pub offline: bool,
 }
 impl<'a> Args<'a> {
   pub fn new(arch: &'a str, mirrors: &'a MirrorLists) -> Self {
-    Self { arch, mirrors, cache_dir: None, filter_cached: false }
+    Self { arch, mirrors, cache_dir: None, filter_cached: false, offline: false }
   }
   pub fn cache<P: AsRef<Path> + 'a>(mut self, cache_dir: &'a P, filter_cached: bool) -> Self {
     self.cache_dir = self.cache_dir.or(Some(cache_dir.as_ref()));
     self.filter_cached = filter_cached;
     self
   }
+  pub fn offline(mut self, offline: bool) -> Self {
+    self.offline = offline;
+    self
+  }
 }
 
 #[tracing::instrument(level = "debug", skip_all, fields(arch = %args.arch))]
@@ -67,6 +85,14 @@ where
   let urls = packages.clone().into_iter().map(|package| {
     package.find_arch(args.arch).ok_or_else(|| Error::package_arch_not_found(package, args.arch))
   }).collect::<Result<Vec<_>, _>>()?;
+  if args.offline {
+    let cache_dir = args.cache_dir.ok_or(Error::OfflineWithoutCache)?;
+    let missing = urls.iter().filter(|pkg| !cache_dir.join(&pkg.filename).exists())
+      .map(|pkg| pkg.filename.clone()).collect::<Vec<_>>();
+    if !missing.is_empty() {
+      return Err(Error::MissingFromCache { cache_dir: cache_dir.to_path_buf(), missing })
+    }
+  }
   for (i, (info, pkg)) in packages.into_iter().zip(urls).enumerate() {
     tracker.on_event(ItemEvent::Progress { current: i, max: None });
     tracker.on_event(ItemEvent::Message { name: format!("probing {}", info.name) });
@@ -78,6 +104,7 @@ where
           name: info.name.clone(),
           pkg_url: target.to_string_lossy().to_string(),
           pkg_size: target.metadata().when(("metadata", &target))?.len(),
+          tab: None,
         }, true)
       },
       _ => (step(args.mirrors, pkg).await?, false),
@@ -110,3 +137,86 @@ async fn test_probe() {
   assert_eq!(result.len(), resolved.len());
   assert_eq!(result.iter().map(|i| &i.url.name).collect::<Vec<_>>(), resolved.iter().map(|i| &i.name).collect::<Vec<_>>());
 }
+
+#[tokio::test]
+async fn test_probe_offline() {
+  use std::sync::atomic::Ordering;
+  use crate::tests::*;
+  init_logger(None);
+  let (mirrors, requests) = counting_mirror(|_| StandInResponse::ok("bottle")).await;
+  let package = |name: &str| PackageVersion {
+    name: name.to_string(),
+    version: "1.0".to_string(),
+    revision: 0,
+    desc: String::new(),
+    license: None,
+    deps: vec![],
+    prebuilds: vec![sample_pkg(name, "1.0", b"")],
+    link_overwrite: vec![],
+  };
+  let packages = [package("cached"), package("missing")];
+  let cache_dir = &*TestDir::new("test_probe_offline");
+  std::fs::write(cache_dir.join(&packages[0].prebuilds[0].filename), "bottle").unwrap();
+
+  let result = exec(Args::new(ARCH, &mirrors).cache(&cache_dir, false).offline(true), &packages, ()).await;
+  match result {
+    Err(Error::MissingFromCache { missing, .. }) => assert_eq!(missing, vec![packages[1].prebuilds[0].filename.clone()]),
+    _ => panic!("should fail with MissingFromCache"),
+  }
+
+  std::fs::write(cache_dir.join(&packages[1].prebuilds[0].filename), "bottle!").unwrap();
+  let result = exec(Args::new(ARCH, &mirrors).cache(&cache_dir, false).offline(true), &packages, ()).await.unwrap();
+  assert!(result.iter().all(|i| i.cached));
+  assert_eq!(result[1].url.pkg_size, 7);
+  assert_eq!(requests.load(Ordering::SeqCst), 0);
+
+  let result = exec(Args::new(ARCH, &mirrors).offline(true), &packages, ()).await;
+  assert!(matches!(result, Err(Error::OfflineWithoutCache)));
+  assert_eq!(requests.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn test_probe_cache() {
+  use std::sync::atomic::Ordering;
+  use crate::{io::probe_cache::ProbeCache, package::mirror::{MirrorServer, MirrorType}, tests::*};
+  init_logger(None);
+  let (server, heads) = counting_server(|_| StandInResponse::ok("bottle")).await;
+  let base = server.base_url.clone();
+  let pkg = sample_pkg("wget", "1.24.5", b"bottle");
+  let dir = TestDir::new("test_probe_cache");
+  let path = dir.join("probe_cache.json");
+  let mirrors = || MirrorLists::new(vec![MirrorServer::new(MirrorType::Bottle, &base, None)]).probe_cache(ProbeCache::load(&path));
+
+  let first = MirrorLists::new(vec![server]).probe_cache(ProbeCache::load(&path));
+  assert_eq!(step(&first, &pkg).await.unwrap().pkg_size, 6);
+  assert_eq!(step(&first, &pkg).await.unwrap().pkg_size, 6);
+  assert_eq!(heads.load(Ordering::SeqCst), 1);
+  first.probed.save().unwrap();
+
+  let second = mirrors();
+  assert_eq!(step(&second, &pkg).await.unwrap().pkg_size, 6);
+  assert_eq!(heads.load(Ordering::SeqCst), 1);
+  second.probed.invalidate(&pkg.sha256);
+  step(&second, &pkg).await.unwrap();
+  assert_eq!(heads.load(Ordering::SeqCst), 2);
+
+  let other = MirrorLists::new(vec![MirrorServer::new(MirrorType::Bottle, &format!("{}/other", base), None)]).probe_cache(ProbeCache::load(&path));
+  assert!(other.probed.get(&pkg.sha256, other.lists.iter().map(|i| i.base_url.as_str())).is_none());
+}
+
+#[tokio::test]
+async fn test_probe_health() {
+  use crate::tests::*;
+  init_logger(None);
+  let mirrors = MirrorLists::new(vec![
+    counting_server(|_| StandInResponse::status(404)).await.0,
+    counting_server(|_| StandInResponse::status(503)).await.0,
+  ]);
+  let (lacking, broken) = (&mirrors.lists[0].base_url, &mirrors.lists[1].base_url);
+  let pkg = sample_pkg("wget", "1.24.5", b"bottle");
+
+  assert!(step(&mirrors, &pkg).await.is_err());
+  // a bottle missing from the mirror doesn't lower its health, a server error does
+  assert!(mirrors.health.get(lacking).is_none());
+  assert_eq!(mirrors.health.get(broken).unwrap().consecutive_failures, 1);
+}
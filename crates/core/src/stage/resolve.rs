@@ -1,61 +1,58 @@
 use std::{borrow::Borrow, collections::{HashMap, HashSet, VecDeque}, time::Duration};
 
-///! query would find in Vec<Formula> to get correspond Package
+///! query would find in a FormulaSource to get correspond Package
 ///! with there dependences.
 
-use crate::{error::Result, package::{formula::Formula, package::PackageVersion}, ui::{event::ItemEvent, EventListener}};
+use crate::{error::Result, io::index::FormulaSource, package::{formula::Formula, package::PackageVersion}, ui::{event::ItemEvent, EventListener}};
 
 pub struct Value {
   pub names: Vec<String>,
   pub packages: Vec<PackageVersion>,
 }
 
+/// `formulas` is either the parsed formula.json or a [`FormulaIndex`](crate::io::index::FormulaIndex), which decodes only the resolved ones
 #[tracing::instrument(level = "debug", skip_all, fields(formulas.len=formulas.len()))]
-pub async fn exec<'a, S, I>(
-  formulas: &[Formula],
+pub async fn exec<'a, F, S, I>(
+  formulas: &F,
   query: I,
   tracker: impl EventListener<ItemEvent>
 ) -> Result<Value>
 where
+  F: FormulaSource + ?Sized,
   S: Borrow<str> + ?Sized + 'a,
   I: IntoIterator<Item = &'a S>,
 {
-  let mut queue = VecDeque::from_iter(query.into_iter().map(|i| i.borrow()));
-  let mut direct_names = queue.iter().map(|&i| (i, i)).collect::<HashMap<_,_>>();
-  let mut visited = HashSet::<&str>::new();
-  let mut formula_index = formulas.iter().map(|f| (f.name.as_str(), f)).collect::<HashMap<_, _>>();
-  formula_index.extend(formulas.iter().flat_map(|f| f.oldname.iter().map(move |name| (name.as_str(), f))));
-  formula_index.extend(formulas.iter().flat_map(|f| f.oldnames.iter().map(move |name| (name.as_str(), f))));
-  formula_index.extend(formulas.iter().flat_map(|f| f.aliases.iter().map(move |name| (name.as_str(), f))));
-  formula_index.extend(formulas.iter().map(|f| (f.full_name.as_str(), f)));
-  let mut collected = Vec::new();
+  let mut queue = VecDeque::from_iter(query.into_iter().map(|i| i.borrow().to_string()));
+  let mut direct_names = queue.iter().map(|i| (i.clone(), i.clone())).collect::<HashMap<_,_>>();
+  let mut visited = HashSet::<String>::new();
+  let mut collected = Vec::<Formula>::new();
 
   let mut i = 0;
   while let Some(item) = queue.pop_front() {
     i += 1;
     tracker.on_event(ItemEvent::Progress { current: i, max: Some(i + queue.len()) });
     tracker.on_event(ItemEvent::Message { name: format!("resolving {}", item) });
-    let formula = *formula_index.get(item).ok_or_else(|| crate::error::Error::package_not_found(item))?;
-    if direct_names.contains_key(item) {
-      direct_names.insert(item, &formula.full_name);
+    let formula = formulas.find(&item)?.ok_or_else(|| crate::error::Error::package_not_found(&item))?;
+    if let Some(name) = direct_names.get_mut(&item) {
+      name.clone_from(&formula.full_name);
     }
-    if visited.contains(formula.name.as_str()) {
+    if visited.contains(&formula.name) {
       continue;
     }
-    visited.insert(&formula.name);
+    visited.insert(formula.name.clone());
     // TODO: warn about cyclic dep here;
-    let deps = formula.dependencies.iter().filter(|i| !visited.contains(i.as_str())).map(|d| d.as_str()).collect::<Vec<_>>();
+    let deps = formula.dependencies.iter().filter(|i| !visited.contains(i.as_str())).cloned().collect::<Vec<_>>();
     if !deps.is_empty() {
       debug!(deps.from=formula.name, deps.to=deps.join(","));
     }
     queue.extend(deps);
-    collected.push(formula.clone());
+    collected.push(formula.into_owned());
     // TODO: better parking method
     tokio::time::sleep(Duration::from_millis(0)).await;
   }
   tracker.on_event(ItemEvent::Message { name: format!("resolve finished") });
   tracker.on_event(ItemEvent::Finish);
-  let mut direct_names = direct_names.values().map(|i| i.to_string()).collect::<Vec<_>>();
+  let mut direct_names = direct_names.into_values().collect::<Vec<_>>();
   direct_names.sort();
   // TODO: convert formula to package
   Ok(Value {
@@ -83,3 +80,29 @@ async fn test_resolve() {
   assert_eq!(result.names.iter().map(|i| i.split('@').next().unwrap()).collect::<HashSet<_>>(), query.iter().cloned().collect());
   assert_eq!(result.packages.len(), result.packages.iter().map(|f| &f.name).collect::<HashSet<_>>().len())
 }
+
+#[tokio::test]
+async fn test_resolve_index() {
+  use crate::{io::index::FormulaIndex, tests::*};
+  init_logger(None);
+  let dir = &*TestDir::new("test_resolve_index");
+  std::fs::create_dir_all(dir).unwrap();
+  let mut openssl = sample_formula("openssl@3", "3.2.1", &["ca-certificates"]);
+  openssl["aliases"] = serde_json::json!(["openssl"]);
+  let formulas = serde_json::json!([
+    sample_formula("wget", "1.24.5", &["openssl@3", "libidn2"]),
+    openssl,
+    sample_formula("ca-certificates", "2024-03-11", &[]),
+    sample_formula("libidn2", "2.3.7", &[]),
+    sample_formula("unrelated", "1.0", &[]),
+  ]);
+  std::fs::write(dir.join("formula.json"), formulas.to_string()).unwrap();
+  let index = FormulaIndex::open(dir.join("formula.json")).unwrap();
+
+  let result = exec(&index, ["wget", "openssl"], ()).await.unwrap();
+  assert_eq!(result.names, ["openssl@3", "wget"]);
+  let mut resolved = result.packages.iter().map(|i| i.name.as_str()).collect::<Vec<_>>();
+  resolved.sort();
+  assert_eq!(resolved, ["ca-certificates", "libidn2", "openssl@3", "wget"]);
+  assert!(exec(&index, ["curl"], ()).await.is_err());
+}
@@ -1,46 +1,68 @@
-use std::{ffi::OsString, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+use std::{collections::{BTreeMap, BTreeSet}, ffi::OsString, path::{Path, PathBuf}, sync::{Arc, Mutex}};
 
-use crate::{error::{ErrorExt, IoErrorExt, Result}, io::{relocate::{relocate, RelocateType, RelocationPattern}, untar::{untar_gz, UnpackEvent}}, package::package::{PackageCache, PackageInstalled}, ui::{event::{BytesEvent, DetailEvent}, EventListener}};
+use crate::{error::{Error, ErrorExt, IoErrorExt, Result}, io::{read::try_abs_path, relocate::{relocate_report, RelocateType, RelocationPattern}, untar::{untar_gz, UnpackEvent}}, package::package::{Cellar, PackageCache, PackageInstalled}, ui::{event::{BytesEvent, DetailEvent}, EventListener}};
 
-pub async fn step<P: AsRef<Path>, Q: AsRef<Path>>(pattern: &RelocationPattern, cache_pkg: P, dest_dir: Q, tracker: impl EventListener<BytesEvent>) -> Result<Vec<(PathBuf, RelocateType)>> {
+/// unknown placeholders left in files
+pub type Unknown = BTreeMap<PathBuf, BTreeSet<String>>;
+
+/// relocate each file when unpacked, unless `pattern` is None (`:any_skip_relocation` bottles)
+pub async fn step<P: AsRef<Path>, Q: AsRef<Path>>(pattern: Option<&RelocationPattern>, cache_pkg: P, dest_dir: Q, tracker: impl EventListener<BytesEvent>) -> Result<(Vec<(PathBuf, RelocateType)>, Unknown)> {
   use crate::ui::event::Event::*;
   let dest_dir = dest_dir.as_ref();
   let relocates = Arc::new(Mutex::new(Vec::new()));
+  let unknown = Arc::new(Mutex::new(Unknown::new()));
+  let failed = Arc::new(Mutex::new(None));
   untar_gz(&cache_pkg, dest_dir, |e: UnpackEvent| {
-    if let Some(name) = e.current_entry {
-      match relocate(dest_dir.join(&name), pattern) {
-        Ok(RelocateType::None) => {},
-        Ok(ty) => relocates.lock().unwrap().push((name, ty)),
+    if let (Some(name), Some(pattern)) = (e.current_entry, pattern) {
+      match relocate_report(dest_dir.join(&name), pattern) {
+        Ok((ty, tokens)) => {
+          if !tokens.is_empty() {
+            warn!(name=%name.display(), ?tokens, "unknown placeholders left in place");
+            unknown.lock().unwrap().insert(name.clone(), tokens);
+          }
+          if ty != RelocateType::None {
+            relocates.lock().unwrap().push((name, ty));
+          }
+        },
         Err(e) => {
           error!(error=?e, "relocate failed");
-          relocates.lock().unwrap().push((name, RelocateType::None));
+          failed.lock().unwrap().get_or_insert(e);
         },
       }
     }
     tracker.on_event(Progress { current: e.pos, max: Some(e.total_size) });
   }).await?;
-  let relocates: Vec<_> = std::mem::take(relocates.lock().unwrap().as_mut());
-  for (name, ty) in &relocates {
-    if ty == &RelocateType::None {
-      warn!(name=%name.display(), "relocate failed");
-      return Err(std::io::Error::other(format!("relocate {} failed", name.display()))).when(("unpack", cache_pkg.as_ref()))?;
-    }
+  // the first failure, which carries the offsets or load command that didn't fit
+  if let Some(e) = failed.lock().unwrap().take() {
+    return Err(e)
   }
-  Ok(relocates)
+  let relocates: Vec<_> = std::mem::take(relocates.lock().unwrap().as_mut());
+  let unknown = std::mem::take(&mut *unknown.lock().unwrap());
+  Ok((relocates, unknown))
 }
 
 pub struct Args<'a> {
   pub prefix: &'a Path,
   pub cellar: &'a Path,
   pub force: bool,
+  /// the bottle tag, placeholder defaults differ on linux
+  pub arch: &'a str,
+  /// overrides of placeholder values, see [`RelocationPattern::placeholder`]
+  pub placeholders: BTreeMap<String, String>,
 }
 impl<'a> Args<'a> {
   pub fn new<P1: AsRef<Path>, P2: AsRef<Path>>(prefix: &'a P1, cellar: &'a P2) -> Self {
-    Self { prefix: prefix.as_ref(), cellar: cellar.as_ref(), force: false }
+    Self { prefix: prefix.as_ref(), cellar: cellar.as_ref(), force: false, arch: "", placeholders: BTreeMap::new() }
   }
   pub fn force(self, f: bool) -> Self {
     Self { force: f, ..self }
   }
+  pub fn arch(self, arch: &'a str) -> Self {
+    Self { arch, ..self }
+  }
+  pub fn placeholders(self, placeholders: BTreeMap<String, String>) -> Self {
+    Self { placeholders, ..self }
+  }
 }
 
 pub async fn exec<'a, I: IntoIterator<Item = &'a PackageCache> + Clone>(
@@ -51,9 +73,25 @@ pub async fn exec<'a, I: IntoIterator<Item = &'a PackageCache> + Clone>(
   use DetailEvent::*;
   use crate::ui::event::Event::*;
   let mut result = Vec::new();
-  let pattern = RelocationPattern::new(args.prefix, args.cellar);
+  let pattern = args.placeholders.iter().fold(
+    RelocationPattern::new(args.prefix, args.cellar).target(args.arch),
+    |pattern, (name, value)| pattern.placeholder(name, value),
+  );
+  // fail before anything is unpacked
+  let cellar = try_abs_path(args.cellar).unwrap_or_else(|| args.cellar.to_path_buf());
+  for pkg in pkgs.clone() {
+    if let Cellar::Fixed(expected) = Cellar::parse(&pkg.cellar) {
+      if path_clean::clean(&expected) != cellar {
+        return Err(Error::CellarMismatch { name: pkg.name.clone(), expected, actual: cellar })
+      }
+    }
+  }
   tracker.on_event(Overall(Init { max: pkgs.clone().into_iter().count() }));
   for (i, pkg) in pkgs.into_iter().enumerate() {
+    let pattern = match Cellar::parse(&pkg.cellar) {
+      Cellar::AnySkipRelocation => None,
+      Cellar::Any | Cellar::Fixed(_) => Some(&pattern),
+    };
     let tmp_target = Path::new(args.cellar).join(&pkg.name).join("tmp");
     debug!(cache_pkg=%pkg.cache_pkg.display(), tmp_dir=%tmp_target.display());
     std::fs::remove_dir_all(&tmp_target).ok_not_found().when(("remove_dir_all", &tmp_target))?;
@@ -61,7 +99,7 @@ pub async fn exec<'a, I: IntoIterator<Item = &'a PackageCache> + Clone>(
 
     tracker.on_event(Item(i, Message { name: format!("{}", pkg.name) }));
     tracker.on_event(Item(i, Message { name: format!("unpacking {}", pkg.name) }));
-    let reloc = step(&pattern, &pkg.cache_pkg, &tmp_target, |e: BytesEvent| tracker.on_event(Item(i, e))).await?;
+    let (reloc, unknown) = step(pattern, &pkg.cache_pkg, &tmp_target, |e: BytesEvent| tracker.on_event(Item(i, e))).await?;
     tracker.on_event(Item(i, Finish));
     tracker.on_event(Overall(Progress { current: i, max: None }));
     let version = guess_version(tmp_target.join(&pkg.name)).when(("unpack guess version", &tmp_target))?;
@@ -79,6 +117,7 @@ pub async fn exec<'a, I: IntoIterator<Item = &'a PackageCache> + Clone>(
       dest: target_versioned,
       version: version.to_string_lossy().to_string(),
       reloc: reloc.into_iter().collect(),
+      unknown_placeholders: unknown,
     });
   }
   tracker.on_event(Overall(Finish));
@@ -114,3 +153,30 @@ async fn test_verify() {
     assert!(i.dest.exists());
   }
 }
+
+#[tokio::test]
+async fn test_unpack_cellar() {
+  use crate::tests::*;
+  let root = &*TestDir::new("test_unpack_cellar");
+  let cellar = root.join("Cellar");
+  std::fs::create_dir_all(&cellar).unwrap();
+  let config = b"prefix=@@HOMEBREW_PREFIX@@\n";
+  let bottle = root.join("wget-1.24.5.arm64_sonoma.bottle.tar.gz");
+  std::fs::write(&bottle, sample_bottle(&[("wget/1.24.5/bin/wget-config", config)])).unwrap();
+  let pkg = |cellar: &str| PackageCache { name: "wget".to_string(), cache_pkg: bottle.clone(), cache_size: 0, cellar: cellar.to_string() };
+  let installed = cellar.join("wget/1.24.5/bin/wget-config");
+
+  let result = exec(Args::new(&root, &cellar).force(true), &[pkg(":any_skip_relocation")], ()).await.unwrap();
+  assert!(result[0].reloc.is_empty());
+  assert_eq!(std::fs::read(&installed).unwrap(), config);
+
+  let result = exec(Args::new(&root, &cellar).force(true), &[pkg(":any")], ()).await.unwrap();
+  assert_eq!(result[0].reloc.values().collect::<Vec<_>>(), [&RelocateType::Text]);
+  let prefix = try_abs_path(root).unwrap();
+  assert_eq!(std::fs::read_to_string(&installed).unwrap(), format!("prefix={}\n", prefix.display()));
+
+  let error = exec(Args::new(&root, &cellar).force(true), &[pkg("/opt/homebrew/Cellar")], ()).await.unwrap_err();
+  assert!(matches!(&error, Error::CellarMismatch { name, .. } if name == "wget"), "{}", error);
+  let fixed = try_abs_path(&cellar).unwrap();
+  exec(Args::new(&root, &cellar).force(true), &[pkg(fixed.to_str().unwrap())], ()).await.unwrap();
+}
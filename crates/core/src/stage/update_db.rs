@@ -1,23 +1,119 @@
 use std::path::Path;
 
-use crate::{error::{Error, ErrorExt as _, Result}, io::{fetch::{fetch_remote, FetchReq, MirrorLists}, read::{read_formulas, tmp_path}, FetchState}, ui::{event::BytesEvent, EventListener}};
-
-#[tracing::instrument(level = "debug", skip_all, fields(mirrors.len = mirrors.len()))]
-pub async fn exec<P: AsRef<Path>>(mirrors: &MirrorLists, dest_dir: P, tracker: impl EventListener<BytesEvent>) -> Result<()> {
-  // TODO: support formula.json.gz
-  let req = FetchReq::Api("formula.json".to_string());
-  let target = req.target(dest_dir);
-  let tmp_file = tmp_path(&target, ".new");
-  fetch_remote(mirrors, req, &tmp_file, |state: FetchState| tracker.on_event(BytesEvent::Progress { current: state.current, max: Some(state.max) })).await?;
-  if !tmp_file.exists() {
-    return Err(Error::parse_response_error("fetch", &tmp_file.display().to_string(), "not exists"));
+use crate::{error::{Error, ErrorExt as _, IoErrorExt as _, Result}, io::{compress::{read_decoded, write_encoded, Compression}, fetch::{fetch_remote, FetchOutcome, FetchReq, MirrorLists}, http::Validators, index::FormulaIndex, jws::{self, PublicKeys}, read::{read_formulas, tmp_path}, FetchState}, ui::{event::BytesEvent, EventListener}};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+  Updated,
+  /// the mirror answered `304 Not Modified`
+  UpToDate,
+}
+
+pub struct Args<'a> {
+  pub mirrors: &'a MirrorLists,
+  pub dest_dir: &'a Path,
+  /// store as `formula.json.gz` etc.
+  pub compression: Option<Compression>,
+  /// fetch `formula.jws.json` instead, and only accept the payload signed by one of these keys
+  pub keys: Option<&'a PublicKeys>,
+}
+impl<'a> Args<'a> {
+  pub fn new<P: AsRef<Path> + ?Sized>(mirrors: &'a MirrorLists, dest_dir: &'a P) -> Self {
+    Self { mirrors, dest_dir: dest_dir.as_ref(), compression: None, keys: None }
+  }
+  pub fn compression(mut self, compression: Option<Compression>) -> Self {
+    self.compression = compression;
+    self
   }
-  let formulas = read_formulas(&tmp_file)?;
-  if formulas.is_empty() {
-    return Err(Error::parse_response_error("fetch", &tmp_file.display().to_string(), "empty"));
+  pub fn verify(mut self, keys: Option<&'a PublicKeys>) -> Self {
+    self.keys = keys;
+    self
   }
-  std::fs::rename(&tmp_file, &target).when(("rename", &target))?;
-  Ok(())
+}
+
+const FORMULA: &str = "formula.json";
+const FORMULA_JWS: &str = "formula.jws.json";
+
+/// the index is stored as `formula.json`, or `formula.json.gz` etc. if `compression` is set,
+/// the other variants left from a previous setting are removed after a successful update.
+/// when verifying, the signed `formula.jws.json` is kept next to it for conditional requests, and its payload becomes the index.
+/// the [`FormulaIndex`] is rebuilt along with it.
+#[tracing::instrument(level = "debug", skip_all, fields(mirrors.len = args.mirrors.len(), compression = ?args.compression, verify = args.keys.is_some()))]
+pub async fn exec(args: Args<'_>, tracker: impl EventListener<BytesEvent>) -> Result<Value> {
+  let Args { mirrors, dest_dir, compression, keys } = args;
+  let req = FetchReq::Api(if keys.is_some() { FORMULA_JWS } else { FORMULA }.to_string());
+  let target = Compression::path(&dest_dir.join(FORMULA), compression);
+  // the file fetched from mirrors, which is target itself if not verifying
+  let fetched = Compression::path(&req.target(dest_dir), compression);
+  let tmp_file = Compression::path(&tmp_path(&req.target(dest_dir), ".new"), compression);
+  let tmp_validators = Validators::path(&tmp_file);
+  let tmp_target = match keys {
+    Some(_) => Compression::path(&tmp_path(&dest_dir.join(FORMULA), ".new"), compression),
+    None => tmp_file.clone(),
+  };
+  // leftovers of an interrupted run must not make the request conditional
+  std::fs::remove_file(&tmp_file).ok_not_found_none().when(("remove", &tmp_file))?;
+  std::fs::remove_file(&tmp_validators).ok_not_found_none().when(("remove", &tmp_validators))?;
+  // start from the current file, so the request is conditional on it, and a bad response never replaces it
+  if let Some(validators) = Validators::load(&fetched).filter(|_| target.exists()) {
+    if std::fs::hard_link(&fetched, &tmp_file).is_err() {
+      std::fs::copy(&fetched, &tmp_file).when(("copy", &tmp_file))?;
+    }
+    validators.save(&tmp_file)?;
+  }
+  let outcome = fetch_remote(mirrors, req, &tmp_file, |state: FetchState| tracker.on_event(BytesEvent::Progress { current: state.current, max: Some(state.max) })).await?;
+  if outcome == FetchOutcome::NotModified {
+    std::fs::remove_file(&tmp_file).when(("remove", &tmp_file))?;
+    std::fs::remove_file(&tmp_validators).ok_not_found_none().when(("remove", &tmp_validators))?;
+    FormulaIndex::open(&target)?;
+    return Ok(Value::UpToDate)
+  }
+  // validators of a rejected file must not make the next request conditional
+  let result = async {
+    if !tmp_file.exists() {
+      return Err(Error::parse_response_error("fetch", &tmp_file.display().to_string(), "not exists"));
+    }
+    if let Some(keys) = keys {
+      let payload = jws::verify(&tmp_file, &read_decoded(&tmp_file)?, keys)?;
+      write_encoded(&tmp_target, &payload, compression).await?;
+    }
+    let formulas = read_formulas(&tmp_target)?;
+    if formulas.is_empty() {
+      return Err(Error::parse_response_error("fetch", &tmp_target.display().to_string(), "empty"));
+    }
+    std::fs::rename(&tmp_target, &target).when(("rename", &target))?;
+    if tmp_file != tmp_target {
+      std::fs::rename(&tmp_file, &fetched).when(("rename", &fetched))?;
+    }
+    // validators of the old file are stale now
+    let validators = Validators::path(&fetched);
+    std::fs::remove_file(&validators).ok_not_found_none().when(("remove", &validators))?;
+    if tmp_validators.exists() {
+      std::fs::rename(&tmp_validators, &validators).when(("rename", &validators))?;
+    }
+    for name in [FORMULA, FORMULA_JWS] {
+      for other in [None, Some(Compression::Gzip), Some(Compression::Brotli)] {
+        let stale = Compression::path(&dest_dir.join(name), other);
+        if stale == target || stale == fetched {
+          continue
+        }
+        let validators = Validators::path(&stale);
+        std::fs::remove_file(&stale).ok_not_found_none().when(("remove", &stale))?;
+        std::fs::remove_file(&validators).ok_not_found_none().when(("remove", &validators))?;
+      }
+    }
+    // formulas refreshed one by one are superseded
+    let provenance = super::update_formula::Provenance::path(dest_dir);
+    std::fs::remove_file(&provenance).ok_not_found_none().when(("remove", &provenance))?;
+    FormulaIndex::build(&target)?;
+    Ok(Value::Updated)
+  }.await;
+  if result.is_err() {
+    for i in [&tmp_file, &tmp_validators, &tmp_target] {
+      std::fs::remove_file(i).ok();
+    }
+  }
+  result
 }
 
 #[tokio::test]
@@ -31,10 +127,132 @@ async fn test_update_formula() {
     active_pb,
     None,
     None,
-    |tracker| exec(&mirrors, CACHE_PATH, tracker),
+    |tracker| exec(Args::new(&mirrors, CACHE_PATH), tracker),
     (),
   ).await.unwrap();
   assert!(target.exists());
   info!(len=%std::fs::metadata(&target).unwrap().len());
   // std::fs::remove_file(target).unwrap();
 }
+
+#[tokio::test]
+async fn test_update_conditional() {
+  use std::sync::atomic::Ordering;
+  use crate::tests::*;
+  init_logger(None);
+  let formulas = serde_json::json!([sample_formula("wget", "1.24.5", &[])]).to_string();
+  let (mirrors, requests) = counting_mirror(move |req| {
+    if req.path != "/api/formula.json" {
+      return StandInResponse::status(404)
+    }
+    if req.headers.get("if-none-match").map(String::as_str) == Some("\"v1\"") {
+      return StandInResponse::status(304)
+    }
+    StandInResponse::ok(formulas.clone()).header("etag", "\"v1\"").header("last-modified", "Wed, 21 Oct 2015 07:28:00 GMT")
+  }).await;
+  let dest = &*TestDir::new("test_update_conditional");
+
+  assert_eq!(exec(Args::new(&mirrors, dest), ()).await.unwrap(), Value::Updated);
+  let validators = Validators::load(&dest.join("formula.json")).unwrap();
+  assert_eq!(validators.etag.as_deref(), Some("\"v1\""));
+  assert_eq!(validators.last_modified.as_deref(), Some("Wed, 21 Oct 2015 07:28:00 GMT"));
+  assert_eq!(exec(Args::new(&mirrors, dest), ()).await.unwrap(), Value::UpToDate);
+  // the second one is answered by 304
+  assert_eq!(requests.load(Ordering::SeqCst), 2);
+  assert_eq!(read_formulas(dest.join("formula.json")).unwrap().len(), 1);
+  assert!(!dest.join("formula.json.new").exists());
+  assert!(!dest.join("formula.json.new.validators").exists());
+  assert!(dest.join("formula.idx").exists());
+}
+
+#[tokio::test]
+async fn test_update_empty() {
+  use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+  use crate::tests::*;
+  init_logger(None);
+  let full = Arc::new(AtomicUsize::new(0));
+  let counter = full.clone();
+  let (mirrors, _) = counting_mirror(move |req| {
+    if req.headers.contains_key("if-none-match") {
+      return StandInResponse::status(304)
+    }
+    counter.fetch_add(1, Ordering::SeqCst);
+    StandInResponse::ok("[]").header("etag", "\"v1\"")
+  }).await;
+  let dest = &*TestDir::new("test_update_empty");
+
+  // the rejected file and its validators are removed, so the retry is not conditional
+  for _ in 0..2 {
+    assert!(exec(Args::new(&mirrors, dest), ()).await.is_err());
+    assert!(!dest.join("formula.json").exists());
+    assert!(!dest.join("formula.json.new").exists());
+    assert!(!dest.join("formula.json.new.validators").exists());
+  }
+  assert_eq!(full.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn test_update_compressed() {
+  use std::io::Write as _;
+  use crate::tests::*;
+  init_logger(None);
+  let formulas = serde_json::json!([sample_formula("wget", "1.24.5", &[])]).to_string();
+  let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+  encoder.write_all(formulas.as_bytes()).unwrap();
+  let gzipped = encoder.finish().unwrap();
+  let body = gzipped.clone();
+  let (mirrors, _) = counting_mirror(move |req| {
+    match req.headers.get("accept-encoding") {
+      Some(accept) if accept.contains("gzip") => StandInResponse::ok(body.clone()).header("content-encoding", "gzip"),
+      _ => StandInResponse::status(406),
+    }
+  }).await;
+  let dest = &*TestDir::new("test_update_compressed");
+
+  // decoded while downloading
+  assert_eq!(exec(Args::new(&mirrors, dest), ()).await.unwrap(), Value::Updated);
+  assert_eq!(std::fs::read_to_string(dest.join("formula.json")).unwrap(), formulas);
+  // kept compressed, and the plain one is removed
+  assert_eq!(exec(Args::new(&mirrors, dest).compression(Some(Compression::Gzip)), ()).await.unwrap(), Value::Updated);
+  assert!(!dest.join("formula.json").exists());
+  assert_eq!(std::fs::read(dest.join("formula.json.gz")).unwrap().len(), gzipped.len());
+  let formulas = read_formulas(dest.join("formula.json.gz")).unwrap();
+  assert_eq!(formulas[0].name, "wget");
+}
+
+#[tokio::test]
+async fn test_update_signed() {
+  use std::sync::{Arc, Mutex};
+  use crate::tests::*;
+  init_logger(None);
+  let (private, public) = generate_key();
+  let mut keys = PublicKeys::new();
+  keys.add_pem(PublicKeys::HOMEBREW_KID, &public).unwrap();
+  let formulas = serde_json::json!([sample_formula("wget", "1.24.5", &[])]).to_string();
+  let served = Arc::new(Mutex::new(sign_jws(&formulas, PublicKeys::HOMEBREW_KID, &private, false)));
+  let body = served.clone();
+  let (mirrors, _) = counting_mirror(move |req| match req.path.as_str() {
+    "/api/formula.jws.json" => StandInResponse::ok(body.lock().unwrap().clone()).header("etag", "\"v1\""),
+    _ => StandInResponse::status(404),
+  }).await;
+  let dest = &*TestDir::new("test_update_signed");
+
+  assert_eq!(exec(Args::new(&mirrors, dest).verify(Some(&keys)), ()).await.unwrap(), Value::Updated);
+  assert_eq!(std::fs::read_to_string(dest.join("formula.json")).unwrap(), formulas);
+  assert!(dest.join("formula.jws.json").exists());
+
+  // a mirror changing the payload is rejected, and the cached index is kept
+  let tampered = served.lock().unwrap().replace("1.24.5", "6.6.6");
+  *served.lock().unwrap() = tampered;
+  let result = exec(Args::new(&mirrors, dest).verify(Some(&keys)), ()).await;
+  assert!(matches!(result, Err(Error::SignatureInvalid { .. })), "{:?}", result.map(|_| ()));
+  assert_eq!(std::fs::read_to_string(dest.join("formula.json")).unwrap(), formulas);
+  assert!(!dest.join("formula.jws.json.new").exists());
+  assert!(!dest.join("formula.jws.json.new.validators").exists());
+
+  // so is a payload signed by another key
+  let (other, _) = generate_key();
+  *served.lock().unwrap() = sign_jws(&formulas.replace("1.24.5", "6.6.6"), PublicKeys::HOMEBREW_KID, &other, false);
+  assert!(exec(Args::new(&mirrors, dest).verify(Some(&keys)), ()).await.is_err());
+  assert_eq!(read_formulas(dest.join("formula.json")).unwrap()[0].versions.stable, "1.24.5");
+}
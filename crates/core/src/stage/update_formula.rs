@@ -0,0 +1,189 @@
+//! refresh a few formulas from `api/formula/<name>.json` instead of the whole `formula.json`.
+//! the fetched files are kept in `formula/` of the cache dir (so later fetches are conditional),
+//! merged into the local formula.json, and their fetch time recorded in `formula.provenance.json`.
+//! the api signs only the whole `formula.jws.json`, these files are not signed, so merging them is not verified.
+
+use std::{collections::{BTreeMap, HashSet, VecDeque}, path::{Path, PathBuf}};
+
+use serde::{Serialize, Deserialize};
+use serde_json::value::RawValue;
+
+use crate::{error::{Error, ErrorExt as _, IoErrorExt as _, Result}, io::{compress::{read_decoded, write_encoded, Compression}, fetch::{fetch_remote, FetchReq, MirrorLists}, health::unix_now, index::FormulaIndex, read::{save_state, tmp_path}}, package::formula::Formula, ui::{event::ItemEvent, EventListener}};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+  /// unix timestamp in seconds
+  pub fetched_at: u64,
+  /// the api file it was fetched as, e.g. `formula/wget.json`
+  pub api: String,
+}
+
+impl Provenance {
+  /// formulas refreshed one by one since the last full update, cleared by [`update_db`](super::update_db)
+  pub fn path(dest_dir: &Path) -> PathBuf {
+    dest_dir.join("formula.provenance.json")
+  }
+
+  pub fn load(dest_dir: &Path) -> Result<BTreeMap<String, Self>> {
+    let path = Self::path(dest_dir);
+    match std::fs::read(&path).ok_not_found().when(("read", &path))? {
+      Some(content) => serde_json::from_slice(&content).when(("de", "Provenance", None)),
+      None => Ok(Default::default()),
+    }
+  }
+}
+
+pub struct Value {
+  /// canonical names of fetched formulas, in fetch order
+  pub names: Vec<String>,
+}
+
+pub struct Args<'a> {
+  pub mirrors: &'a MirrorLists,
+  pub dest_dir: &'a Path,
+  /// formula.json is stored as `formula.json.gz` etc.
+  pub compression: Option<Compression>,
+  /// also fetch the dependency closure
+  pub deps: bool,
+}
+impl<'a> Args<'a> {
+  pub fn new<P: AsRef<Path> + ?Sized>(mirrors: &'a MirrorLists, dest_dir: &'a P) -> Self {
+    Self { mirrors, dest_dir: dest_dir.as_ref(), compression: None, deps: false }
+  }
+  pub fn compression(mut self, compression: Option<Compression>) -> Self {
+    self.compression = compression;
+    self
+  }
+  pub fn deps(mut self, deps: bool) -> Self {
+    self.deps = deps;
+    self
+  }
+}
+
+/// only the name is needed to merge
+#[derive(Deserialize)]
+struct Name {
+  name: String,
+}
+
+/// aliases and old names are resolved by the local index if any, the api only knows canonical names
+#[tracing::instrument(level = "debug", skip_all, fields(mirrors.len = args.mirrors.len(), deps = args.deps))]
+pub async fn exec<'a, S, I>(args: Args<'_>, names: I, tracker: impl EventListener<ItemEvent>) -> Result<Value>
+where
+  S: AsRef<str> + ?Sized + 'a,
+  I: IntoIterator<Item = &'a S>,
+{
+  let Args { mirrors, dest_dir, compression, deps } = args;
+  let target = Compression::path(&dest_dir.join("formula.json"), compression);
+  let index = match target.exists() {
+    true => Some(FormulaIndex::open(&target)?),
+    false => None,
+  };
+  let canonical = |name: &str| match index.as_ref().and_then(|index| index.lookup(name)) {
+    Some(i) => index.as_ref().unwrap().name(i).to_string(),
+    None => name.to_string(),
+  };
+
+  let mut queue = names.into_iter().map(|i| canonical(i.as_ref())).collect::<VecDeque<_>>();
+  let mut visited = HashSet::new();
+  let mut fetched = Vec::<(String, String)>::new();
+  while let Some(name) = queue.pop_front() {
+    if !visited.insert(name.clone()) {
+      continue
+    }
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+      return Err(Error::package_not_found(&name))
+    }
+    tracker.on_event(ItemEvent::Progress { current: fetched.len(), max: Some(fetched.len() + queue.len() + 1) });
+    tracker.on_event(ItemEvent::Message { name: format!("fetching {}", name) });
+    let api = format!("formula/{}.json", name);
+    let path = dest_dir.join(&api);
+    fetch_remote(mirrors, FetchReq::Api(api.clone()), &path, ()).await?;
+    let content = std::fs::read_to_string(&path).when(("read", &path))?;
+    let formula = serde_json::from_str::<Formula>(&content).when(("de", "Formula", Some(&content)))?;
+    if formula.name != name {
+      return Err(Error::parse_response_error("fetch", &api, "name mismatch"))
+    }
+    if deps {
+      queue.extend(formula.dependencies.iter().map(|i| canonical(i)));
+    }
+    fetched.push((formula.name, content));
+  }
+  drop(index);
+
+  merge(&dest_dir.join("formula.json"), compression, &fetched).await?;
+  let path = Provenance::path(dest_dir);
+  let mut provenance = Provenance::load(dest_dir)?;
+  let now = unix_now();
+  for (name, _) in &fetched {
+    provenance.insert(name.clone(), Provenance { fetched_at: now, api: format!("formula/{}.json", name) });
+  }
+  save_state(&path, &provenance)?;
+  FormulaIndex::build(&target)?;
+  tracker.on_event(ItemEvent::Finish);
+  Ok(Value { names: fetched.into_iter().map(|(name, _)| name).collect() })
+}
+
+/// replace the formulas of the same name in formula.json, and append the new ones
+async fn merge(plain: &Path, compression: Option<Compression>, fetched: &[(String, String)]) -> Result<()> {
+  let target = &Compression::path(plain, compression);
+  let content = match target.exists() {
+    true => read_decoded(target)?,
+    false => b"[]".to_vec(),
+  };
+  let raws = serde_json::from_slice::<Vec<&RawValue>>(&content).when(("de", "Vec<RawValue>", None))?;
+  let mut replace = fetched.iter().map(|(name, content)| (name.as_str(), content.trim())).collect::<BTreeMap<_, _>>();
+  let mut merged = Vec::with_capacity(raws.len() + replace.len());
+  for raw in raws {
+    let name = serde_json::from_str::<Name>(raw.get()).when(("de", "Name", Some(raw.get())))?.name;
+    merged.push(replace.remove(name.as_str()).unwrap_or(raw.get()));
+  }
+  merged.extend(fetched.iter().filter_map(|(name, _)| replace.remove(name.as_str())));
+  let tmp = Compression::path(&tmp_path(plain, ".merge"), compression);
+  write_encoded(&tmp, format!("[{}]", merged.join(",")).as_bytes(), compression).await?;
+  std::fs::rename(&tmp, target).when(("rename", target))?;
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_update_formula_api() {
+  use std::sync::{Arc, Mutex};
+  use crate::{io::{index::FormulaSource as _, read::read_formulas}, tests::*};
+  init_logger(None);
+  let dest = &*TestDir::new("test_update_formula_api");
+  let mut wget = sample_formula("wget", "1.24.5", &["openssl@3"]);
+  wget["aliases"] = serde_json::json!(["gnu-wget"]);
+  let local = serde_json::json!([wget, sample_formula("openssl@3", "3.2.1", &[]), sample_formula("curl", "8.7.1", &[])]);
+  std::fs::write(dest.join("formula.json"), local.to_string()).unwrap();
+
+  let requested = Arc::new(Mutex::new(Vec::new()));
+  let log = requested.clone();
+  let (mirrors, _) = counting_mirror(move |req| {
+    log.lock().unwrap().push(req.path.clone());
+    match req.path.as_str() {
+      "/api/formula/wget.json" => StandInResponse::ok(sample_formula("wget", "1.25.0", &["openssl@3", "libidn2"]).to_string()),
+      "/api/formula/openssl@3.json" => StandInResponse::ok(sample_formula("openssl@3", "3.3.0", &[]).to_string()),
+      "/api/formula/libidn2.json" => StandInResponse::ok(sample_formula("libidn2", "2.3.7", &[]).to_string()),
+      _ => StandInResponse::status(404),
+    }
+  }).await;
+
+  let result = exec(Args::new(&mirrors, dest), ["gnu-wget"], ()).await.unwrap();
+  assert_eq!(result.names, ["wget"]);
+  assert_eq!(*requested.lock().unwrap(), ["/api/formula/wget.json"]);
+  let index = FormulaIndex::open(dest.join("formula.json")).unwrap();
+  assert_eq!(index.find("wget").unwrap().unwrap().versions.stable, "1.25.0");
+  assert_eq!(index.find("openssl@3").unwrap().unwrap().versions.stable, "3.2.1");
+  drop(index);
+
+  let result = exec(Args::new(&mirrors, dest).deps(true), ["wget"], ()).await.unwrap();
+  assert_eq!(result.names, ["wget", "openssl@3", "libidn2"]);
+  let formulas = read_formulas(dest.join("formula.json")).unwrap();
+  assert_eq!(formulas.iter().map(|i| i.name.as_str()).collect::<Vec<_>>(), ["wget", "openssl@3", "curl", "libidn2"]);
+  assert_eq!(formulas[1].versions.stable, "3.3.0");
+  let provenance = Provenance::load(dest).unwrap();
+  assert_eq!(provenance.keys().collect::<Vec<_>>(), ["libidn2", "openssl@3", "wget"]);
+  assert_eq!(provenance["wget"].api, "formula/wget.json");
+
+  assert!(exec(Args::new(&mirrors, dest), ["missing"], ()).await.is_err());
+}
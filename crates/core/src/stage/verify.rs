@@ -48,6 +48,7 @@ pub async fn exec<'a, P: AsRef<Path>, I: IntoIterator<Item = (&'a PkgBuild, &'a
         name: pkg.name.clone(),
         cache_pkg,
         cache_size,
+        cellar: pkg.cellar.clone(),
       }
     });
     let mut reason = None;
@@ -108,11 +109,13 @@ pub fn get_pkgs<'a>(packages: &'a [crate::package::package::PackageVersion], cac
       name: pkg.name.clone(),
       pkg_url: file_name.clone(),
       pkg_size: entry.metadata().unwrap().len(),
+      tab: None,
     };
     let cache = PackageCache {
       name: pkg.name.clone(),
       cache_pkg: entry.path(),
       cache_size: entry.metadata().unwrap().len(),
+      cellar: pkg.cellar.clone(),
     };
     pkgs.push((pkg, url, cache));
   }